@@ -0,0 +1,480 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::layers::{NamespaceValidator, ValidationOutcome};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Longest wait for the inner validator, slower lookups count as failures.
+    pub timeout: Duration,
+    /// Consecutive failures, timeouts or `Unavailable`, that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a single lookup probes the inner validator.
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            failure_threshold: 5,
+            open_for: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Lookups go to the inner validator.
+    Closed,
+    /// Lookups fail right away with [`CircuitOpen`].
+    Open,
+    /// One lookup probes the inner validator, the others fail with [`CircuitOpen`].
+    HalfOpen,
+}
+
+/// Error of the lookups rejected while the circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace validation circuit is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Error of the lookups that took longer than [`CircuitBreakerConfig::timeout`].
+#[derive(Debug)]
+pub struct LookupTimedOut(pub Duration);
+
+impl fmt::Display for LookupTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace lookup timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for LookupTimedOut {}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: State,
+    /// Times the circuit opened, results of lookups admitted before the last time are stale.
+    opened: u64,
+}
+
+/// Lookup allowed to call the inner validator.
+#[derive(Clone, Copy, Debug)]
+struct Admission {
+    opened: u64,
+    /// Whether this lookup moved the circuit from open to half-open.
+    probe: bool,
+}
+
+/// Bounds the time of every lookup and stops calling a failing validator for a while.
+///
+/// Whether requests go through while the circuit is open is up to the layer, see
+/// [`ValidateNamespaceLayer::when_circuit_open`](crate::layers::ValidateNamespaceLayer::when_circuit_open).
+#[derive(Clone)]
+pub struct CircuitBreaker<V> {
+    inner: V,
+    config: CircuitBreakerConfig,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl<V: NamespaceValidator> CircuitBreaker<V> {
+    pub fn new(inner: V, config: CircuitBreakerConfig) -> Self {
+        Self {
+            inner,
+            config,
+            circuit: Arc::new(Mutex::new(Circuit {
+                state: State::Closed { failures: 0 },
+                opened: 0,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.circuit.lock().unwrap().state {
+            State::Closed { .. } => CircuitState::Closed,
+            // Reported open until a lookup starts probing.
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a lookup may call the inner validator, moving an expired open circuit to
+    /// half-open for this lookup to probe.
+    fn admit(&self) -> Option<Admission> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let probe = match circuit.state {
+            State::Closed { .. } => false,
+            State::Open { until } if Instant::now() >= until => {
+                circuit.state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => return None,
+        };
+        Some(Admission {
+            opened: circuit.opened,
+            probe,
+        })
+    }
+
+    fn record(&self, admission: Admission, success: bool) {
+        let mut guard = self.circuit.lock().unwrap();
+        let circuit = &mut *guard;
+        // Started before the circuit opened, against a validator that may have recovered or
+        // failed since: only the probe decides.
+        if admission.opened != circuit.opened {
+            return;
+        }
+        let state = &mut circuit.state;
+        *state = match (&*state, success) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.config.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Closed { .. } | State::HalfOpen, false) => {
+                tracing::warn!(
+                    "Opening namespace validation circuit for {:?}",
+                    self.config.open_for
+                );
+                circuit.opened += 1;
+                State::Open {
+                    until: Instant::now() + self.config.open_for,
+                }
+            }
+            // Unreachable, opening makes the lookups admitted before stale.
+            (State::Open { until }, false) => State::Open { until: *until },
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl<V: NamespaceValidator> NamespaceValidator for CircuitBreaker<V> {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        let Some(admission) = self.admit() else {
+            return ValidationOutcome::Unavailable(Arc::new(CircuitOpen));
+        };
+        let lookup = Lookup {
+            circuit: &self.circuit,
+            admission,
+            finished: false,
+        };
+
+        let outcome =
+            match tokio::time::timeout(self.config.timeout, self.inner.validate(namespace)).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    ValidationOutcome::Unavailable(Arc::new(LookupTimedOut(self.config.timeout)))
+                }
+            };
+        self.record(
+            admission,
+            !matches!(outcome, ValidationOutcome::Unavailable(_)),
+        );
+        lookup.finish();
+        outcome
+    }
+}
+
+/// Lookup admitted by the breaker, which might be dropped before it finishes.
+struct Lookup<'a> {
+    circuit: &'a Mutex<Circuit>,
+    admission: Admission,
+    finished: bool,
+}
+
+impl Lookup<'_> {
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Lookup<'_> {
+    fn drop(&mut self) {
+        if self.finished || !self.admission.probe {
+            return;
+        }
+        let mut circuit = self.circuit.lock().unwrap();
+        // A cancelled probe would leave the circuit half-open for good, let the next lookup
+        // probe instead.
+        if circuit.opened == self.admission.opened && matches!(circuit.state, State::HalfOpen) {
+            circuit.state = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::{FailureMode, NamespaceMetadata, ValidateNamespaceLayer};
+    use axum::{body::Body, extract::Request, http::StatusCode, routing::get, Extension, Router};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tower::util::ServiceExt;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Behavior {
+        Answer,
+        Fail,
+        Hang,
+    }
+
+    #[derive(Clone)]
+    struct MockValidator {
+        behavior: Arc<Mutex<Behavior>>,
+        calls: Arc<AtomicU64>,
+    }
+
+    impl MockValidator {
+        fn new(behavior: Behavior) -> Self {
+            Self {
+                behavior: Arc::new(Mutex::new(behavior)),
+                calls: Arc::default(),
+            }
+        }
+
+        fn set(&self, behavior: Behavior) {
+            *self.behavior.lock().unwrap() = behavior;
+        }
+
+        fn calls(&self) -> u64 {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NamespaceValidator for MockValidator {
+        async fn validate(&self, namespace: &str) -> ValidationOutcome {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let behavior = *self.behavior.lock().unwrap();
+            match behavior {
+                Behavior::Answer => ValidationOutcome::Exists(NamespaceMetadata {
+                    id: namespace.to_owned(),
+                    name: format!("Name of {}", namespace),
+                    created_at: 1,
+                    labels: BTreeMap::new(),
+                }),
+                Behavior::Fail => {
+                    ValidationOutcome::Unavailable(Arc::new(std::io::Error::other("down")))
+                }
+                Behavior::Hang => std::future::pending().await,
+            }
+        }
+    }
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            timeout: Duration::from_millis(100),
+            failure_threshold: 2,
+            open_for: Duration::from_secs(1),
+        }
+    }
+
+    fn is_error<E: std::error::Error + 'static>(outcome: &ValidationOutcome) -> bool {
+        matches!(outcome, ValidationOutcome::Unavailable(error) if error.is::<E>())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_times_out_slow_lookups() {
+        let mock = MockValidator::new(Behavior::Hang);
+        let breaker = CircuitBreaker::new(mock.clone(), config());
+
+        let start = Instant::now();
+        assert!(is_error::<LookupTimedOut>(&breaker.validate("ns1").await));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_opens_after_consecutive_failures_and_probes_once() {
+        let mock = MockValidator::new(Behavior::Fail);
+        let breaker = CircuitBreaker::new(mock.clone(), config());
+
+        breaker.validate("ns1").await;
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.validate("ns1").await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(is_error::<CircuitOpen>(&breaker.validate("ns1").await));
+        assert_eq!(mock.calls(), 2, "not called while open");
+
+        // A failed probe opens the circuit again.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!is_error::<CircuitOpen>(&breaker.validate("ns1").await));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(mock.calls(), 3);
+
+        // While a probe is running, the other lookups don't wait for it.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        mock.set(Behavior::Hang);
+        let probe = tokio::spawn({
+            let breaker = breaker.clone();
+            async move { breaker.validate("ns1").await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(is_error::<CircuitOpen>(&breaker.validate("ns2").await));
+        assert!(is_error::<LookupTimedOut>(&probe.await.unwrap()));
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // A successful probe closes it.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        mock.set(Behavior::Answer);
+        assert!(matches!(
+            breaker.validate("ns1").await,
+            ValidationOutcome::Exists(_)
+        ));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_probe_lets_the_next_lookup_probe() {
+        let mock = MockValidator::new(Behavior::Fail);
+        let breaker = CircuitBreaker::new(mock.clone(), config());
+        breaker.validate("ns1").await;
+        breaker.validate("ns1").await;
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        mock.set(Behavior::Hang);
+        let probe = tokio::spawn({
+            let breaker = breaker.clone();
+            async move { breaker.validate("ns1").await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        probe.abort();
+        assert!(probe.await.unwrap_err().is_cancelled());
+
+        mock.set(Behavior::Answer);
+        assert!(matches!(
+            breaker.validate("ns1").await,
+            ValidationOutcome::Exists(_)
+        ));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lookups_admitted_before_opening_do_not_end_the_probe() {
+        let mock = MockValidator::new(Behavior::Hang);
+        let breaker = CircuitBreaker::new(
+            mock.clone(),
+            CircuitBreakerConfig {
+                timeout: Duration::from_secs(10),
+                ..config()
+            },
+        );
+        let spawn_lookup = || {
+            let breaker = breaker.clone();
+            tokio::spawn(async move { breaker.validate("ns1").await })
+        };
+        let cancelled = spawn_lookup();
+        let timed_out = spawn_lookup();
+        tokio::task::yield_now().await;
+
+        mock.set(Behavior::Fail);
+        breaker.validate("ns1").await;
+        breaker.validate("ns1").await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        mock.set(Behavior::Hang);
+        let probe = spawn_lookup();
+        tokio::task::yield_now().await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        cancelled.abort();
+        assert!(cancelled.await.unwrap_err().is_cancelled());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(is_error::<CircuitOpen>(&breaker.validate("ns2").await));
+
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert!(is_error::<LookupTimedOut>(&timed_out.await.unwrap()));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(mock.calls(), 5, "a single probe");
+
+        probe.abort();
+        assert!(probe.await.unwrap_err().is_cancelled());
+        mock.set(Behavior::Answer);
+        assert!(matches!(
+            breaker.validate("ns1").await,
+            ValidationOutcome::Exists(_)
+        ));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_success_resets_failure_count() {
+        let mock = MockValidator::new(Behavior::Fail);
+        let breaker = CircuitBreaker::new(mock.clone(), config());
+
+        breaker.validate("ns1").await;
+        mock.set(Behavior::Answer);
+        breaker.validate("ns1").await;
+        mock.set(Behavior::Fail);
+        breaker.validate("ns1").await;
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    async fn status_with_open_circuit(mode: FailureMode) -> (StatusCode, Option<String>) {
+        let breaker = CircuitBreaker::new(MockValidator::new(Behavior::Fail), config());
+        breaker.validate("ns1").await;
+        breaker.validate("ns1").await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let app = Router::new()
+            .route(
+                "/namespaces/:namespace",
+                get(|Extension(metadata): Extension<NamespaceMetadata>| async move {
+                    metadata.name
+                }),
+            )
+            .layer(ValidateNamespaceLayer::new(breaker).when_circuit_open(mode));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/namespaces/ns1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        (
+            status,
+            (status == StatusCode::OK).then(|| String::from_utf8(body.to_vec()).unwrap()),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_layer_fails_closed_or_open() {
+        assert_eq!(
+            status_with_open_circuit(FailureMode::FailClosed).await,
+            (StatusCode::SERVICE_UNAVAILABLE, None)
+        );
+        // Unverified namespaces only have their id.
+        assert_eq!(
+            status_with_open_circuit(FailureMode::FailOpen).await,
+            (StatusCode::OK, Some("ns1".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::layers::{NamespaceValidator, ValidationOutcome};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// Namespaces kept, the least recently used is evicted first.
+    pub capacity: usize,
+    /// How long `Exists` is trusted.
+    pub positive_ttl: Duration,
+    /// How long the other outcomes but `Unavailable` are trusted, usually shorter so that
+    /// a namespace is usable soon after it's created elsewhere.
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            positive_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to wait for the inner validator.
+    pub misses: u64,
+    /// Calls to the inner validator, lower than the misses when lookups were coalesced.
+    pub loads: u64,
+}
+
+/// Caches the outcomes of another validator.
+///
+/// Concurrent misses for the same namespace share a single call to the inner validator.
+/// `Unavailable` is never cached. Namespaces created or deleted through this process should
+/// be [invalidated](Self::invalidate), others are picked up when their entry expires.
+#[derive(Clone)]
+pub struct CachedValidator<V> {
+    inner: V,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    config: CacheConfig,
+    entries: Mutex<Lru>,
+    inflight: Mutex<HashMap<String, Arc<OnceCell<ValidationOutcome>>>>,
+    /// Bumped by every invalidation, loads started before one aren't cached.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    loads: AtomicU64,
+}
+
+struct Entry {
+    outcome: ValidationOutcome,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Entries with their recency, `by_use` maps the last use of every entry to its namespace.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn get(&mut self, namespace: &str, now: Instant) -> Option<ValidationOutcome> {
+        let entry = self.entries.get_mut(namespace)?;
+        if entry.expires_at <= now {
+            self.remove(namespace);
+            return None;
+        }
+        self.clock += 1;
+        self.by_use.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.by_use.insert(self.clock, namespace.to_owned());
+        Some(entry.outcome.clone())
+    }
+
+    fn insert(&mut self, namespace: &str, outcome: ValidationOutcome, expires_at: Instant) {
+        self.remove(namespace);
+        self.clock += 1;
+        self.entries.insert(
+            namespace.to_owned(),
+            Entry {
+                outcome,
+                expires_at,
+                last_used: self.clock,
+            },
+        );
+        self.by_use.insert(self.clock, namespace.to_owned());
+    }
+
+    fn remove(&mut self, namespace: &str) {
+        if let Some(entry) = self.entries.remove(namespace) {
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+
+    fn evict_to(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            let Some((_, namespace)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.entries.remove(&namespace);
+        }
+    }
+}
+
+impl<V: NamespaceValidator> CachedValidator<V> {
+    pub fn new(inner: V, config: CacheConfig) -> Self {
+        Self {
+            inner,
+            shared: Arc::new(Shared {
+                config,
+                entries: Mutex::new(Lru::default()),
+                inflight: Mutex::new(HashMap::new()),
+                generation: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                loads: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Forgets `namespace`, the next lookup goes to the inner validator.
+    pub fn invalidate(&self, namespace: &str) {
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        self.shared.entries.lock().unwrap().remove(namespace);
+        self.shared.inflight.lock().unwrap().remove(namespace);
+    }
+
+    pub fn invalidate_all(&self) {
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        *self.shared.entries.lock().unwrap() = Lru::default();
+        self.shared.inflight.lock().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            loads: self.shared.loads.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Calls the inner validator and caches what it found, unless invalidated meanwhile.
+    async fn load(&self, namespace: &str) -> ValidationOutcome {
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        self.shared.loads.fetch_add(1, Ordering::Relaxed);
+        let outcome = self.inner.validate(namespace).await;
+
+        let ttl = match outcome {
+            ValidationOutcome::Exists(_) => self.shared.config.positive_ttl,
+            ValidationOutcome::NotFound
+            | ValidationOutcome::Forbidden
+            | ValidationOutcome::Disabled
+            | ValidationOutcome::Undecided => self.shared.config.negative_ttl,
+            ValidationOutcome::Unavailable(_) => return outcome,
+        };
+        let mut entries = self.shared.entries.lock().unwrap();
+        if self.shared.generation.load(Ordering::SeqCst) == generation {
+            entries.insert(namespace, outcome.clone(), Instant::now() + ttl);
+            entries.evict_to(self.shared.config.capacity);
+        }
+        outcome
+    }
+}
+
+#[async_trait::async_trait]
+impl<V: NamespaceValidator> NamespaceValidator for CachedValidator<V> {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        let cached = self
+            .shared
+            .entries
+            .lock()
+            .unwrap()
+            .get(namespace, Instant::now());
+        if let Some(outcome) = cached {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return outcome;
+        }
+        self.shared.misses.fetch_add(1, Ordering::Relaxed);
+
+        let cell = self
+            .shared
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(namespace.to_owned())
+            .or_default()
+            .clone();
+        // Only one caller runs the load, the others wait for its outcome. Should it be
+        // cancelled, one of them takes over.
+        let outcome = cell.get_or_init(|| self.load(namespace)).await.clone();
+
+        let mut inflight = self.shared.inflight.lock().unwrap();
+        if inflight
+            .get(namespace)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            inflight.remove(namespace);
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::NamespaceMetadata;
+    use std::collections::BTreeMap;
+
+    /// Knows the namespaces starting with `ns`, takes `delay` to answer.
+    #[derive(Clone, Default)]
+    struct MockValidator {
+        delay: Duration,
+        unavailable: bool,
+        calls: Arc<AtomicU64>,
+    }
+
+    #[async_trait::async_trait]
+    impl NamespaceValidator for MockValidator {
+        async fn validate(&self, namespace: &str) -> ValidationOutcome {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            if self.unavailable {
+                ValidationOutcome::Unavailable(Arc::new(std::io::Error::other("down")))
+            } else if namespace.starts_with("ns") {
+                ValidationOutcome::Exists(NamespaceMetadata {
+                    id: namespace.to_owned(),
+                    name: namespace.to_owned(),
+                    created_at: 0,
+                    labels: BTreeMap::new(),
+                })
+            } else {
+                ValidationOutcome::NotFound
+            }
+        }
+    }
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            capacity: 2,
+            positive_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_positive_and_negative_results_expire_separately() {
+        let mock = MockValidator::default();
+        let cache = CachedValidator::new(mock.clone(), config());
+
+        assert!(matches!(
+            cache.validate("ns1").await,
+            ValidationOutcome::Exists(_)
+        ));
+        assert!(matches!(
+            cache.validate("other").await,
+            ValidationOutcome::NotFound
+        ));
+        cache.validate("ns1").await;
+        cache.validate("other").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        cache.validate("ns1").await;
+        cache.validate("other").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 3, "negative expired");
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        cache.validate("ns1").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 4, "positive expired");
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 4,
+                loads: 4
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let mock = MockValidator::default();
+        let cache = CachedValidator::new(mock.clone(), config());
+
+        cache.validate("ns1").await;
+        cache.validate("ns2").await;
+        // ns1 is now more recent than ns2.
+        cache.validate("ns1").await;
+        cache.validate("ns3").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 3);
+
+        cache.validate("ns1").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 3, "ns1 kept");
+        cache.validate("ns2").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 4, "ns2 evicted");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesces_concurrent_lookups() {
+        let mock = MockValidator {
+            delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let cache = CachedValidator::new(mock.clone(), config());
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.validate("ns1").await })
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(matches!(
+                lookup.await.unwrap(),
+                ValidationOutcome::Exists(_)
+            ));
+        }
+
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().misses, 10);
+        assert_eq!(cache.stats().loads, 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_and_unavailable_backend() {
+        let mock = MockValidator::default();
+        let cache = CachedValidator::new(mock.clone(), config());
+
+        cache.validate("ns1").await;
+        cache.invalidate("ns1");
+        cache.validate("ns1").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 2);
+        cache.invalidate_all();
+        cache.validate("ns1").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 3);
+
+        let mock = MockValidator {
+            unavailable: true,
+            ..Default::default()
+        };
+        let cache = CachedValidator::new(mock.clone(), config());
+        cache.validate("ns1").await;
+        cache.validate("ns1").await;
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 2, "never cached");
+    }
+}
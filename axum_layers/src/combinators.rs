@@ -0,0 +1,216 @@
+//! Policies made of several validators, for example a static denylist in front of a cached
+//! lookup in the store:
+//!
+//! ```ignore
+//! FirstDecisive(denylist, CachedValidator::new(RocksDbValidator::new(registry), config))
+//! ```
+//!
+//! Combinators take two validators and nest for more.
+use crate::layers::{NamespaceValidator, ValidationOutcome};
+
+/// Lets a namespace through when both validators do, with the metadata of the second.
+/// Otherwise answers the first refusal, without asking the second after a refusal of the first.
+#[derive(Clone)]
+pub struct AllOf<A, B>(pub A, pub B);
+
+#[async_trait::async_trait]
+impl<A: NamespaceValidator, B: NamespaceValidator> NamespaceValidator for AllOf<A, B> {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        match self.0.validate(namespace).await {
+            ValidationOutcome::Exists(_) => self.1.validate(namespace).await,
+            refused => refused,
+        }
+    }
+}
+
+/// Lets a namespace through when either validator does, asking the second only when the first
+/// refuses. When both refuse, answers `Unavailable` if either was, since it might have let the
+/// namespace through, and the first decisive refusal otherwise.
+#[derive(Clone)]
+pub struct AnyOf<A, B>(pub A, pub B);
+
+#[async_trait::async_trait]
+impl<A: NamespaceValidator, B: NamespaceValidator> NamespaceValidator for AnyOf<A, B> {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        let first = self.0.validate(namespace).await;
+        if let ValidationOutcome::Exists(_) = first {
+            return first;
+        }
+        match (first, self.1.validate(namespace).await) {
+            (_, second @ ValidationOutcome::Exists(_)) => second,
+            (unavailable @ ValidationOutcome::Unavailable(_), _)
+            | (_, unavailable @ ValidationOutcome::Unavailable(_)) => unavailable,
+            (ValidationOutcome::Undecided, second) => second,
+            (first, _) => first,
+        }
+    }
+}
+
+/// Answers what the first validator decides, asking the second only when the first is
+/// [`Undecided`](ValidationOutcome::Undecided).
+#[derive(Clone)]
+pub struct FirstDecisive<A, B>(pub A, pub B);
+
+#[async_trait::async_trait]
+impl<A: NamespaceValidator, B: NamespaceValidator> NamespaceValidator for FirstDecisive<A, B> {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        match self.0.validate(namespace).await {
+            ValidationOutcome::Undecided => self.1.validate(namespace).await,
+            decided => decided,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::NamespaceMetadata;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Answers with `outcome`, counting its calls.
+    #[derive(Clone)]
+    struct Fixed {
+        outcome: fn(&str) -> ValidationOutcome,
+        calls: Arc<AtomicU64>,
+    }
+
+    fn fixed(outcome: fn(&str) -> ValidationOutcome) -> Fixed {
+        Fixed {
+            outcome,
+            calls: Arc::default(),
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NamespaceValidator for Fixed {
+        async fn validate(&self, namespace: &str) -> ValidationOutcome {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            (self.outcome)(namespace)
+        }
+    }
+
+    fn exists(name: &str) -> ValidationOutcome {
+        ValidationOutcome::Exists(NamespaceMetadata::unverified(name))
+    }
+
+    fn exists_a(_: &str) -> ValidationOutcome {
+        exists("a")
+    }
+
+    fn exists_b(_: &str) -> ValidationOutcome {
+        exists("b")
+    }
+
+    fn not_found(_: &str) -> ValidationOutcome {
+        ValidationOutcome::NotFound
+    }
+
+    fn forbidden(_: &str) -> ValidationOutcome {
+        ValidationOutcome::Forbidden
+    }
+
+    fn undecided(_: &str) -> ValidationOutcome {
+        ValidationOutcome::Undecided
+    }
+
+    fn unavailable(_: &str) -> ValidationOutcome {
+        ValidationOutcome::Unavailable(Arc::new(std::io::Error::other("down")))
+    }
+
+    /// Name of the metadata when it exists, the variant otherwise.
+    fn summary(outcome: ValidationOutcome) -> String {
+        match outcome {
+            ValidationOutcome::Exists(metadata) => metadata.name,
+            ValidationOutcome::NotFound => "NotFound".into(),
+            ValidationOutcome::Forbidden => "Forbidden".into(),
+            ValidationOutcome::Disabled => "Disabled".into(),
+            ValidationOutcome::Unavailable(_) => "Unavailable".into(),
+            ValidationOutcome::Undecided => "Undecided".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_all_of() {
+        assert_eq!(
+            summary(AllOf(fixed(exists_a), fixed(exists_b)).validate("ns").await),
+            "b"
+        );
+        assert_eq!(
+            summary(
+                AllOf(fixed(exists_a), fixed(forbidden))
+                    .validate("ns")
+                    .await
+            ),
+            "Forbidden"
+        );
+
+        let second = fixed(exists_b);
+        let all = AllOf(fixed(not_found), second.clone());
+        assert_eq!(summary(all.validate("ns").await), "NotFound");
+        assert_eq!(second.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_any_of() {
+        let second = fixed(exists_b);
+        let any = AnyOf(fixed(exists_a), second.clone());
+        assert_eq!(summary(any.validate("ns").await), "a");
+        assert_eq!(second.calls.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            summary(
+                AnyOf(fixed(not_found), fixed(exists_b))
+                    .validate("ns")
+                    .await
+            ),
+            "b"
+        );
+        assert_eq!(
+            summary(
+                AnyOf(fixed(forbidden), fixed(not_found))
+                    .validate("ns")
+                    .await
+            ),
+            "Forbidden"
+        );
+        assert_eq!(
+            summary(
+                AnyOf(fixed(undecided), fixed(not_found))
+                    .validate("ns")
+                    .await
+            ),
+            "NotFound"
+        );
+        assert_eq!(
+            summary(
+                AnyOf(fixed(not_found), fixed(unavailable))
+                    .validate("ns")
+                    .await
+            ),
+            "Unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_first_decisive_chains() {
+        let last = fixed(exists_b);
+        let chain = FirstDecisive(
+            fixed(undecided),
+            FirstDecisive(fixed(forbidden), last.clone()),
+        );
+        assert_eq!(summary(chain.validate("ns").await), "Forbidden");
+        assert_eq!(last.calls.load(Ordering::SeqCst), 0);
+
+        let chain = FirstDecisive(fixed(undecided), FirstDecisive(fixed(undecided), last));
+        assert_eq!(summary(chain.validate("ns").await), "b");
+        assert_eq!(
+            summary(
+                FirstDecisive(fixed(undecided), fixed(undecided))
+                    .validate("ns")
+                    .await
+            ),
+            "Undecided"
+        );
+    }
+}
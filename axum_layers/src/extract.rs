@@ -0,0 +1,97 @@
+use axum::{extract::FromRequestParts, http::request::Parts, http::StatusCode};
+use std::ops::Deref;
+
+use crate::layers::NamespaceMetadata;
+
+/// The namespace of the request, as resolved by the
+/// [`ValidateNamespaceLayer`](crate::layers::ValidateNamespaceLayer), without a second lookup.
+///
+/// Rejects with a 500 when the layer isn't installed on the route: that's a bug of the
+/// router, not of the request.
+#[derive(Clone, Debug)]
+pub struct ValidatedNamespace(pub NamespaceMetadata);
+
+impl Deref for ValidatedNamespace {
+    type Target = NamespaceMetadata;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ValidatedNamespace {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<NamespaceMetadata>()
+            .cloned()
+            .map(Self)
+            .ok_or_else(|| {
+                tracing::error!(
+                    "No validated namespace for {}, is ValidateNamespaceLayer installed?",
+                    parts.uri.path()
+                );
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Namespace validation layer is not installed",
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::ValidateNamespaceLayer;
+    use crate::static_list::{StaticListValidator, StaticLists};
+    use axum::{body::Body, extract::Request, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::util::ServiceExt;
+
+    async fn handler(namespace: ValidatedNamespace) -> String {
+        namespace.name.clone()
+    }
+
+    async fn get_uri(app: Router, uri: &str) -> (StatusCode, String) {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_extracts_namespace_resolved_by_layer() {
+        let app = Router::new()
+            .route("/namespaces/:namespace", get(handler))
+            .layer(ValidateNamespaceLayer::new(StaticListValidator::new(
+                StaticLists {
+                    allow: ["ns1".to_owned()].into(),
+                    ..Default::default()
+                },
+            )));
+
+        assert_eq!(
+            get_uri(app, "/namespaces/ns1").await,
+            (StatusCode::OK, "ns1".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_without_layer() {
+        let app = Router::new().route("/namespaces/:namespace", get(handler));
+
+        assert_eq!(
+            get_uri(app, "/namespaces/ns1").await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Namespace validation layer is not installed".to_owned()
+            )
+        );
+    }
+}
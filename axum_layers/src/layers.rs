@@ -1,51 +1,107 @@
-use async_trait::async_trait;
 use axum::{
-    extract::{Path, Request},
-    http::StatusCode,
-    middleware::Next,
+    extract::{FromRequestParts, RawPathParams, Request},
+    http::{header, StatusCode},
     response::Response,
-    routing::Router,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use tower_layer::Layer;
 use tower_service::Service;
 
-// Define the namespace path parameter structure
-#[derive(Deserialize, Debug)]
-struct NamespacePath {
-    namespace: String,
+use crate::breaker::CircuitOpen;
+
+/// What the validator knows about a namespace, handed to handlers in the request extensions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamespaceMetadata {
+    pub id: String,
+    pub name: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl NamespaceMetadata {
+    /// Metadata of a namespace let through without being validated, only the id is known.
+    pub fn unverified(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            created_at: 0,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Error of the backend a validator relies on.
+pub type BackendError = Arc<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub enum ValidationOutcome {
+    Exists(NamespaceMetadata),
+    NotFound,
+    /// The namespace exists but the caller may not use it.
+    Forbidden,
+    /// The namespace exists but was disabled.
+    Disabled,
+    /// The validator couldn't tell, the request may be retried.
+    Unavailable(BackendError),
+    /// The validator has no opinion and leaves the decision to the next one, see
+    /// [`FirstDecisive`](crate::combinators::FirstDecisive). Answered as not found when no
+    /// validator decides.
+    Undecided,
 }
 
 // Trait for namespace validation
 #[async_trait::async_trait]
 pub trait NamespaceValidator: Clone + Send + Sync + 'static {
-    async fn namespace_exists(&self, namespace: &str) -> bool;
+    async fn validate(&self, namespace: &str) -> ValidationOutcome;
 }
 
-// Example validator implementation
-#[derive(Clone)]
-pub struct ExampleValidator;
+/// `Retry-After` of the 503 answered when the validator is unavailable, unless configured.
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
 
-#[async_trait::async_trait]
-impl NamespaceValidator for ExampleValidator {
-    async fn namespace_exists(&self, namespace: &str) -> bool {
-        // Replace this with your actual validation logic
-        // e.g., database lookup, API call, etc.
-        true
-    }
+/// What the layer does with requests while a [`CircuitBreaker`](crate::breaker::CircuitBreaker)
+/// is open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Lets them through with [`NamespaceMetadata::unverified`].
+    FailOpen,
+    /// Answers 503, like for any other unavailable validator.
+    #[default]
+    FailClosed,
 }
 
 // Layer struct that holds the validator
 #[derive(Clone)]
 pub struct ValidateNamespaceLayer<V> {
     validator: V,
+    retry_after: Duration,
+    when_circuit_open: FailureMode,
 }
 
 impl<V: NamespaceValidator> ValidateNamespaceLayer<V> {
     pub fn new(validator: V) -> Self {
-        Self { validator }
+        Self {
+            validator,
+            retry_after: DEFAULT_RETRY_AFTER,
+            when_circuit_open: FailureMode::default(),
+        }
+    }
+
+    /// `Retry-After` of the 503 answered when the validator is unavailable.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn when_circuit_open(mut self, mode: FailureMode) -> Self {
+        self.when_circuit_open = mode;
+        self
     }
 }
 
@@ -59,19 +115,31 @@ where
         ValidateNamespaceMiddleware {
             inner,
             validator: self.validator.clone(),
+            retry_after: self.retry_after,
+            when_circuit_open: self.when_circuit_open,
         }
     }
 }
 
+/// Validates the `:namespace` path parameter before calling the inner service, which then
+/// finds the [`NamespaceMetadata`] in the request extensions. Otherwise answers without
+/// calling it: 404 when not found, 403 when forbidden, 410 when disabled and 503 with
+/// `Retry-After` when the validator is unavailable, unless configured to fail open while a
+/// circuit breaker is open. Routes without that parameter go through unchecked.
+///
+/// Path parameters are only known once the request is routed, so the layer has to be added
+/// with `Router::layer`, or `Router::route_layer`, not around the whole router.
 #[derive(Clone)]
 pub struct ValidateNamespaceMiddleware<S, V> {
     inner: S,
     validator: V,
+    retry_after: Duration,
+    when_circuit_open: FailureMode,
 }
 
 impl<S, V> Service<Request> for ValidateNamespaceMiddleware<S, V>
 where
-    S: Service<Request, Response = Response> + Send + 'static,
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
     S::Future: Send + 'static,
     V: NamespaceValidator,
 {
@@ -88,36 +156,207 @@ where
 
     fn call(&mut self, req: Request) -> Self::Future {
         let validator = self.validator.clone();
-        let inner_future = self.inner.call(req);
+        let retry_after = self.retry_after;
+        let when_circuit_open = self.when_circuit_open;
+        // Take the service that was driven to readiness, leave a clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
 
         Box::pin(async move {
-            // Check if the path starts with /namespaces/
-            let path = req.uri().path();
-            if !path.starts_with("/namespaces/") {
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(axum::body::Body::empty())
-                    .unwrap());
-            }
+            let (mut parts, body) = req.into_parts();
+            let namespace = RawPathParams::from_request_parts(&mut parts, &())
+                .await
+                .ok()
+                .and_then(|params| {
+                    params
+                        .iter()
+                        .find(|(key, _)| *key == "namespace")
+                        .map(|(_, namespace)| namespace.to_owned())
+                });
 
-            let matched_path = req
-                .extensions()
-                .get::<MatchedPath>()
-                .map(|matched_path| matched_path.as_str());
-
-            // Extract and validate the namespace
-            if let Ok(Path(NamespacePath { namespace })) =
-                Path::<NamespacePath>::try_from(req.uri())
-            {
-                if validator.namespace_exists(&namespace).await {
-                    return inner_future.await;
+            if let Some(namespace) = namespace {
+                match validator.validate(&namespace).await {
+                    ValidationOutcome::Exists(metadata) => {
+                        parts.extensions.insert(metadata);
+                    }
+                    ValidationOutcome::Unavailable(error)
+                        if when_circuit_open == FailureMode::FailOpen
+                            && error.is::<CircuitOpen>() =>
+                    {
+                        tracing::warn!("Letting namespace {} through unverified", namespace);
+                        parts
+                            .extensions
+                            .insert(NamespaceMetadata::unverified(&namespace));
+                    }
+                    outcome => return Ok(rejection(&namespace, outcome, retry_after)),
                 }
             }
 
-            Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(axum::body::Body::empty())
-                .unwrap())
+            inner.call(Request::from_parts(parts, body)).await
         })
     }
 }
+
+/// Response to a request for a namespace that can't be used.
+fn rejection(namespace: &str, outcome: ValidationOutcome, retry_after: Duration) -> Response {
+    let (status, body) = match outcome {
+        ValidationOutcome::Exists(_) => unreachable!("existing namespaces are not rejected"),
+        ValidationOutcome::NotFound | ValidationOutcome::Undecided => {
+            (StatusCode::NOT_FOUND, "Namespace not found")
+        }
+        ValidationOutcome::Forbidden => (StatusCode::FORBIDDEN, "Namespace forbidden"),
+        ValidationOutcome::Disabled => (StatusCode::GONE, "Namespace disabled"),
+        ValidationOutcome::Unavailable(error) => {
+            tracing::warn!("Can't validate namespace {}: {}", namespace, error);
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+                .body("Namespace validation unavailable".into())
+                .unwrap();
+        }
+    };
+    tracing::debug!("Rejected namespace {}: {}", namespace, body);
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use http_body_util::BodyExt;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tower::util::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct MockValidator {
+        outcomes: HashMap<&'static str, ValidationOutcome>,
+        lookups: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockValidator {
+        fn new(outcomes: impl IntoIterator<Item = (&'static str, ValidationOutcome)>) -> Self {
+            Self {
+                outcomes: outcomes.into_iter().collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NamespaceValidator for MockValidator {
+        async fn validate(&self, namespace: &str) -> ValidationOutcome {
+            self.lookups.lock().unwrap().push(namespace.to_owned());
+            self.outcomes
+                .get(namespace)
+                .cloned()
+                .unwrap_or(ValidationOutcome::NotFound)
+        }
+    }
+
+    fn metadata(id: &str) -> NamespaceMetadata {
+        NamespaceMetadata {
+            id: id.to_owned(),
+            name: format!("Name of {}", id),
+            created_at: 0,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    fn app(validator: MockValidator) -> Router {
+        Router::new()
+            .route(
+                "/namespaces/:namespace",
+                get(|Extension(metadata): Extension<NamespaceMetadata>| async move {
+                    metadata.name
+                }),
+            )
+            .route("/namespaces/:namespace/keys/:key", get(|| async { "key" }))
+            .route("/health", get(|| async { "ok" }))
+            .layer(ValidateNamespaceLayer::new(validator).retry_after(Duration::from_secs(7)))
+    }
+
+    async fn get_uri(app: Router, uri: &str) -> Response {
+        app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn status(app: Router, uri: &str) -> StatusCode {
+        get_uri(app, uri).await.status()
+    }
+
+    #[tokio::test]
+    async fn test_known_namespace_reaches_handler_with_metadata() {
+        let validator = MockValidator::new([("ns1", ValidationOutcome::Exists(metadata("ns1")))]);
+
+        let response = get_uri(app(validator.clone()), "/namespaces/ns1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Name of ns1");
+        assert_eq!(
+            status(app(validator.clone()), "/namespaces/ns1/keys/k").await,
+            StatusCode::OK
+        );
+        assert_eq!(*validator.lookups.lock().unwrap(), vec!["ns1", "ns1"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_namespaces_do_not_reach_handler() {
+        let validator = MockValidator::new([
+            ("forbidden", ValidationOutcome::Forbidden),
+            ("disabled", ValidationOutcome::Disabled),
+        ]);
+
+        for (namespace, expected) in [
+            ("unknown", StatusCode::NOT_FOUND),
+            ("forbidden", StatusCode::FORBIDDEN),
+            ("disabled", StatusCode::GONE),
+        ] {
+            assert_eq!(
+                status(
+                    app(validator.clone()),
+                    &format!("/namespaces/{}", namespace)
+                )
+                .await,
+                expected
+            );
+            assert_eq!(
+                status(
+                    app(validator.clone()),
+                    &format!("/namespaces/{}/keys/k", namespace)
+                )
+                .await,
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_validator_asks_to_retry() {
+        let error: BackendError = Arc::new(std::io::Error::other("backend down"));
+        let validator = MockValidator::new([("ns1", ValidationOutcome::Unavailable(error))]);
+
+        let response = get_uri(app(validator), "/namespaces/ns1").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+    }
+
+    #[tokio::test]
+    async fn test_routes_without_namespace_are_not_validated() {
+        let validator = MockValidator::default();
+
+        assert_eq!(
+            status(app(validator.clone()), "/health").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(app(validator.clone()), "/nonexistent").await,
+            StatusCode::NOT_FOUND
+        );
+        assert!(validator.lookups.lock().unwrap().is_empty());
+    }
+}
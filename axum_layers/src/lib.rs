@@ -0,0 +1,7 @@
+pub mod breaker;
+pub mod cache;
+pub mod combinators;
+pub mod extract;
+pub mod layers;
+pub mod namespaces;
+pub mod static_list;
@@ -1,33 +1,562 @@
 use axum::{
-    extract::{MatchedPath, Path, RawPathParams, Request},
-    http::StatusCode,
+    body::Bytes,
+    extract::{MatchedPath, Path, Query, RawPathParams, Request, State},
+    http::{header, HeaderMap, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_layers::{
+    breaker::{CircuitBreaker, CircuitBreakerConfig},
+    cache::{CacheConfig, CachedValidator},
+    combinators::FirstDecisive,
+    extract::ValidatedNamespace,
+    layers::{FailureMode, NamespaceMetadata, ValidateNamespaceLayer},
+    namespaces::RocksDbValidator,
+    static_list::StaticListValidator,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rocksdb_transactiondb::{
+    kv::{self, Expected, KeyRange, KvStore, OpResult, PreconditionFailed},
+    namespace::{self, AlreadyExists, NamespaceRegistry},
+    pinned::{PinConfig, PinId, SnapshotExpired, SnapshotPins, TooManySnapshots},
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-// mod layers;
+#[derive(Clone)]
+struct AppState {
+    registry: Arc<NamespaceRegistry>,
+    kv: Arc<KvStore>,
+    /// Snapshots of the listings being paged through.
+    pins: Arc<SnapshotPins>,
+    /// Invalidated on creation and deletion, so that they're visible right away.
+    cache: CachedValidator<CircuitBreaker<RocksDbValidator>>,
+}
+
+/// Answers 503 for writes that lost to concurrent ones, see [`kv::is_contention`], and 500
+/// for the other errors of the store.
+struct AppError(anyhow::Error);
 
-#[derive(Serialize, Deserialize)]
-struct Namespace {
-    id: String,
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if kv::is_contention(&self.0) {
+            tracing::warn!("{:#}", self.0);
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                "Key is locked by another transaction, retry",
+            )
+                .into_response();
+        }
+        tracing::error!("{:#}", self.0);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response()
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(error: E) -> Self {
+        Self(error.into())
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateNamespace {
     name: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+async fn list_namespaces(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<NamespaceMetadata>>, AppError> {
+    let namespaces = tokio::task::spawn_blocking(move || state.registry.list()).await??;
+    Ok(Json(namespaces.into_iter().map(Into::into).collect()))
+}
+
+async fn create_namespace(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<CreateNamespace>,
+) -> Result<Response, AppError> {
+    if let Err(error) = namespace::validate_id(&id) {
+        return Ok((StatusCode::BAD_REQUEST, error.to_string()).into_response());
+    }
+
+    let registry = state.registry.clone();
+    let created = tokio::task::spawn_blocking({
+        let id = id.clone();
+        move || registry.create(&id, &request.name, request.labels)
+    })
+    .await?;
+    state.cache.invalidate(&id);
+    match created {
+        Ok(namespace) => Ok((
+            StatusCode::CREATED,
+            Json(NamespaceMetadata::from(namespace)),
+        )
+            .into_response()),
+        Err(error) if error.is::<AlreadyExists>() => {
+            Ok((StatusCode::CONFLICT, error.to_string()).into_response())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn get_namespace(ValidatedNamespace(namespace): ValidatedNamespace) -> impl IntoResponse {
+    axum::Json(namespace)
+}
+
+async fn delete_namespace(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+) -> Result<StatusCode, AppError> {
+    let registry = state.registry.clone();
+    let kv = state.kv.clone();
+    let id = namespace.id.clone();
+    let deleted = tokio::task::spawn_blocking(move || -> anyhow::Result<bool> {
+        if !registry.delete(&id)? {
+            return Ok(false);
+        }
+        // Keys written while the namespace is being deleted may survive, they are only
+        // reachable again if a namespace with the same id is created.
+        kv.clear(&id)?;
+        Ok(true)
+    })
+    .await??;
+    state.cache.invalidate(&namespace.id);
+    // Deleted concurrently since it was validated.
+    Ok(if deleted {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Content type of the values stored without one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Answers the value as it was stored, `HEAD` is answered by the same route without the body.
+async fn get_key(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+    Path((_, key)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    let kv = state.kv.clone();
+    let entry =
+        tokio::task::spawn_blocking(move || kv.get(&namespace.id, key.as_bytes())).await??;
+    let Some(entry) = entry else {
+        return Ok((StatusCode::NOT_FOUND, "Key not found").into_response());
+    };
+    let content_type = entry
+        .content_type
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned());
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::ETAG, etag(entry.version)),
+        ],
+        entry.value,
+    )
+        .into_response())
+}
+
+/// Stores the body as is with its content type, answers 201 when the key is new and 204 when
+/// it was replaced.
+async fn put_key(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    value: Bytes,
+) -> Result<Response, AppError> {
+    let content_type = match headers
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.to_str())
+    {
+        Some(Ok(content_type)) => Some(content_type.to_owned()),
+        Some(Err(_)) => {
+            return Ok((StatusCode::BAD_REQUEST, "Invalid content type").into_response())
+        }
+        None => None,
+    };
+    let kv = state.kv.clone();
+    let written = tokio::task::spawn_blocking(move || {
+        kv.put(
+            &namespace.id,
+            key.as_bytes(),
+            &value,
+            content_type.as_deref(),
+        )
+    })
+    .await??;
+    let status = if written.created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::NO_CONTENT
+    };
+    Ok((status, [(header::ETAG, etag(written.version))]).into_response())
+}
+
+async fn delete_key(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+    Path((_, key)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let kv = state.kv.clone();
+    let deleted =
+        tokio::task::spawn_blocking(move || kv.delete(&namespace.id, key.as_bytes())).await??;
+    Ok(if deleted {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Keys listed when the request doesn't say.
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct ListKeys {
+    prefix: Option<String>,
+    start_after: Option<String>,
+    /// Between 1 and [`MAX_LIST_LIMIT`], clamped otherwise.
+    limit: Option<usize>,
+    /// Continues a listing, which `prefix` and `start_after` can't change.
+    cursor: Option<String>,
+    /// List the values, with their content type.
+    #[serde(default)]
+    values: bool,
+    /// List the sizes of the values.
+    #[serde(default)]
+    sizes: bool,
+}
+
+#[derive(Serialize)]
+struct ListedKey {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    /// Base64 encoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct KeyListing {
+    keys: Vec<ListedKey>,
+    /// Continues the listing, `None` after the last page.
+    cursor: Option<String>,
+}
+
+/// Where a listing stopped, handed to clients as opaque base64.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    namespace: String,
+    /// Pinned snapshot every page is read at.
+    snapshot: u64,
+    /// Secret of the pin, without it the cursor can't be forged from another listing's.
+    secret: u64,
+    prefix: String,
+    after: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("cursors serialize"))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+}
+
+/// Lists keys in order, page by page. Every page of a listing is read at the snapshot taken
+/// for its first page, which stays pinned until the last page or for [`PinConfig::ttl`];
+/// continuing after that answers 410.
+async fn list_keys(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+    Query(query): Query<ListKeys>,
+) -> Result<Response, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let (pin, range) = match query.cursor {
+        Some(cursor) => match Cursor::decode(&cursor) {
+            Some(cursor) if cursor.namespace == namespace.id => (
+                Some(PinId {
+                    seq: cursor.snapshot,
+                    secret: cursor.secret,
+                }),
+                KeyRange {
+                    prefix: cursor.prefix.into_bytes(),
+                    start_after: Some(cursor.after.into_bytes()),
+                },
+            ),
+            _ => return Ok((StatusCode::BAD_REQUEST, "Invalid cursor").into_response()),
+        },
+        None => (
+            None,
+            KeyRange {
+                prefix: query.prefix.unwrap_or_default().into_bytes(),
+                start_after: query.start_after.map(String::into_bytes),
+            },
+        ),
+    };
+
+    let pins = state.pins.clone();
+    let id = namespace.id.clone();
+    let prefix = range.prefix.clone();
+    let listed = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let pin = match pin {
+            Some(pin) => pin,
+            None => pins.pin()?,
+        };
+        let page = pins.with(pin, move |db, snapshot| {
+            kv::scan(db, Some(snapshot), &id, &range, limit)
+        })??;
+        if !page.more {
+            pins.release(pin);
+        }
+        Ok((pin, page))
+    })
+    .await?;
+    let (pin, page) = match listed {
+        Ok(listed) => listed,
+        Err(error) if error.is::<SnapshotExpired>() => {
+            return Ok(
+                (StatusCode::GONE, "Cursor expired, start the listing again").into_response(),
+            )
+        }
+        Err(error) if error.is::<TooManySnapshots>() => {
+            tracing::warn!("Can't list keys of {}: {}", namespace.id, error);
+            return Ok((
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                "Too many listings in progress",
+            )
+                .into_response());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let cursor = match page.entries.last() {
+        Some((last, _)) if page.more => Some(
+            Cursor {
+                namespace: namespace.id.clone(),
+                snapshot: pin.seq,
+                secret: pin.secret,
+                prefix: String::from_utf8_lossy(&prefix).into_owned(),
+                after: String::from_utf8_lossy(last).into_owned(),
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+    let keys = page
+        .entries
+        .into_iter()
+        .map(|(key, entry)| ListedKey {
+            key: String::from_utf8_lossy(&key).into_owned(),
+            size: query.sizes.then_some(entry.value.len()),
+            value: query.values.then(|| URL_SAFE_NO_PAD.encode(&entry.value)),
+            content_type: if query.values {
+                entry.content_type
+            } else {
+                None
+            },
+        })
+        .collect();
+    Ok(Json(KeyListing { keys, cursor }).into_response())
+}
+
+/// Operations accepted in a single transaction.
+const MAX_TXN_OPS: usize = 100;
+
+#[derive(Deserialize)]
+struct TxnRequest {
+    ops: Vec<TxnOp>,
+}
+
+/// Operation of a transaction, values are base64 encoded like in key listings.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum TxnOp {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        content_type: Option<String>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Fails the transaction unless the key has this value, `null` when it must be missing.
+    CheckValue {
+        key: String,
+        value: Option<String>,
+    },
+    /// Fails the transaction unless the key has this version, `null` when it must be missing.
+    CheckVersion {
+        key: String,
+        version: Option<u64>,
+    },
+}
+
+impl TxnOp {
+    fn decode(self) -> Result<kv::Op, base64::DecodeError> {
+        let decode = |value: String| URL_SAFE_NO_PAD.decode(value);
+        Ok(match self {
+            Self::Get { key } => kv::Op::Get {
+                key: key.into_bytes(),
+            },
+            Self::Put {
+                key,
+                value,
+                content_type,
+            } => kv::Op::Put {
+                key: key.into_bytes(),
+                value: decode(value)?,
+                content_type,
+            },
+            Self::Delete { key } => kv::Op::Delete {
+                key: key.into_bytes(),
+            },
+            Self::CheckValue { key, value } => kv::Op::Check {
+                key: key.into_bytes(),
+                expected: Expected::Value(value.map(decode).transpose()?),
+            },
+            Self::CheckVersion { key, version } => kv::Op::Check {
+                key: key.into_bytes(),
+                expected: Expected::Version(version),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum TxnResult {
+    Get {
+        found: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    Put {
+        version: u64,
+        created: bool,
+    },
+    Delete {
+        deleted: bool,
+    },
+    Check,
+}
+
+impl From<OpResult> for TxnResult {
+    fn from(result: OpResult) -> Self {
+        match result {
+            OpResult::Got(entry) => Self::Get {
+                found: entry.is_some(),
+                value: entry
+                    .as_ref()
+                    .map(|entry| URL_SAFE_NO_PAD.encode(&entry.value)),
+                version: entry.as_ref().map(|entry| entry.version),
+                content_type: entry.and_then(|entry| entry.content_type),
+            },
+            OpResult::Put(written) => Self::Put {
+                version: written.version,
+                created: written.created,
+            },
+            OpResult::Deleted(deleted) => Self::Delete { deleted },
+            OpResult::Checked => Self::Check,
+        }
+    }
 }
 
-async fn get_namespace(Path(id): Path<String>) -> impl IntoResponse {
-    let name = format!("Namespace {}", id);
-    let user = Namespace { id, name };
-    axum::Json(user)
+#[derive(Serialize)]
+struct TxnResponse {
+    results: Vec<TxnResult>,
 }
 
-async fn get_namespace_key(Path((id, key)): Path<(String, String)>) -> impl IntoResponse {
-    axum::Json(format!("Namespace {} key {}", id, key))
+/// Body of the 409 answered when a check fails.
+#[derive(Serialize)]
+struct TxnConflict {
+    error: String,
+    /// Position of the failed check among the operations.
+    index: usize,
+    key: String,
+    /// `None` when the key is missing.
+    actual_version: Option<u64>,
+}
+
+/// Applies the operations in order in a single transaction, every checked key is locked until
+/// the commit. Answers the result of every operation, or 409 without writing anything when a
+/// check fails.
+async fn apply_txn(
+    State(state): State<AppState>,
+    namespace: ValidatedNamespace,
+    Json(request): Json<TxnRequest>,
+) -> Result<Response, AppError> {
+    if request.ops.len() > MAX_TXN_OPS {
+        let message = format!("At most {} operations per transaction", MAX_TXN_OPS);
+        return Ok((StatusCode::BAD_REQUEST, message).into_response());
+    }
+    let ops = request
+        .ops
+        .into_iter()
+        .enumerate()
+        .map(|(index, op)| {
+            op.decode()
+                .map_err(|error| format!("Invalid value in operation {}: {}", index, error))
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let ops = match ops {
+        Ok(ops) => ops,
+        Err(message) => return Ok((StatusCode::BAD_REQUEST, message).into_response()),
+    };
+
+    let kv = state.kv.clone();
+    let applied = tokio::task::spawn_blocking(move || kv.apply(&namespace.id, &ops)).await?;
+    let results = match applied.map_err(anyhow::Error::downcast::<PreconditionFailed>) {
+        Ok(results) => results,
+        Err(Ok(failed)) => {
+            let conflict = TxnConflict {
+                error: failed.to_string(),
+                index: failed.index,
+                key: String::from_utf8_lossy(&failed.key).into_owned(),
+                actual_version: failed.actual_version,
+            };
+            return Ok((StatusCode::CONFLICT, Json(conflict)).into_response());
+        }
+        Err(Err(error)) => return Err(error.into()),
+    };
+    Ok(Json(TxnResponse {
+        results: results.into_iter().map(Into::into).collect(),
+    })
+    .into_response())
 }
 
 async fn health_check() -> StatusCode {
@@ -43,12 +572,6 @@ async fn namespace_middleware(params: RawPathParams, request: Request, next: Nex
             request.uri().path()
         };
         tracing::info!("Matched namespace path: {} = {}", path, namespace);
-        if namespace == "invalid" {
-            return Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body("Namespace not found".into())
-                .unwrap();
-        }
     } else {
         tracing::info!("Not matching {:?}", namespace_param);
         for (key, value) in &params {
@@ -59,11 +582,51 @@ async fn namespace_middleware(params: RawPathParams, request: Request, next: Nex
     next.run(request).await
 }
 
-pub fn create_app() -> Router {
+/// The denylist of `lists` is checked before the store, denied namespaces can't be used even
+/// if registered. The allowlist is ignored: keys can only live in registered namespaces, which
+/// deleting clears.
+pub fn create_app(registry: Arc<NamespaceRegistry>, lists: StaticListValidator) -> Router {
+    // Cache hits don't depend on the store being up.
+    let cache = CachedValidator::new(
+        CircuitBreaker::new(
+            RocksDbValidator::new(registry.clone()),
+            CircuitBreakerConfig::default(),
+        ),
+        CacheConfig::default(),
+    );
+
+    // Routes of existing namespaces only, creation goes around the validation.
+    let validated = Router::new()
+        .route(
+            "/namespaces/:namespace",
+            get(get_namespace).delete(delete_namespace),
+        )
+        .route("/namespaces/:namespace/keys", get(list_keys))
+        .route("/namespaces/:namespace/txn", post(apply_txn))
+        .route(
+            "/namespaces/:namespace/keys/:key",
+            get(get_key).put(put_key).delete(delete_key),
+        )
+        .route_layer(
+            ValidateNamespaceLayer::new(FirstDecisive(lists.deny_only(), cache.clone()))
+                .retry_after(Duration::from_secs(1))
+                .when_circuit_open(FailureMode::FailClosed),
+        );
+
     Router::new()
-        .route("/namespaces/:namespace", get(get_namespace))
-        .route("/namespaces/:namespace/keys/:key", get(get_namespace_key))
+        .route("/namespaces", get(list_namespaces))
+        .route("/namespaces/:namespace", put(create_namespace))
+        .merge(validated)
         .route("/health", get(health_check))
+        .with_state(AppState {
+            kv: Arc::new(KvStore::new(registry.db().clone())),
+            pins: Arc::new(SnapshotPins::new(
+                registry.db().clone(),
+                PinConfig::default(),
+            )),
+            registry,
+            cache,
+        })
         .layer(
             TraceLayer::new_for_http().make_span_with(|req: &Request<_>| {
                 let path = if let Some(path) = req.extensions().get::<MatchedPath>() {
@@ -88,7 +651,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = create_app();
+    let db_path =
+        std::env::var("NAMESPACE_DB_PATH").unwrap_or_else(|_| ".rocksdb_storage_namespaces".into());
+    let db = namespace::open_db(&db_path)?;
+    // Denylist, reloaded while running.
+    let (lists, reloader) = match std::env::var_os("NAMESPACE_LISTS_PATH") {
+        Some(path) => {
+            let lists = StaticListValidator::from_file(path.as_ref())?;
+            let reloader = lists.watch(path, Duration::from_secs(5));
+            (lists, Some(reloader))
+        }
+        None => (StaticListValidator::default(), None),
+    };
+    let app = create_app(Arc::new(NamespaceRegistry::new(Arc::new(db))), lists);
 
     // Run it
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
@@ -96,6 +671,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(addr).await?;
     axum::serve(listener, app).await?;
 
+    if let Some(reloader) = reloader {
+        reloader.stop().await?;
+    }
     Ok(())
 }
 
@@ -106,12 +684,47 @@ mod tests {
         body::Body,
         http::{Request, StatusCode},
     };
+    use axum_layers::static_list::StaticLists;
     use http_body_util::BodyExt;
+    use tempfile::TempDir;
     use tower::util::ServiceExt;
 
+    /// App over a fresh store holding the namespaces `123` and `denied`, the latter denylisted,
+    /// and with `allowed` allowlisted but not registered.
+    fn test_app() -> (TempDir, Router) {
+        let (dir, _, app) = test_app_and_registry();
+        (dir, app)
+    }
+
+    fn test_app_and_registry() -> (TempDir, Arc<NamespaceRegistry>, Router) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = namespace::open_db(dir.path()).unwrap();
+        let registry = Arc::new(NamespaceRegistry::new(Arc::new(db)));
+        registry
+            .create("123", "Namespace 123", BTreeMap::new())
+            .unwrap();
+        registry
+            .create("denied", "Denied", BTreeMap::new())
+            .unwrap();
+        let lists = StaticListValidator::new(StaticLists {
+            allow: ["allowed".to_owned()].into(),
+            deny: ["denied".to_owned()].into(),
+        });
+        (dir, registry.clone(), create_app(registry, lists))
+    }
+
+    fn json_request(method: &str, uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn test_get_namespace() {
-        let app = create_app();
+        let (_dir, app) = test_app();
 
         let response = app
             .oneshot(
@@ -126,15 +739,447 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let body = response.into_body().collect().await.unwrap().to_bytes();
-        let namespace: Namespace = serde_json::from_slice(&body).unwrap();
+        let namespace: NamespaceMetadata = serde_json::from_slice(&body).unwrap();
 
         assert_eq!(namespace.id, "123");
         assert_eq!(namespace.name, "Namespace 123");
+        assert!(namespace.created_at > 0);
+    }
+
+    fn request(method: &str, uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_put_get_and_delete_keys() {
+        let (_dir, app) = test_app();
+        let uri = "/namespaces/123/keys/abc";
+
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .header("content-type", "text/plain")
+                    .body(Body::from("hello"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["etag"], "\"1\"");
+
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.headers()["etag"], "\"1\"");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello");
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", uri, vec![0u8, 0xff]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["etag"], "\"2\"");
+
+        let response = app.clone().oneshot(request("HEAD", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/octet-stream"
+        );
+        assert_eq!(response.headers()["content-length"], "2");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], [0u8, 0xff]);
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", uri, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", uri, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Created again, with a version never used for a previous content.
+        let response = app.oneshot(request("PUT", uri, "hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["etag"], "\"3\"");
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Keys of a listing and its cursor.
+    async fn list(app: &Router, uri: &str) -> (Vec<String>, Option<String>) {
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let listing = json_body(response).await;
+        let keys = listing["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["key"].as_str().unwrap().to_owned())
+            .collect();
+        (keys, listing["cursor"].as_str().map(str::to_owned))
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_in_pages_at_a_snapshot() {
+        let (_dir, app) = test_app();
+        for key in ["a", "b/1", "b/2", "b/3"] {
+            let uri = format!("/namespaces/123/keys/{}", key.replace('/', "%2F"));
+            let response = app
+                .clone()
+                .oneshot(request("PUT", &uri, key))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let (keys, cursor) = list(&app, "/namespaces/123/keys?prefix=b/&limit=2").await;
+        assert_eq!(keys, ["b/1", "b/2"]);
+        let cursor = cursor.unwrap();
+
+        // Not seen by the next page, which is read at the snapshot of the first one.
+        app.clone()
+            .oneshot(request("PUT", "/namespaces/123/keys/b%2F25", "new"))
+            .await
+            .unwrap();
+        app.clone()
+            .oneshot(request("DELETE", "/namespaces/123/keys/b%2F3", ""))
+            .await
+            .unwrap();
+
+        let next = format!("/namespaces/123/keys?cursor={}&limit=2", cursor);
+        let (keys, last) = list(&app, &next).await;
+        assert_eq!(keys, ["b/3"]);
+        assert_eq!(last, None);
+
+        // The snapshot is released after the last page.
+        let response = app
+            .clone()
+            .oneshot(request("GET", &next, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        let (keys, _) = list(&app, "/namespaces/123/keys?start_after=b/2").await;
+        assert_eq!(keys, ["b/25"]);
+    }
+
+    #[tokio::test]
+    async fn test_forged_cursor_cannot_use_another_listing() {
+        let (_dir, app) = test_app();
+        for key in ["a", "b", "c"] {
+            let uri = format!("/namespaces/123/keys/{}", key);
+            app.clone()
+                .oneshot(request("PUT", &uri, key))
+                .await
+                .unwrap();
+        }
+
+        let (_, cursor) = list(&app, "/namespaces/123/keys?limit=1").await;
+        let cursor = cursor.unwrap();
+        let (_, other) = list(&app, "/namespaces/123/keys?limit=1").await;
+        let other = Cursor::decode(&other.unwrap()).unwrap();
+
+        // The pin of the first listing, under the secret of the other one.
+        let forged = Cursor {
+            snapshot: Cursor::decode(&cursor).unwrap().snapshot,
+            ..other
+        }
+        .encode();
+        for limit in [1, 10] {
+            let uri = format!("/namespaces/123/keys?cursor={}&limit={}", forged, limit);
+            let response = app.clone().oneshot(request("GET", &uri, "")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::GONE);
+        }
+
+        let next = format!("/namespaces/123/keys?cursor={}&limit=10", cursor);
+        let (keys, last) = list(&app, &next).await;
+        assert_eq!(keys, ["b", "c"]);
+        assert_eq!(last, None);
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_with_values_and_sizes() {
+        let (_dir, app) = test_app();
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/namespaces/123/keys/greeting")
+                    .header("content-type", "text/plain")
+                    .body(Body::from("hello"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/namespaces/123/keys", ""))
+            .await
+            .unwrap();
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({"keys": [{"key": "greeting"}], "cursor": null})
+        );
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/namespaces/123/keys?values=true&sizes=true",
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({
+                "keys": [{
+                    "key": "greeting",
+                    "size": 5,
+                    "value": "aGVsbG8",
+                    "content_type": "text/plain",
+                }],
+                "cursor": null,
+            })
+        );
+
+        let response = app
+            .oneshot(request("GET", "/namespaces/123/keys?cursor=nope", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_applies_every_operation() {
+        let (_dir, app) = test_app();
+        app.clone()
+            .oneshot(request("PUT", "/namespaces/123/keys/a", "hello"))
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "POST",
+                "/namespaces/123/txn",
+                serde_json::json!({"ops": [
+                    {"op": "check_version", "key": "a", "version": 1},
+                    {"op": "check_value", "key": "b", "value": null},
+                    {"op": "put", "key": "a", "value": "d29ybGQ", "content_type": "text/plain"},
+                    {"op": "get", "key": "a"},
+                    {"op": "get", "key": "b"},
+                    {"op": "delete", "key": "b"},
+                ]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({"results": [
+                {"op": "check"},
+                {"op": "check"},
+                {"op": "put", "version": 2, "created": false},
+                {"op": "get", "found": true, "value": "d29ybGQ", "content_type": "text/plain", "version": 2},
+                {"op": "get", "found": false},
+                {"op": "delete", "deleted": false},
+            ]})
+        );
+
+        let response = app
+            .oneshot(request("GET", "/namespaces/123/keys/a", ""))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"world");
+    }
+
+    #[tokio::test]
+    async fn test_transaction_fails_on_precondition() {
+        let (_dir, app) = test_app();
+        app.clone()
+            .oneshot(request("PUT", "/namespaces/123/keys/a", "hello"))
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "POST",
+                "/namespaces/123/txn",
+                serde_json::json!({"ops": [
+                    {"op": "put", "key": "b", "value": "bmV3"},
+                    {"op": "check_value", "key": "a", "value": "aGVsbG8"},
+                    {"op": "check_version", "key": "a", "version": 2},
+                ]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({
+                "error": "precondition of operation 2 failed on key a",
+                "index": 2,
+                "key": "a",
+                "actual_version": 1,
+            })
+        );
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/namespaces/123/keys/b", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "rolled back");
+
+        let response = app
+            .oneshot(json_request(
+                "POST",
+                "/namespaces/123/txn",
+                serde_json::json!({"ops": [{"op": "put", "key": "b", "value": "not base64!"}]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_check_version_fails_after_delete_and_recreate() {
+        let (_dir, app) = test_app();
+        let uri = "/namespaces/123/keys/a";
+        // Version 1 read by the client.
+        app.clone()
+            .oneshot(request("PUT", uri, "hello"))
+            .await
+            .unwrap();
+        app.clone()
+            .oneshot(request("DELETE", uri, ""))
+            .await
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(request("PUT", uri, "other"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "POST",
+                "/namespaces/123/txn",
+                serde_json::json!({"ops": [
+                    {"op": "check_version", "key": "a", "version": 1},
+                    {"op": "put", "key": "a", "value": "bmV3"},
+                ]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(json_body(response).await["actual_version"], 2);
+
+        let response = app.oneshot(request("GET", uri, "")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"other");
+    }
+
+    #[tokio::test]
+    async fn test_contended_writes_are_retried_later() {
+        let (_dir, registry, app) = test_app_and_registry();
+        let db = registry.db();
+        let holder = db.transaction();
+        holder
+            .get_for_update_cf(
+                &rocksdb_transactiondb::DBColumnFamilies::User.cf_db(db),
+                kv::encode_key("123", b"abc"),
+                true,
+            )
+            .unwrap();
+
+        for request in [
+            request("PUT", "/namespaces/123/keys/abc", "v"),
+            request("DELETE", "/namespaces/123/keys/abc", ""),
+            json_request(
+                "POST",
+                "/namespaces/123/txn",
+                serde_json::json!({"ops": [{"op": "delete", "key": "abc"}]}),
+            ),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        }
+
+        drop(holder);
+        let response = app
+            .oneshot(request("PUT", "/namespaces/123/keys/abc", "v"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_deleting_namespace_deletes_its_keys() {
+        let (_dir, app) = test_app();
+        let uri = "/namespaces/123/keys/abc";
+
+        let response = app.clone().oneshot(request("PUT", uri, "v")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", "/namespaces/123", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.clone().oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "no namespace");
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/namespaces/123",
+                serde_json::json!({"name": "Namespace 123 again"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app.oneshot(request("GET", uri, "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "no key");
     }
 
     #[tokio::test]
     async fn test_get_invalid_namespace() {
-        let app = create_app();
+        let (_dir, app) = test_app();
 
         let response = app
             .oneshot(
@@ -149,9 +1194,139 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    #[tokio::test]
+    async fn test_allowlisted_namespace_must_be_registered() {
+        let (_dir, app) = test_app();
+
+        for (method, uri) in [
+            ("PUT", "/namespaces/allowed/keys/abc"),
+            ("GET", "/namespaces/allowed/keys/abc"),
+            ("DELETE", "/namespaces/allowed"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request(method, uri, "v"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{method} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_denied_namespace() {
+        let (_dir, app) = test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/namespaces/denied/keys/abc")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_create_list_and_delete_namespaces() {
+        let (_dir, app) = test_app();
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/namespaces/orders",
+                serde_json::json!({"name": "Orders", "labels": {"team": "storage"}}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let orders: NamespaceMetadata = serde_json::from_slice(&body).unwrap();
+        assert_eq!(orders.labels["team"], "storage");
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/namespaces/orders",
+                serde_json::json!({"name": "Other"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/namespaces")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let namespaces: Vec<NamespaceMetadata> = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<_> = namespaces.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, vec!["123", "denied", "orders"]);
+
+        let delete = || {
+            Request::builder()
+                .method("DELETE")
+                .uri("/namespaces/orders")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(delete()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.clone().oneshot(delete()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The cached miss is invalidated by the creation.
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/namespaces/orders",
+                serde_json::json!({"name": "Orders again"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/namespaces/orders")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_create_invalid_namespace_id() {
+        let (_dir, app) = test_app();
+
+        let response = app
+            .oneshot(json_request(
+                "PUT",
+                "/namespaces/not%20valid",
+                serde_json::json!({"name": "Invalid"}),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn test_health_check() {
-        let app = create_app();
+        let (_dir, app) = test_app();
 
         let response = app
             .oneshot(
@@ -168,7 +1343,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_not_found() {
-        let app = create_app();
+        let (_dir, app) = test_app();
 
         let response = app
             .oneshot(
@@ -0,0 +1,80 @@
+use rocksdb_transactiondb::namespace::{Namespace, NamespaceRegistry};
+use std::sync::Arc;
+
+use crate::layers::{NamespaceMetadata, NamespaceValidator, ValidationOutcome};
+
+impl From<Namespace> for NamespaceMetadata {
+    fn from(namespace: Namespace) -> Self {
+        Self {
+            id: namespace.id,
+            name: namespace.name,
+            created_at: namespace.created_at,
+            labels: namespace.labels,
+        }
+    }
+}
+
+/// Validates namespaces against the records of a [`NamespaceRegistry`].
+#[derive(Clone)]
+pub struct RocksDbValidator {
+    registry: Arc<NamespaceRegistry>,
+}
+
+impl RocksDbValidator {
+    pub fn new(registry: Arc<NamespaceRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+#[async_trait::async_trait]
+impl NamespaceValidator for RocksDbValidator {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        let registry = self.registry.clone();
+        let id = namespace.to_owned();
+        // RocksDB reads block, keep them off the async workers.
+        match tokio::task::spawn_blocking(move || registry.get(&id)).await {
+            Ok(Ok(Some(namespace))) => ValidationOutcome::Exists(namespace.into()),
+            Ok(Ok(None)) => ValidationOutcome::NotFound,
+            Ok(Err(error)) => {
+                let error: Box<dyn std::error::Error + Send + Sync> = error.into();
+                ValidationOutcome::Unavailable(error.into())
+            }
+            Err(error) => ValidationOutcome::Unavailable(Arc::new(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn registry() -> (tempfile::TempDir, Arc<NamespaceRegistry>) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = rocksdb_transactiondb::namespace::open_db(dir.path()).unwrap();
+        (dir, Arc::new(NamespaceRegistry::new(Arc::new(db))))
+    }
+
+    #[tokio::test]
+    async fn test_validates_registered_namespaces() {
+        let (_dir, registry) = registry();
+        let labels = BTreeMap::from([("team".to_owned(), "storage".to_owned())]);
+        let orders = registry.create("orders", "Orders", labels).unwrap();
+        let validator = RocksDbValidator::new(registry.clone());
+
+        match validator.validate("orders").await {
+            ValidationOutcome::Exists(metadata) => assert_eq!(metadata, orders.into()),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+        assert!(matches!(
+            validator.validate("carts").await,
+            ValidationOutcome::NotFound
+        ));
+
+        registry.delete("orders").unwrap();
+        assert!(matches!(
+            validator.validate("orders").await,
+            ValidationOutcome::NotFound
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::layers::{NamespaceMetadata, NamespaceValidator, ValidationOutcome};
+
+/// Namespaces always let through or always refused, as stored in a JSON file:
+///
+/// ```json
+/// { "allow": ["health"], "deny": ["invalid", "internal"] }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct StaticLists {
+    #[serde(default)]
+    pub allow: BTreeSet<String>,
+    /// Wins over `allow`.
+    #[serde(default)]
+    pub deny: BTreeSet<String>,
+}
+
+impl StaticLists {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&json, path)
+    }
+
+    fn parse(json: &[u8], path: &Path) -> Result<Self> {
+        serde_json::from_slice(json).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+/// Refuses denied namespaces, lets allowed ones through with
+/// [`NamespaceMetadata::unverified`] and is [`Undecided`](ValidationOutcome::Undecided) about
+/// the others, meant to be chained with [`FirstDecisive`](crate::combinators::FirstDecisive).
+///
+/// Clones share the lists, so a [`Reloader`] updates every one of them.
+#[derive(Clone, Default)]
+pub struct StaticListValidator {
+    lists: Arc<RwLock<StaticLists>>,
+}
+
+impl StaticListValidator {
+    pub fn new(lists: StaticLists) -> Self {
+        Self {
+            lists: Arc::new(RwLock::new(lists)),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(Self::new(StaticLists::from_file(path)?))
+    }
+
+    pub fn lists(&self) -> StaticLists {
+        self.lists.read().unwrap().clone()
+    }
+
+    /// Replaces the lists, returns whether they changed.
+    pub fn replace(&self, lists: StaticLists) -> bool {
+        let mut current = self.lists.write().unwrap();
+        if *current == lists {
+            return false;
+        }
+        *current = lists;
+        true
+    }
+
+    /// Validator applying only the denylist, for namespaces that must also exist in a store:
+    /// allowed namespaces are [`Undecided`](ValidationOutcome::Undecided) like the others.
+    /// It shares the lists, reloads included.
+    pub fn deny_only(&self) -> DenyOnly {
+        DenyOnly(self.clone())
+    }
+
+    /// Replaces the lists with those of `path`, keeps the current ones when it can't be read.
+    pub fn reload_from(&self, path: &Path) -> Result<bool> {
+        Ok(self.replace(StaticLists::from_file(path)?))
+    }
+
+    /// Spawns a task reloading the lists from `path` at every `interval` on the current tokio
+    /// runtime. Errors are logged and the lists kept, so that a half written file doesn't
+    /// lift the denylist.
+    pub fn watch(&self, path: impl Into<PathBuf>, interval: Duration) -> Reloader {
+        let (stop, stopped) = oneshot::channel();
+        let handle = tokio::spawn(reload_until_stopped(
+            self.clone(),
+            path.into(),
+            interval,
+            stopped,
+        ));
+        Reloader { stop, handle }
+    }
+}
+
+#[async_trait::async_trait]
+impl NamespaceValidator for StaticListValidator {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        let lists = self.lists.read().unwrap();
+        if lists.deny.contains(namespace) {
+            ValidationOutcome::Forbidden
+        } else if lists.allow.contains(namespace) {
+            ValidationOutcome::Exists(NamespaceMetadata::unverified(namespace))
+        } else {
+            ValidationOutcome::Undecided
+        }
+    }
+}
+
+/// See [`StaticListValidator::deny_only`].
+#[derive(Clone)]
+pub struct DenyOnly(StaticListValidator);
+
+#[async_trait::async_trait]
+impl NamespaceValidator for DenyOnly {
+    async fn validate(&self, namespace: &str) -> ValidationOutcome {
+        if self.0.lists.read().unwrap().deny.contains(namespace) {
+            ValidationOutcome::Forbidden
+        } else {
+            ValidationOutcome::Undecided
+        }
+    }
+}
+
+/// Background task keeping a [`StaticListValidator`] in sync with its file.
+pub struct Reloader {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<usize>,
+}
+
+impl Reloader {
+    /// Stops the task, returns how many times it changed the lists.
+    pub async fn stop(self) -> Result<usize> {
+        let _ = self.stop.send(());
+        Ok(self.handle.await?)
+    }
+}
+
+async fn reload_until_stopped(
+    validator: StaticListValidator,
+    path: PathBuf,
+    interval: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) -> usize {
+    let mut ticks = tokio::time::interval(interval);
+    let mut reloads = 0;
+    loop {
+        tokio::select! {
+            _ = &mut stopped => return reloads,
+            _ = ticks.tick() => {
+                let lists = match tokio::fs::read(&path).await {
+                    Ok(json) => StaticLists::parse(&json, &path),
+                    Err(error) => Err(anyhow::Error::new(error)
+                        .context(format!("reading {}", path.display()))),
+                };
+                match lists {
+                    Ok(lists) => {
+                        if validator.replace(lists) {
+                            tracing::info!("Reloaded namespace lists from {}", path.display());
+                            reloads += 1;
+                        }
+                    }
+                    Err(error) => tracing::warn!("Keeping namespace lists: {:#}", error),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combinators::FirstDecisive;
+
+    fn write(path: &Path, json: &str) {
+        std::fs::write(path, json).unwrap();
+    }
+
+    /// Waits for `validator` to answer `Forbidden` for `namespace`, or panics.
+    async fn until_forbidden(validator: &StaticListValidator, namespace: &str) {
+        for _ in 0..200 {
+            if let ValidationOutcome::Forbidden = validator.validate(namespace).await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never denied", namespace);
+    }
+
+    #[tokio::test]
+    async fn test_deny_wins_and_others_are_undecided() {
+        let validator = StaticListValidator::new(StaticLists {
+            allow: BTreeSet::from(["health".to_owned(), "internal".to_owned()]),
+            deny: BTreeSet::from(["internal".to_owned()]),
+        });
+
+        assert!(matches!(
+            validator.validate("health").await,
+            ValidationOutcome::Exists(metadata) if metadata.id == "health"
+        ));
+        assert!(matches!(
+            validator.validate("internal").await,
+            ValidationOutcome::Forbidden
+        ));
+        assert!(matches!(
+            validator.validate("orders").await,
+            ValidationOutcome::Undecided
+        ));
+
+        let deny_only = validator.deny_only();
+        assert!(matches!(
+            deny_only.validate("health").await,
+            ValidationOutcome::Undecided
+        ));
+        assert!(matches!(
+            deny_only.validate("internal").await,
+            ValidationOutcome::Forbidden
+        ));
+
+        let chained = FirstDecisive(validator, StaticListValidator::default());
+        assert!(matches!(
+            chained.validate("orders").await,
+            ValidationOutcome::Undecided
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reloads_when_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lists.json");
+        write(&path, r#"{"deny": ["invalid"]}"#);
+        let validator = StaticListValidator::from_file(&path).unwrap();
+        assert!(!validator.reload_from(&path).unwrap(), "unchanged");
+
+        let reloader = validator.watch(&path, Duration::from_millis(10));
+        until_forbidden(&validator, "invalid").await;
+        write(&path, r#"{"deny": ["invalid", "internal"]}"#);
+        until_forbidden(&validator, "internal").await;
+
+        write(&path, r#"{"deny": ["#);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            validator.lists().deny,
+            BTreeSet::from(["invalid".to_owned(), "internal".to_owned()]),
+            "kept when the file is broken or gone"
+        );
+
+        assert_eq!(reloader.stop().await.unwrap(), 1);
+        assert!(validator.reload_from(&path).is_err());
+    }
+}
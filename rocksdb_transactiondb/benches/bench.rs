@@ -14,17 +14,33 @@ mod tests {
 
     use anyhow::{anyhow, Context, Ok, Result};
     use rocksdb::{
-        BoundColumnFamily, ColumnFamilyDescriptor, OptimisticTransactionDB, Options, TransactionDB,
-        TransactionDBOptions, WriteBatchWithTransaction,
+        BoundColumnFamily, ColumnFamilyDescriptor, IteratorMode, OptimisticTransactionDB, Options,
+        TransactionDB, TransactionDBOptions, WriteBatchWithTransaction,
+    };
+    use rocksdb_transactiondb::{
+        blob::{BlobConfig, WriteStats},
+        bulk_load::BulkLoader,
+        prefix_delete::{delete_prefix_in_txn, delete_prefix_range},
+        tenant::{open_tenant_db, TenantRegistry},
     };
     use strum::IntoEnumIterator;
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-    // const env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-    //     .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
-    // tracing_subscriber::registry()
-    //     .with(tracing_subscriber::fmt::layer().with_filter(env_filter))
-    //     .init();
+    /// Logs to stderr, at the level of `RUST_LOG` or `info`. Every benchmark may call it.
+    fn init_tracing() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+            tracing_subscriber::registry()
+                .with(
+                    tracing_subscriber::fmt::layer()
+                        .with_writer(std::io::stderr)
+                        .with_filter(env_filter),
+                )
+                .init();
+        });
+    }
 
     pub trait OptionExtensions<T> {
         fn expect_lazy<F: FnOnce() -> String>(self, msg_getter: F) -> T;
@@ -166,4 +182,395 @@ mod tests {
 
         b.bytes = 1005 * 10000;
     }
+
+    fn optimistic_db(path: &str) -> Arc<OptimisticTransactionDB> {
+        if fs::exists(path).unwrap() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        fs::create_dir_all(path).unwrap();
+
+        let sm_column_families = DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default()));
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+
+        Arc::new(
+            OptimisticTransactionDB::open_cf_descriptors(&db_opts, path, sm_column_families)
+                .unwrap(),
+        )
+    }
+
+    #[bench]
+    fn bench_single_batch_put_optimistic(b: &mut Bencher) {
+        let db = optimistic_db(".rocksdb_storage_batch_put_optimistic");
+
+        let data: Vec<u8> = vec![0; 1000];
+
+        b.iter(|| {
+            let mut batch_write = WriteBatchWithTransaction::<true>::default();
+            let cf = DBColumnFamilies::User.cf(&db);
+            for i in black_box(0..10000) {
+                batch_write.put_cf(&cf, format!("key_{}", i).as_bytes(), &data);
+            }
+            db.write(batch_write).unwrap();
+        });
+
+        b.bytes = 1005 * 10000;
+    }
+
+    #[bench]
+    fn bench_bulk_load_sst(b: &mut Bencher) {
+        let db = optimistic_db(".rocksdb_storage_bulk_load_sst");
+        let loader = BulkLoader::new(&db, ".rocksdb_storage_bulk_load_sst_staging");
+
+        let data: Vec<u8> = vec![0; 1000];
+
+        b.iter(|| {
+            let cf = DBColumnFamilies::User.cf(&db);
+            let entries =
+                black_box(0..10000).map(|i| (format!("key_{}", i).into_bytes(), data.clone()));
+            loader.load(&cf, entries).unwrap();
+        });
+
+        b.bytes = 1005 * 10000;
+    }
+
+    const TENANTS: usize = 100;
+    const KEYS_PER_TENANT: usize = 100;
+
+    fn tenant_db_opts(path: &str) -> Options {
+        if fs::exists(path).unwrap() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        fs::create_dir_all(path).unwrap();
+
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        db_opts
+    }
+
+    fn fill_tenant_cf(registry: &TenantRegistry, tenant: &str, data: &[u8]) {
+        let cf = registry.cf(tenant).unwrap();
+        for i in 0..KEYS_PER_TENANT {
+            registry
+                .db()
+                .put_cf(&cf, format!("key_{}", i).as_bytes(), data)
+                .unwrap();
+        }
+    }
+
+    fn fill_tenant_prefix(db: &TransactionDB, tenant: &str, data: &[u8]) {
+        let cf = DBColumnFamilies::User.cf_db(db);
+        for i in 0..KEYS_PER_TENANT {
+            db.put_cf(&cf, format!("{}/key_{}", tenant, i).as_bytes(), data)
+                .unwrap();
+        }
+    }
+
+    fn drop_tenant_prefix(db: &TransactionDB, tenant: &str) {
+        let txn = db.transaction();
+        delete_prefix_in_txn(
+            &txn,
+            &DBColumnFamilies::User.cf_db(db),
+            format!("{}/", tenant).as_bytes(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[bench]
+    fn bench_open_cf_per_tenant(b: &mut Bencher) {
+        let path = ".rocksdb_storage_open_cf_per_tenant";
+        let db_opts = tenant_db_opts(path);
+        let data: Vec<u8> = vec![0; 100];
+
+        {
+            let registry = TenantRegistry::new(Arc::new(open_tenant_db(&db_opts, path).unwrap()));
+            for i in 0..TENANTS {
+                let tenant = format!("tenant_{}", i);
+                registry.create(&tenant).unwrap();
+                fill_tenant_cf(&registry, &tenant, &data);
+            }
+        }
+
+        b.iter(|| black_box(open_tenant_db(&db_opts, path).unwrap()));
+    }
+
+    #[bench]
+    fn bench_open_prefix_per_tenant(b: &mut Bencher) {
+        let path = ".rocksdb_storage_open_prefix_per_tenant";
+        let db_opts = tenant_db_opts(path);
+        let data: Vec<u8> = vec![0; 100];
+
+        {
+            let db = open_tenant_db(&db_opts, path).unwrap();
+            for i in 0..TENANTS {
+                fill_tenant_prefix(&db, &format!("tenant_{}", i), &data);
+            }
+        }
+
+        b.iter(|| black_box(open_tenant_db(&db_opts, path).unwrap()));
+    }
+
+    #[bench]
+    fn bench_create_fill_drop_cf_per_tenant(b: &mut Bencher) {
+        let path = ".rocksdb_storage_drop_cf_per_tenant";
+        let db_opts = tenant_db_opts(path);
+        let registry = TenantRegistry::new(Arc::new(open_tenant_db(&db_opts, path).unwrap()));
+        let data: Vec<u8> = vec![0; 100];
+
+        b.iter(|| {
+            registry.create("tenant").unwrap();
+            fill_tenant_cf(&registry, "tenant", &data);
+            registry.remove("tenant").unwrap();
+        });
+    }
+
+    #[bench]
+    fn bench_create_fill_drop_prefix_per_tenant(b: &mut Bencher) {
+        let path = ".rocksdb_storage_drop_prefix_per_tenant";
+        let db_opts = tenant_db_opts(path);
+        let db = open_tenant_db(&db_opts, path).unwrap();
+        let data: Vec<u8> = vec![0; 100];
+
+        b.iter(|| {
+            fill_tenant_prefix(&db, "tenant", &data);
+            drop_tenant_prefix(&db, "tenant");
+        });
+    }
+
+    const PURGED_KEYS: usize = 10000;
+
+    fn fill_prefix(db: &OptimisticTransactionDB, prefix: &str, data: &[u8]) {
+        let mut batch_write = WriteBatchWithTransaction::<true>::default();
+        let cf = DBColumnFamilies::User.cf(db);
+        for i in 0..PURGED_KEYS {
+            batch_write.put_cf(&cf, format!("{}/key_{:05}", prefix, i).as_bytes(), data);
+        }
+        db.write(batch_write).unwrap();
+    }
+
+    fn purge_prefix_txn(db: &OptimisticTransactionDB, prefix: &str) {
+        let txn = db.transaction();
+        delete_prefix_in_txn(
+            &txn,
+            &DBColumnFamilies::User.cf(db),
+            format!("{}/", prefix).as_bytes(),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+    }
+
+    fn purge_prefix_range(db: &OptimisticTransactionDB, prefix: &str) {
+        delete_prefix_range(
+            db,
+            &DBColumnFamilies::User.cf(db),
+            format!("{}/", prefix).as_bytes(),
+        )
+        .unwrap();
+    }
+
+    #[bench]
+    fn bench_purge_prefix_range(b: &mut Bencher) {
+        let db = optimistic_db(".rocksdb_storage_purge_prefix_range");
+        let data: Vec<u8> = vec![0; 100];
+
+        b.iter(|| {
+            fill_prefix(&db, "tenant", &data);
+            purge_prefix_range(&db, "tenant");
+        });
+    }
+
+    #[bench]
+    fn bench_purge_prefix_txn(b: &mut Bencher) {
+        let db = optimistic_db(".rocksdb_storage_purge_prefix_txn");
+        let data: Vec<u8> = vec![0; 100];
+
+        b.iter(|| {
+            fill_prefix(&db, "tenant", &data);
+            purge_prefix_txn(&db, "tenant");
+        });
+    }
+
+    /// Scans a column family where the tenant between two live tenants was purged with
+    /// `purge`, the scan has to skip its tombstones.
+    fn bench_scan_after_purge(
+        b: &mut Bencher,
+        path: &str,
+        purge: fn(&OptimisticTransactionDB, &str),
+    ) {
+        let db = optimistic_db(path);
+        let data: Vec<u8> = vec![0; 100];
+        fill_prefix(&db, "tenant_a", &data);
+        fill_prefix(&db, "tenant_b", &data);
+        fill_prefix(&db, "tenant_c", &data);
+        purge(&db, "tenant_b");
+
+        b.iter(|| {
+            let count = db
+                .iterator_cf(&DBColumnFamilies::User.cf(&db), IteratorMode::Start)
+                .count();
+            assert_eq!(black_box(count), 2 * PURGED_KEYS);
+        });
+    }
+
+    #[bench]
+    fn bench_scan_after_purge_range(b: &mut Bencher) {
+        bench_scan_after_purge(
+            b,
+            ".rocksdb_storage_scan_after_purge_range",
+            purge_prefix_range,
+        );
+    }
+
+    #[bench]
+    fn bench_scan_after_purge_txn(b: &mut Bencher) {
+        bench_scan_after_purge(b, ".rocksdb_storage_scan_after_purge_txn", purge_prefix_txn);
+    }
+
+    /// Bytes written by every iteration of the blob benchmarks.
+    const BLOB_BENCH_DATA: usize = 4 * 1024 * 1024;
+
+    /// Database whose `User` column family stores values of 1 KiB or more in blob files when
+    /// `blob` is set, with statistics enabled in the returned options.
+    fn blob_db(path: &str, blob: bool) -> (Options, OptimisticTransactionDB) {
+        if fs::exists(path).unwrap() {
+            fs::remove_dir_all(path).unwrap();
+        }
+        fs::create_dir_all(path).unwrap();
+
+        let mut cf_opts = Options::default();
+        // Small memtables, for flushes and compactions to happen within the benchmark.
+        cf_opts.set_write_buffer_size(4 * 1024 * 1024);
+        if blob {
+            BlobConfig {
+                min_blob_size: 1024,
+                ..BlobConfig::default()
+            }
+            .apply(&mut cf_opts);
+        }
+        let sm_column_families = DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), cf_opts.clone()));
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        db_opts.enable_statistics();
+
+        let db = OptimisticTransactionDB::open_cf_descriptors(&db_opts, path, sm_column_families)
+            .unwrap();
+        (db_opts, db)
+    }
+
+    /// Overwrites `BLOB_BENCH_DATA` bytes of `size` byte values, one transaction per value,
+    /// and prints the write amplification once done.
+    fn bench_txn_put_values(b: &mut Bencher, path: &str, size: usize, blob: bool) {
+        init_tracing();
+        let (db_opts, db) = blob_db(path, blob);
+        let value: Vec<u8> = vec![0; size];
+        let keys = BLOB_BENCH_DATA / size;
+        let mut iterations = 0;
+
+        b.iter(|| {
+            let cf = DBColumnFamilies::User.cf(&db);
+            for k in black_box(0..keys) {
+                let txn = db.transaction();
+                txn.put_cf(&cf, format!("key_{:05}", k).as_bytes(), &value)
+                    .unwrap();
+                txn.commit().unwrap();
+            }
+            iterations += 1;
+        });
+
+        let stats = WriteStats::from_options(&db_opts);
+        tracing::info!(
+            "{path}: write amplification {:.2} ({stats:?})",
+            stats.amplification((iterations * keys * size) as u64)
+        );
+        b.bytes = (keys * size) as u64;
+    }
+
+    /// Reads `BLOB_BENCH_DATA` bytes of `size` byte values in a transaction, once flushed.
+    fn bench_txn_get_values(b: &mut Bencher, path: &str, size: usize, blob: bool) {
+        let (_db_opts, db) = blob_db(path, blob);
+        let value: Vec<u8> = vec![0; size];
+        let keys = BLOB_BENCH_DATA / size;
+        let cf = DBColumnFamilies::User.cf(&db);
+        for k in 0..keys {
+            db.put_cf(&cf, format!("key_{:05}", k).as_bytes(), &value)
+                .unwrap();
+        }
+        db.flush_cf(&cf).unwrap();
+
+        b.iter(|| {
+            let txn = db.transaction();
+            for k in black_box(0..keys) {
+                let value = txn.get_cf(&cf, format!("key_{:05}", k).as_bytes()).unwrap();
+                assert_eq!(black_box(value).map(|v| v.len()), Some(size));
+            }
+        });
+
+        b.bytes = (keys * size) as u64;
+    }
+
+    #[bench]
+    fn bench_txn_put_1kb_inline(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_1kb_inline", 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_put_1kb_blob(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_1kb_blob", 1024, true);
+    }
+
+    #[bench]
+    fn bench_txn_put_64kb_inline(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_64kb_inline", 64 * 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_put_64kb_blob(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_64kb_blob", 64 * 1024, true);
+    }
+
+    #[bench]
+    fn bench_txn_put_1mb_inline(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_1mb_inline", 1024 * 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_put_1mb_blob(b: &mut Bencher) {
+        bench_txn_put_values(b, ".rocksdb_storage_txn_put_1mb_blob", 1024 * 1024, true);
+    }
+
+    #[bench]
+    fn bench_txn_get_1kb_inline(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_1kb_inline", 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_get_1kb_blob(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_1kb_blob", 1024, true);
+    }
+
+    #[bench]
+    fn bench_txn_get_64kb_inline(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_64kb_inline", 64 * 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_get_64kb_blob(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_64kb_blob", 64 * 1024, true);
+    }
+
+    #[bench]
+    fn bench_txn_get_1mb_inline(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_1mb_inline", 1024 * 1024, false);
+    }
+
+    #[bench]
+    fn bench_txn_get_1mb_blob(b: &mut Bencher) {
+        bench_txn_get_values(b, ".rocksdb_storage_txn_get_1mb_blob", 1024 * 1024, true);
+    }
 }
@@ -0,0 +1,198 @@
+#![warn(clippy::pedantic)]
+#![allow(clippy::similar_names)]
+#![allow(clippy::single_match_else)]
+#![allow(clippy::too_many_lines)]
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use rocksdb_transactiondb::inspect::{
+    cf, column_families, dump, load, open, open_read_only, scan, Bounds, Format,
+};
+
+/// Inspects and edits a RocksDB store without writing Rust code.
+#[derive(Parser)]
+struct Cli {
+    /// Database directory, like `.rocksdb_storage`.
+    path: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists column families with their sizes and properties.
+    Cfs,
+    /// Prints the value of a key.
+    Get {
+        cf: String,
+        key: String,
+        /// Encoding of the key argument: hex, utf8 or json.
+        #[arg(long, default_value = "utf8")]
+        key_format: Format,
+        /// Output format of the value: hex, utf8 or json.
+        #[arg(long, default_value = "utf8")]
+        format: Format,
+    },
+    /// Writes a key, outside of any transaction.
+    Put {
+        cf: String,
+        key: String,
+        value: String,
+        #[arg(long, default_value = "utf8")]
+        key_format: Format,
+        #[arg(long, default_value = "utf8")]
+        value_format: Format,
+    },
+    /// Deletes a key, outside of any transaction.
+    Delete {
+        cf: String,
+        key: String,
+        #[arg(long, default_value = "utf8")]
+        key_format: Format,
+    },
+    /// Prints records as NDJSON, all of them or those under a prefix or in a range.
+    Scan {
+        cf: String,
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        prefix: Option<String>,
+        /// First key of the range, included.
+        #[arg(long)]
+        start: Option<String>,
+        /// Last key of the range, excluded.
+        #[arg(long)]
+        end: Option<String>,
+        /// Encoding of the prefix and range arguments, and output format of the keys.
+        #[arg(long, default_value = "utf8")]
+        key_format: Format,
+        /// Output format of the values.
+        #[arg(long, default_value = "utf8")]
+        format: Format,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Dumps a column family to NDJSON, on stdout by default.
+    Dump {
+        cf: String,
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Loads NDJSON written by `dump` into a column family, from stdin by default.
+    Load {
+        cf: String,
+        #[arg(long)]
+        input: Option<PathBuf>,
+        #[arg(long, default_value_t = 10_000)]
+        batch_size: usize,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut stdout = io::stdout().lock();
+
+    match cli.command {
+        Command::Cfs => {
+            for info in column_families(&cli.path)? {
+                writeln!(stdout, "{info}")?;
+            }
+        }
+        Command::Get {
+            cf: cf_name,
+            key,
+            key_format,
+            format,
+        } => {
+            let db = open_read_only(&cli.path)?;
+            let value = db.get_cf(&cf(&db, &cf_name)?, key_format.parse(&key)?)?;
+            match value {
+                Some(value) => writeln!(stdout, "{}", format.print(&value))?,
+                None => anyhow::bail!("key {key} not found in {cf_name}"),
+            }
+        }
+        Command::Put {
+            cf: cf_name,
+            key,
+            value,
+            key_format,
+            value_format,
+        } => {
+            let db = open(&cli.path)?;
+            db.put_cf(
+                &cf(&db, &cf_name)?,
+                key_format.parse(&key)?,
+                value_format.parse(&value)?,
+            )?;
+        }
+        Command::Delete {
+            cf: cf_name,
+            key,
+            key_format,
+        } => {
+            let db = open(&cli.path)?;
+            db.delete_cf(&cf(&db, &cf_name)?, key_format.parse(&key)?)?;
+        }
+        Command::Scan {
+            cf: cf_name,
+            prefix,
+            start,
+            end,
+            key_format,
+            format,
+            limit,
+        } => {
+            let bounds = match (prefix, start, end) {
+                (Some(prefix), _, _) => Bounds::Prefix(key_format.parse(&prefix)?),
+                (None, None, None) => Bounds::All,
+                (None, start, end) => Bounds::Range {
+                    start: start.map_or(Ok(Vec::new()), |start| key_format.parse(&start))?,
+                    end: end.map(|end| key_format.parse(&end)).transpose()?,
+                },
+            };
+            let db = open_read_only(&cli.path)?;
+            let handle = cf(&db, &cf_name)?;
+            for item in scan(&db, &handle, &bounds).take(limit.unwrap_or(usize::MAX)) {
+                let (key, value) = item?;
+                let record = serde_json::json!({
+                    "key": key_format.print(&key),
+                    "value": format.print(&value),
+                });
+                writeln!(stdout, "{record}")?;
+            }
+        }
+        Command::Dump {
+            cf: cf_name,
+            output,
+        } => {
+            let db = open_read_only(&cli.path)?;
+            let count = match output {
+                Some(output) => dump(&db, &cf_name, BufWriter::new(File::create(output)?))?,
+                None => dump(&db, &cf_name, &mut stdout)?,
+            };
+            eprintln!("dumped {count} records");
+        }
+        Command::Load {
+            cf: cf_name,
+            input,
+            batch_size,
+        } => {
+            let db = open(&cli.path)?;
+            let count = match input {
+                Some(input) => load(
+                    &db,
+                    &cf_name,
+                    BufReader::new(File::open(input)?),
+                    batch_size,
+                )?,
+                None => load(&db, &cf_name, io::stdin().lock(), batch_size)?,
+            };
+            eprintln!("loaded {count} records");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+#![warn(clippy::pedantic)]
+#![allow(clippy::similar_names)]
+#![allow(clippy::single_match_else)]
+#![allow(clippy::too_many_lines)]
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use rocksdb_transactiondb::report::{generate, splice_readme};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Markdown,
+    Json,
+}
+
+/// Runs every locking, snapshot and isolation scenario against every engine and prints what
+/// was observed.
+#[derive(Parser)]
+struct Cli {
+    #[arg(long, value_enum, default_value_t = Output::Markdown)]
+    format: Output,
+    /// Scratch directory for the databases, removed first.
+    #[arg(long, default_value = ".rocksdb_storage_report")]
+    db_dir: PathBuf,
+    /// Writes the Markdown table into this README instead of printing it.
+    #[arg(long, conflicts_with = "format")]
+    readme: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if fs::exists(&cli.db_dir)? {
+        fs::remove_dir_all(&cli.db_dir)?;
+    }
+    let report = generate(&cli.db_dir)?;
+    fs::remove_dir_all(&cli.db_dir)?;
+
+    match (cli.readme, cli.format) {
+        (Some(readme), _) => {
+            let content = fs::read_to_string(&readme)?;
+            fs::write(&readme, splice_readme(&content, &report.to_markdown())?)?;
+        }
+        (None, Output::Markdown) => print!("{}", report.to_markdown()),
+        (None, Output::Json) => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Key-value separation for column families holding large values.
+//!
+//! With blob files enabled, values of at least [`BlobConfig::min_blob_size`] bytes are written
+//! to blob files at flush and the SST files only keep a reference to them. Compactions then
+//! rewrite the small references instead of the values, which cuts write amplification for
+//! large values at the cost of an extra read per lookup. Overwritten and deleted values stay
+//! in their blob file until blob garbage collection relocates the live values of the oldest
+//! files during compaction and deletes them.
+//!
+//! Blob settings are column family options, transactions are not affected: values still go
+//! through the WAL and the memtable, and are only separated when flushed.
+use rocksdb::{statistics::Ticker, ColumnFamilyDescriptor, Options};
+
+use crate::DBColumnFamilies;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobConfig {
+    /// Values of at least this many bytes go to blob files.
+    pub min_blob_size: u64,
+    /// Size at which a blob file is closed and a new one started.
+    pub blob_file_size: u64,
+    /// Relocate live values of old blob files during compaction.
+    pub enable_gc: bool,
+    /// Fraction of the blob files, oldest first, whose live values compaction relocates.
+    pub gc_age_cutoff: f64,
+    /// Ratio of garbage in the oldest blob files from which the SST files referencing them
+    /// are compacted on purpose, `1.0` never forces it.
+    pub gc_force_threshold: f64,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            min_blob_size: 4 * 1024,
+            blob_file_size: 256 * 1024 * 1024,
+            enable_gc: true,
+            gc_age_cutoff: 0.25,
+            gc_force_threshold: 1.0,
+        }
+    }
+}
+
+impl BlobConfig {
+    pub fn apply(&self, cf_opts: &mut Options) {
+        cf_opts.set_enable_blob_files(true);
+        cf_opts.set_min_blob_size(self.min_blob_size);
+        cf_opts.set_blob_file_size(self.blob_file_size);
+        cf_opts.set_enable_blob_gc(self.enable_gc);
+        cf_opts.set_blob_gc_age_cutoff(self.gc_age_cutoff);
+        cf_opts.set_blob_gc_force_threshold(self.gc_force_threshold);
+    }
+}
+
+/// Descriptor of `cf` with `cf_opts`, and blob files enabled when `blob` is given.
+pub fn descriptor(
+    cf: &DBColumnFamilies,
+    mut cf_opts: Options,
+    blob: Option<&BlobConfig>,
+) -> ColumnFamilyDescriptor {
+    if let Some(blob) = blob {
+        blob.apply(&mut cf_opts);
+    }
+    ColumnFamilyDescriptor::new(cf.as_ref(), cf_opts)
+}
+
+/// Bytes written to disk, from the statistics of a database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub wal: u64,
+    /// Written by flushes, blob files included.
+    pub flush: u64,
+    /// Written by compactions, blob files included.
+    pub compaction: u64,
+    /// Written to blob files, by flushes and compactions.
+    pub blob_files: u64,
+}
+
+impl WriteStats {
+    /// Counters of `db_opts`, which must have had statistics enabled before opening the
+    /// database.
+    pub fn from_options(db_opts: &Options) -> Self {
+        Self {
+            wal: db_opts.get_ticker_count(Ticker::WalFileBytes),
+            flush: db_opts.get_ticker_count(Ticker::FlushWriteBytes),
+            compaction: db_opts.get_ticker_count(Ticker::CompactWriteBytes),
+            blob_files: db_opts.get_ticker_count(Ticker::BlobDbBlobFileBytesWritten),
+        }
+    }
+
+    /// Bytes written by flushes and compactions per byte of user data, the WAL left out.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn amplification(&self, user_bytes: u64) -> f64 {
+        (self.flush + self.compaction) as f64 / user_bytes.max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::OptimisticTransactionDB;
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    fn open(
+        dir: &tempfile::TempDir,
+        blob: Option<&BlobConfig>,
+    ) -> (Options, OptimisticTransactionDB) {
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        db_opts.enable_statistics();
+        let descriptors = DBColumnFamilies::iter().map(|cf| match cf {
+            DBColumnFamilies::User => descriptor(&cf, Options::default(), blob),
+            _ => descriptor(&cf, Options::default(), None),
+        });
+        let db = OptimisticTransactionDB::open_cf_descriptors(&db_opts, dir.path(), descriptors)
+            .unwrap();
+        (db_opts, db)
+    }
+
+    /// Writes small and large values in a transaction, flushes, returns the number of blob
+    /// files of `User`.
+    fn write_and_flush(db: &OptimisticTransactionDB) -> u64 {
+        let cf = DBColumnFamilies::User.cf(db);
+        let txn = db.transaction();
+        for i in 0..10 {
+            txn.put_cf(&cf, format!("small{i}"), vec![1; 100]).unwrap();
+            txn.put_cf(&cf, format!("large{i}"), vec![2; 8 * 1024])
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        db.flush_cf(&cf).unwrap();
+
+        let txn = db.transaction();
+        assert_eq!(txn.get_cf(&cf, "small0").unwrap(), Some(vec![1; 100]));
+        assert_eq!(txn.get_cf(&cf, "large9").unwrap(), Some(vec![2; 8 * 1024]));
+        db.property_int_value_cf(&cf, "rocksdb.num-blob-files")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn large_values_go_to_blob_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db_opts, db) = open(&dir, Some(&BlobConfig::default()));
+
+        assert!(write_and_flush(&db) > 0);
+        let blob_bytes = db
+            .property_int_value_cf(
+                &DBColumnFamilies::User.cf(&db),
+                "rocksdb.total-blob-file-size",
+            )
+            .unwrap()
+            .unwrap();
+        // The large values, and not the small ones that stayed in the SST file.
+        assert!(blob_bytes >= 10 * 8 * 1024);
+        assert!(blob_bytes < 10 * 8 * 1024 + 10 * 100 + 4096);
+        let stats = WriteStats::from_options(&db_opts);
+        assert!(stats.blob_files > 0);
+        assert!(stats.flush >= stats.blob_files);
+    }
+
+    #[test]
+    fn values_stay_inline_without_blob_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db_opts, db) = open(&dir, None);
+
+        assert_eq!(write_and_flush(&db), 0);
+        let stats = WriteStats::from_options(&db_opts);
+        assert_eq!(stats.blob_files, 0);
+        assert!(stats.flush > 10 * 8 * 1024);
+        assert!(stats.amplification(10 * (100 + 8 * 1024)) > 0.0);
+    }
+}
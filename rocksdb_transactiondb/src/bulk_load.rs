@@ -0,0 +1,301 @@
+//! Bulk loading by writing SST files and ingesting them into a column family.
+//!
+//! Writes go through neither the memtable nor the WAL: [`BulkLoader`] sorts the input, writes
+//! it to SST files in a staging directory and hands the files over to RocksDB, which links
+//! them into the LSM tree.
+//!
+//! Only `OptimisticTransactionDB` is supported: the binding has no `ingest_external_file_cf`
+//! on `TransactionDB`. Ingestion would not take its row locks either, so it would not wait for
+//! a transaction holding a key from `get_for_update`.
+//!
+//! Ingestion is not a transaction and does not go through conflict detection:
+//! - it does not wait for open transactions, they hold no lock and their uncommitted writes
+//!   are invisible to it;
+//! - reads at a snapshot taken before the ingestion do not see the ingested keys, reads
+//!   without a snapshot do;
+//! - whether a transaction that wrote an ingested key, or read it with `get_for_update` before
+//!   the ingestion, can still commit depends on the memtable, the only place commit checks for
+//!   conflicts. Ingested files skip it, so with no entry for the key there the commit goes
+//!   through and shadows the ingested value. An ingestion overlapping the memtable flushes it
+//!   first, and the commit may then fail with `TryAgain` or `Busy`. Do not rely on either.
+//!
+//! Callers needing isolation from transactions should have both sides take a
+//! [`crate::range_lock::KeyRange`] lock covering the loaded keys, exclusive for the loader.
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use rocksdb::{
+    AsColumnFamilyRef, IngestExternalFileOptions, OptimisticTransactionDB, Options, SstFileWriter,
+};
+
+/// Distinguishes the staging files of concurrent loads.
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+pub struct BulkLoader<'a> {
+    db: &'a OptimisticTransactionDB,
+    staging_dir: PathBuf,
+    entries_per_file: usize,
+}
+
+impl<'a> BulkLoader<'a> {
+    /// Loads into `db`, writing SST files in `staging_dir` before ingesting them.
+    ///
+    /// `staging_dir` should be on the same filesystem as the database, so that ingestion can
+    /// hard link the files instead of copying them.
+    pub fn new(db: &'a OptimisticTransactionDB, staging_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            db,
+            staging_dir: staging_dir.into(),
+            entries_per_file: 1_000_000,
+        }
+    }
+
+    /// Maximum number of entries written to a single SST file.
+    #[must_use]
+    pub fn entries_per_file(mut self, entries_per_file: usize) -> Self {
+        assert!(entries_per_file > 0, "entries_per_file must be positive");
+        self.entries_per_file = entries_per_file;
+        self
+    }
+
+    /// Sorts `entries` and ingests them into `cf` in a single ingestion, returns the number of
+    /// keys loaded. When a key appears more than once, its last value wins.
+    pub fn load(
+        &self,
+        cf: &impl AsColumnFamilyRef,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<usize> {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        // Stable, so that equal keys keep their input order and the last one can be kept.
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.reverse();
+        entries.dedup_by(|a, b| a.0 == b.0);
+        entries.reverse();
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        fs::create_dir_all(&self.staging_dir)?;
+        let mut files = Vec::new();
+        let result = self.write_and_ingest(cf, &entries, &mut files);
+        for file in &files {
+            // Ingestion hard links or copies the files, the staging copies are not needed.
+            let _ = fs::remove_file(file);
+        }
+        result.map(|()| entries.len())
+    }
+
+    fn write_and_ingest(
+        &self,
+        cf: &impl AsColumnFamilyRef,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let opts = Options::default();
+        for chunk in entries.chunks(self.entries_per_file) {
+            let file = self.staging_dir.join(format!(
+                "bulk_load_{}_{}.sst",
+                std::process::id(),
+                NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+            files.push(file.clone());
+
+            let mut writer = SstFileWriter::create(&opts);
+            writer
+                .open(&file)
+                .with_context(|| format!("failed to open {}", file.display()))?;
+            for (key, value) in chunk {
+                writer.put(key, value)?;
+            }
+            writer.finish()?;
+        }
+
+        let mut ingest_opts = IngestExternalFileOptions::default();
+        ingest_opts.set_move_files(true);
+        self.db
+            .ingest_external_file_cf_opts(cf, &ingest_opts, files.clone())
+            .context("failed to ingest SST files")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rocksdb::{ErrorKind, OptimisticTransactionOptions, ReadOptions, WriteOptions};
+
+    use super::*;
+    use crate::{
+        range_lock::{KeyRange, LockMode, RangeLockError, RangeLockManager},
+        test_utils::temp_optimistic_db,
+        DBColumnFamilies,
+    };
+
+    fn entries(range: std::ops::Range<u32>, value: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        range
+            .map(|i| (format!("key{i:04}").into_bytes(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn loads_unsorted_input_across_files() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging")).entries_per_file(3);
+
+        let mut input = entries(0..10, "v");
+        input.reverse();
+        input.push((b"key0005".to_vec(), b"last".to_vec()));
+        assert_eq!(loader.load(&cf, input).unwrap(), 10);
+
+        assert_eq!(db.get_cf(&cf, b"key0000").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(db.get_cf(&cf, b"key0005").unwrap(), Some(b"last".to_vec()));
+        assert_eq!(db.get_cf(&cf, b"key0009").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(loader.load(&cf, Vec::new()).unwrap(), 0);
+        assert_eq!(
+            fs::read_dir(dir.path().join("staging")).unwrap().count(),
+            0,
+            "staging files are removed"
+        );
+    }
+
+    #[test]
+    fn ingestion_does_not_see_uncommitted_writes() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging"));
+
+        let txn = db.transaction();
+        txn.put_cf(&cf, b"key0001", b"txn").unwrap();
+
+        loader.load(&cf, entries(0..3, "ingested")).unwrap();
+        assert_eq!(
+            db.get_cf(&cf, b"key0001").unwrap(),
+            Some(b"ingested".to_vec())
+        );
+        // The transaction still reads its own write.
+        assert_eq!(txn.get_cf(&cf, b"key0001").unwrap(), Some(b"txn".to_vec()));
+
+        // No conflict, the transaction overwrites the ingested value.
+        txn.commit().unwrap();
+        assert_eq!(db.get_cf(&cf, b"key0001").unwrap(), Some(b"txn".to_vec()));
+        assert_eq!(
+            db.get_cf(&cf, b"key0002").unwrap(),
+            Some(b"ingested".to_vec())
+        );
+    }
+
+    #[test]
+    fn ingestion_over_the_memtable_may_reject_commit() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging"));
+        db.put_cf(&cf, b"key0001", b"memtable").unwrap();
+
+        let txn = db.transaction();
+        assert_eq!(
+            txn.get_for_update_cf(&cf, b"key0001", true).unwrap(),
+            Some(b"memtable".to_vec())
+        );
+        txn.put_cf(&cf, b"key0001", b"txn").unwrap();
+        // Overlaps the memtable, which is flushed first.
+        loader.load(&cf, entries(0..3, "ingested")).unwrap();
+
+        match txn.commit() {
+            Ok(()) => assert_eq!(db.get_cf(&cf, b"key0001").unwrap(), Some(b"txn".to_vec())),
+            Err(err) => {
+                assert!(
+                    matches!(err.kind(), ErrorKind::TryAgain | ErrorKind::Busy),
+                    "{err}"
+                );
+                assert_eq!(
+                    db.get_cf(&cf, b"key0001").unwrap(),
+                    Some(b"ingested".to_vec())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ingestion_does_not_invalidate_get_for_update() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging"));
+
+        let txn = db.transaction();
+        assert_eq!(txn.get_for_update_cf(&cf, b"key0001", true).unwrap(), None);
+
+        loader.load(&cf, entries(0..3, "ingested")).unwrap();
+        txn.put_cf(&cf, b"key0001", b"absent").unwrap();
+
+        // Nothing for the key in the memtable, so this lost update commits.
+        txn.commit().unwrap();
+        assert_eq!(
+            db.get_cf(&cf, b"key0001").unwrap(),
+            Some(b"absent".to_vec())
+        );
+    }
+
+    #[test]
+    fn snapshot_reads_do_not_see_ingested_keys() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging"));
+        db.put_cf(&cf, b"key0000", b"before").unwrap();
+
+        let mut txn_opts = OptimisticTransactionOptions::default();
+        txn_opts.set_snapshot(true);
+        let txn = db.transaction_opt(&WriteOptions::default(), &txn_opts);
+        let snapshot = txn.snapshot();
+        loader.load(&cf, entries(0..2, "ingested")).unwrap();
+
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(&snapshot);
+        assert_eq!(
+            txn.get_cf_opt(&cf, b"key0000", &read_opts).unwrap(),
+            Some(b"before".to_vec())
+        );
+        assert_eq!(txn.get_cf_opt(&cf, b"key0001", &read_opts).unwrap(), None);
+
+        assert_eq!(
+            txn.get_cf(&cf, b"key0001").unwrap(),
+            Some(b"ingested".to_vec())
+        );
+    }
+
+    #[test]
+    fn range_lock_keeps_loader_away_from_transaction() {
+        let (dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        let loader = BulkLoader::new(&db, dir.path().join("staging"));
+        let manager = RangeLockManager::new(Duration::from_millis(10));
+        let range = KeyRange::prefix(DBColumnFamilies::User, "key");
+
+        let txn_lock = manager
+            .lock(manager.owner(), range.clone(), LockMode::Shared)
+            .unwrap();
+        let txn = db.transaction();
+        assert_eq!(txn.get_for_update_cf(&cf, b"key0001", true).unwrap(), None);
+        txn.put_cf(&cf, b"key0001", b"absent").unwrap();
+
+        let loader_lock = manager.lock(manager.owner(), range.clone(), LockMode::Exclusive);
+        assert!(matches!(loader_lock, Err(RangeLockError::Timeout { .. })));
+
+        txn.commit().unwrap();
+        drop(txn_lock);
+        let _loader_lock = manager
+            .lock(manager.owner(), range, LockMode::Exclusive)
+            .unwrap();
+        assert_eq!(loader.load(&cf, entries(0..3, "ingested")).unwrap(), 3);
+        // Loaded after the transaction, the ingested value wins.
+        assert_eq!(
+            db.get_cf(&cf, b"key0001").unwrap(),
+            Some(b"ingested".to_vec())
+        );
+    }
+}
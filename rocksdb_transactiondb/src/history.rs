@@ -0,0 +1,1030 @@
+//! Recording of concurrent transaction histories and an offline serializability checker.
+//!
+//! [`History`] collects what every transaction read (with the value it observed), what it
+//! wrote and whether it committed. [`check`] then builds the dependency graph of the committed
+//! transactions (write-write, write-read and read-write edges) and reports its cycles as
+//! [`Anomaly`]s: a cycle means no serial order of the transactions explains what they observed.
+//!
+//! Every value written through a [`RecordedTxn`] is tagged with the id of its writer, so a read
+//! can be traced back to the transaction that produced it. Values written outside a recorder,
+//! like the seeded state of a workload, are attributed to the initial state.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use rocksdb::{
+    AsColumnFamilyRef, BoundColumnFamily, Error, OptimisticTransactionDB,
+    OptimisticTransactionOptions, ReadOptions, Transaction, TransactionDB, TransactionOptions,
+    WriteOptions,
+};
+
+use crate::DBColumnFamilies;
+
+pub type TxnId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Read {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    /// Transaction that wrote the observed value, `None` for the initial state.
+    pub writer: Option<TxnId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Write {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnOutcome {
+    Committed,
+    Aborted(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxnRecord {
+    pub id: TxnId,
+    pub reads: Vec<Read>,
+    pub writes: Vec<Write>,
+    pub outcome: TxnOutcome,
+}
+
+impl TxnRecord {
+    fn committed(&self) -> bool {
+        self.outcome == TxnOutcome::Committed
+    }
+}
+
+fn tag(id: TxnId, value: &[u8]) -> Vec<u8> {
+    let mut tagged = format!("t{id}:").into_bytes();
+    tagged.extend_from_slice(value);
+    tagged
+}
+
+fn untag(stored: &[u8]) -> (Option<TxnId>, Vec<u8>) {
+    if let Some(rest) = stored.strip_prefix(b"t") {
+        if let Some(sep) = rest.iter().position(|b| *b == b':') {
+            if let Some(id) = std::str::from_utf8(&rest[..sep])
+                .ok()
+                .and_then(|id| id.parse().ok())
+            {
+                return (Some(id), rest[sep + 1..].to_vec());
+            }
+        }
+    }
+    (None, stored.to_vec())
+}
+
+/// Shared log of every transaction started through [`History::begin`].
+///
+/// Records are appended when a transaction finishes. Commits are issued while holding the log
+/// lock, so the order of committed records is the order in which their writes became visible.
+#[derive(Debug, Default)]
+pub struct History {
+    next_id: AtomicU64,
+    records: Mutex<Vec<TxnRecord>>,
+}
+
+impl History {
+    pub fn begin<'h, 'db, DB>(&'h self, txn: Transaction<'db, DB>) -> RecordedTxn<'h, 'db, DB> {
+        RecordedTxn {
+            history: self,
+            txn: Some(txn),
+            record: TxnRecord {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+                reads: Vec::new(),
+                writes: Vec::new(),
+                outcome: TxnOutcome::Aborted("dropped".to_string()),
+            },
+            logged: false,
+        }
+    }
+
+    pub fn records(&self) -> Vec<TxnRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+/// Transaction wrapper that logs its operations into a [`History`].
+///
+/// Dropping it without calling `commit` or `rollback` records the transaction as aborted.
+pub struct RecordedTxn<'h, 'db, DB> {
+    history: &'h History,
+    txn: Option<Transaction<'db, DB>>,
+    record: TxnRecord,
+    logged: bool,
+}
+
+impl<'db, DB> RecordedTxn<'_, 'db, DB> {
+    pub fn id(&self) -> TxnId {
+        self.record.id
+    }
+
+    fn txn(&self) -> &Transaction<'db, DB> {
+        self.txn.as_ref().expect("transaction already finished")
+    }
+
+    fn read_opts(&self) -> ReadOptions {
+        // Reads go through the transaction snapshot when one was requested, and see the latest
+        // committed state otherwise.
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(&self.txn().snapshot());
+        read_opts
+    }
+
+    fn observe(&mut self, key: &[u8], stored: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let (writer, value) = match stored {
+            Some(stored) => {
+                let (writer, value) = untag(&stored);
+                (writer, Some(value))
+            }
+            None => (None, None),
+        };
+        self.record.reads.push(Read {
+            key: key.to_vec(),
+            value: value.clone(),
+            writer,
+        });
+        value
+    }
+
+    pub fn get_cf(
+        &mut self,
+        cf: &impl AsColumnFamilyRef,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let stored = self.txn().get_cf_opt(cf, key, &self.read_opts())?;
+        Ok(self.observe(key, stored))
+    }
+
+    pub fn get_for_update_cf(
+        &mut self,
+        cf: &impl AsColumnFamilyRef,
+        key: &[u8],
+        exclusive: bool,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let stored = self
+            .txn()
+            .get_for_update_cf_opt(cf, key, exclusive, &self.read_opts())?;
+        Ok(self.observe(key, stored))
+    }
+
+    pub fn put_cf(
+        &mut self,
+        cf: &impl AsColumnFamilyRef,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.txn().put_cf(cf, key, tag(self.record.id, value))?;
+        self.record.writes.push(Write {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<(), Error> {
+        let txn = self.txn.take().expect("transaction already finished");
+        let mut records = self.history.records.lock().unwrap();
+        let res = txn.commit();
+        self.record.outcome = match &res {
+            Result::Ok(()) => TxnOutcome::Committed,
+            Err(err) => TxnOutcome::Aborted(err.to_string()),
+        };
+        records.push(self.record.clone());
+        self.logged = true;
+        res
+    }
+
+    pub fn rollback(mut self) -> Result<(), Error> {
+        let txn = self.txn.take().expect("transaction already finished");
+        self.record.outcome = TxnOutcome::Aborted("rollback".to_string());
+        txn.rollback()
+    }
+}
+
+impl<DB> Drop for RecordedTxn<'_, '_, DB> {
+    fn drop(&mut self) {
+        if !self.logged {
+            self.history
+                .records
+                .lock()
+                .unwrap()
+                .push(self.record.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dependency {
+    /// `to` overwrote the version written by `from`.
+    WriteWrite,
+    /// `to` read the version written by `from`.
+    WriteRead,
+    /// `from` read a version that `to` overwrote.
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: TxnId,
+    pub to: TxnId,
+    pub kind: Dependency,
+    pub key: Vec<u8>,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Dependency::WriteWrite => "ww",
+            Dependency::WriteRead => "wr",
+            Dependency::ReadWrite => "rw",
+        };
+        write!(
+            f,
+            "t{} -{kind}({})-> t{}",
+            self.from,
+            String::from_utf8_lossy(&self.key),
+            self.to
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// A committed transaction observed a value written by a transaction that did not commit.
+    AbortedRead {
+        reader: TxnId,
+        writer: TxnId,
+        key: Vec<u8>,
+    },
+    /// Two transactions read the same version of a key and both overwrote it.
+    LostUpdate { cycle: Vec<Edge> },
+    /// Two transactions each overwrote a key the other one read.
+    WriteSkew { cycle: Vec<Edge> },
+    /// Any other dependency cycle.
+    Cycle { cycle: Vec<Edge> },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, cycle) = match self {
+            Anomaly::AbortedRead {
+                reader,
+                writer,
+                key,
+            } => {
+                return write!(
+                    f,
+                    "aborted read: t{reader} read {} from aborted t{writer}",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            Anomaly::LostUpdate { cycle } => ("lost update", cycle),
+            Anomaly::WriteSkew { cycle } => ("write skew", cycle),
+            Anomaly::Cycle { cycle } => ("cycle", cycle),
+        };
+        write!(f, "{name}:")?;
+        for edge in cycle {
+            write!(f, " {edge};")?;
+        }
+        std::result::Result::Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub committed: usize,
+    pub aborted: usize,
+    pub edges: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Report {
+    pub fn is_serializable(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+
+    pub fn lost_updates(&self) -> usize {
+        self.anomalies
+            .iter()
+            .filter(|a| matches!(a, Anomaly::LostUpdate { .. }))
+            .count()
+    }
+
+    pub fn write_skews(&self) -> usize {
+        self.anomalies
+            .iter()
+            .filter(|a| matches!(a, Anomaly::WriteSkew { .. }))
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} committed, {} aborted, {} dependencies, {} anomalies ({} lost updates, {} write skews)",
+            self.committed,
+            self.aborted,
+            self.edges,
+            self.anomalies.len(),
+            self.lost_updates(),
+            self.write_skews()
+        )
+    }
+}
+
+/// Builds the dependency graph of the committed transactions in `records` and reports its
+/// cycles.
+///
+/// Every pair of transactions with dependencies in both directions is reported. Larger cycles
+/// are reported once per strongly connected component that contains no such pair.
+pub fn check(records: &[TxnRecord]) -> Report {
+    let mut report = Report::default();
+    let by_id: HashMap<TxnId, &TxnRecord> = records.iter().map(|r| (r.id, r)).collect();
+
+    // Version order of every key: committed writers in commit order.
+    let mut versions: BTreeMap<&[u8], Vec<TxnId>> = BTreeMap::new();
+    for record in records {
+        if !record.committed() {
+            report.aborted += 1;
+            continue;
+        }
+        report.committed += 1;
+        let keys: BTreeSet<&[u8]> = record.writes.iter().map(|w| w.key.as_slice()).collect();
+        for key in keys {
+            versions.entry(key).or_default().push(record.id);
+        }
+    }
+
+    let mut edges = BTreeSet::new();
+    for (key, writers) in &versions {
+        for pair in writers.windows(2) {
+            edges.insert(Edge {
+                from: pair[0],
+                to: pair[1],
+                kind: Dependency::WriteWrite,
+                key: key.to_vec(),
+            });
+        }
+    }
+    for record in records.iter().filter(|r| r.committed()) {
+        for read in &record.reads {
+            if read.writer == Some(record.id) {
+                continue;
+            }
+            if let Some(writer) = read.writer {
+                match by_id.get(&writer) {
+                    Some(w) if w.committed() => {
+                        edges.insert(Edge {
+                            from: writer,
+                            to: record.id,
+                            kind: Dependency::WriteRead,
+                            key: read.key.clone(),
+                        });
+                    }
+                    _ => {
+                        report.anomalies.push(Anomaly::AbortedRead {
+                            reader: record.id,
+                            writer,
+                            key: read.key.clone(),
+                        });
+                        continue;
+                    }
+                }
+            }
+            let writers = versions
+                .get(read.key.as_slice())
+                .map_or(&[][..], Vec::as_slice);
+            let next = match read.writer {
+                None => writers.first(),
+                Some(writer) => writers
+                    .iter()
+                    .position(|w| *w == writer)
+                    .and_then(|pos| writers.get(pos + 1)),
+            };
+            if let Some(&next) = next {
+                if next != record.id {
+                    edges.insert(Edge {
+                        from: record.id,
+                        to: next,
+                        kind: Dependency::ReadWrite,
+                        key: read.key.clone(),
+                    });
+                }
+            }
+        }
+    }
+    report.edges = edges.len();
+
+    let mut graph: BTreeMap<TxnId, BTreeMap<TxnId, Vec<&Edge>>> = BTreeMap::new();
+    for edge in &edges {
+        graph
+            .entry(edge.from)
+            .or_default()
+            .entry(edge.to)
+            .or_default()
+            .push(edge);
+    }
+
+    let mut explained = BTreeSet::new();
+    for (&a, targets) in &graph {
+        for (&b, forward) in targets {
+            if a >= b {
+                continue;
+            }
+            if let Some(backward) = graph.get(&b).and_then(|t| t.get(&a)) {
+                report.anomalies.push(classify_pair(forward, backward));
+                explained.insert(a);
+                explained.insert(b);
+            }
+        }
+    }
+
+    for component in strongly_connected(&graph) {
+        if component.len() < 2 || component.iter().any(|id| explained.contains(id)) {
+            continue;
+        }
+        if let Some(cycle) = shortest_cycle(&graph, &component) {
+            report.anomalies.push(Anomaly::Cycle { cycle });
+        }
+    }
+
+    report
+}
+
+fn classify_pair(forward: &[&Edge], backward: &[&Edge]) -> Anomaly {
+    for f in forward {
+        for b in backward {
+            let kinds = [f.kind, b.kind];
+            if f.key == b.key
+                && kinds.contains(&Dependency::ReadWrite)
+                && kinds.contains(&Dependency::WriteWrite)
+            {
+                return Anomaly::LostUpdate {
+                    cycle: vec![(*f).clone(), (*b).clone()],
+                };
+            }
+        }
+    }
+    for f in forward {
+        for b in backward {
+            if f.kind == Dependency::ReadWrite && b.kind == Dependency::ReadWrite && f.key != b.key
+            {
+                return Anomaly::WriteSkew {
+                    cycle: vec![(*f).clone(), (*b).clone()],
+                };
+            }
+        }
+    }
+    Anomaly::Cycle {
+        cycle: vec![forward[0].clone(), backward[0].clone()],
+    }
+}
+
+type Graph<'e> = BTreeMap<TxnId, BTreeMap<TxnId, Vec<&'e Edge>>>;
+
+/// Tarjan's algorithm, iterative to stay clear of the stack limit on long histories.
+fn strongly_connected(graph: &Graph) -> Vec<Vec<TxnId>> {
+    let mut index: HashMap<TxnId, usize> = HashMap::new();
+    let mut low: HashMap<TxnId, usize> = HashMap::new();
+    let mut on_stack = BTreeSet::new();
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let no_targets = BTreeMap::new();
+
+    for &root in graph.keys() {
+        if index.contains_key(&root) {
+            continue;
+        }
+        let mut work: Vec<(TxnId, Vec<TxnId>)> = Vec::new();
+        let visit = |node: TxnId,
+                     index: &mut HashMap<TxnId, usize>,
+                     low: &mut HashMap<TxnId, usize>,
+                     stack: &mut Vec<TxnId>,
+                     on_stack: &mut BTreeSet<TxnId>| {
+            let i = index.len();
+            index.insert(node, i);
+            low.insert(node, i);
+            stack.push(node);
+            on_stack.insert(node);
+            (
+                node,
+                graph
+                    .get(&node)
+                    .unwrap_or(&no_targets)
+                    .keys()
+                    .rev()
+                    .copied()
+                    .collect::<Vec<_>>(),
+            )
+        };
+        work.push(visit(root, &mut index, &mut low, &mut stack, &mut on_stack));
+
+        while let Some((node, pending)) = work.last_mut() {
+            let node = *node;
+            if let Some(next) = pending.pop() {
+                if !index.contains_key(&next) {
+                    work.push(visit(next, &mut index, &mut low, &mut stack, &mut on_stack));
+                } else if on_stack.contains(&next) {
+                    let l = low[&node].min(index[&next]);
+                    low.insert(node, l);
+                }
+                continue;
+            }
+            work.pop();
+            if let Some((parent, _)) = work.last() {
+                let l = low[parent].min(low[&node]);
+                low.insert(*parent, l);
+            }
+            if low[&node] == index[&node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+
+    components
+}
+
+/// Breadth-first search for the shortest cycle through the first member of `component`.
+fn shortest_cycle(graph: &Graph, component: &[TxnId]) -> Option<Vec<Edge>> {
+    let members: BTreeSet<TxnId> = component.iter().copied().collect();
+    let start = *members.iter().next()?;
+    let mut parent: HashMap<TxnId, &Edge> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(node) = queue.pop_front() {
+        for (&next, edges) in graph.get(&node)? {
+            if !members.contains(&next) {
+                continue;
+            }
+            if next == start {
+                let mut cycle = vec![edges[0].clone()];
+                let mut cur = node;
+                while cur != start {
+                    let edge = parent[&cur];
+                    cycle.push(edge.clone());
+                    cur = edge.from;
+                }
+                cycle.reverse();
+                return Some(cycle);
+            }
+            if let std::collections::hash_map::Entry::Vacant(e) = parent.entry(next) {
+                e.insert(edges[0]);
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// The two transactional engines, driven the same way by the workloads below.
+pub trait Engine: Send + Sync + Sized + 'static {
+    const NAME: &'static str;
+
+    /// Begins a transaction, optionally taking a snapshot that reads go through and that
+    /// commit-time conflict detection is anchored to.
+    fn begin(&self, config: &WorkloadConfig) -> Transaction<'_, Self>;
+
+    fn user_cf(&self) -> Arc<BoundColumnFamily<'_>>;
+}
+
+impl Engine for TransactionDB {
+    const NAME: &'static str = "TransactionDB";
+
+    fn begin(&self, config: &WorkloadConfig) -> Transaction<'_, Self> {
+        let mut txn_opts = TransactionOptions::default();
+        txn_opts.set_snapshot(config.snapshot);
+        txn_opts.set_lock_timeout(config.lock_timeout_ms);
+        self.transaction_opt(&WriteOptions::default(), &txn_opts)
+    }
+
+    fn user_cf(&self) -> Arc<BoundColumnFamily<'_>> {
+        DBColumnFamilies::User.cf_db(self)
+    }
+}
+
+impl Engine for OptimisticTransactionDB {
+    const NAME: &'static str = "OptimisticTransactionDB";
+
+    fn begin(&self, config: &WorkloadConfig) -> Transaction<'_, Self> {
+        let mut txn_opts = OptimisticTransactionOptions::default();
+        txn_opts.set_snapshot(config.snapshot);
+        self.transaction_opt(&WriteOptions::default(), &txn_opts)
+    }
+
+    fn user_cf(&self) -> Arc<BoundColumnFamily<'_>> {
+        DBColumnFamilies::User.cf(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WorkloadConfig {
+    /// Take a transaction snapshot at begin.
+    pub snapshot: bool,
+    /// Read with `get_for_update_cf` (exclusive) instead of `get_cf`.
+    pub lock_reads: bool,
+    /// Concurrent tokio tasks.
+    pub tasks: usize,
+    /// Transactions run by every task.
+    pub txns_per_task: usize,
+    /// Lock timeout of `TransactionDB` transactions, ignored by `OptimisticTransactionDB`.
+    pub lock_timeout_ms: i64,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            snapshot: false,
+            lock_reads: false,
+            tasks: 4,
+            txns_per_task: 25,
+            lock_timeout_ms: 50,
+        }
+    }
+}
+
+impl<'db, DB: Engine> RecordedTxn<'_, 'db, DB> {
+    fn read(
+        &mut self,
+        db: &'db DB,
+        key: &[u8],
+        config: &WorkloadConfig,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if config.lock_reads {
+            self.get_for_update_cf(&db.user_cf(), key, true)
+        } else {
+            self.get_cf(&db.user_cf(), key)
+        }
+    }
+}
+
+fn parse_counter(value: Option<Vec<u8>>) -> u64 {
+    value
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Every task repeatedly increments the same counter with a read-modify-write transaction,
+/// yielding between the read and the write so that tasks interleave.
+///
+/// Returns the final counter value and the number of committed increments: they differ when
+/// an increment was lost.
+pub async fn counter_workload<DB: Engine>(
+    db: Arc<DB>,
+    history: Arc<History>,
+    config: WorkloadConfig,
+) -> Result<(u64, u64)> {
+    let key = b"counter".as_slice();
+    {
+        let txn = db.begin(&config);
+        txn.put_cf(&db.user_cf(), key, b"0")?;
+        txn.commit()?;
+    }
+
+    let mut handles = Vec::new();
+    for _ in 0..config.tasks {
+        let db = db.clone();
+        let history = history.clone();
+        handles.push(tokio::spawn(async move {
+            let mut committed = 0;
+            for _ in 0..config.txns_per_task {
+                let mut txn = history.begin(db.begin(&config));
+                let Result::Ok(value) = txn.read(&db, key, &config) else {
+                    continue;
+                };
+                tokio::task::yield_now().await;
+                let next = (parse_counter(value) + 1).to_string();
+                if txn.put_cf(&db.user_cf(), key, next.as_bytes()).is_err() {
+                    continue;
+                }
+                if txn.commit().is_ok() {
+                    committed += 1;
+                }
+            }
+            committed
+        }));
+    }
+
+    let mut committed = 0;
+    for handle in handles {
+        committed += handle.await?;
+    }
+    let value = parse_counter(
+        db.begin(&config)
+            .get_cf(&db.user_cf(), key)?
+            .map(|v| untag(&v).1),
+    );
+    Ok((value, committed))
+}
+
+/// Classic on-call write skew: in every round two doctors are on call and each task takes
+/// one of them off call, but only after checking that the other one is still on call.
+///
+/// Returns the number of rounds that ended with nobody on call.
+pub async fn write_skew_workload<DB: Engine>(
+    db: Arc<DB>,
+    history: Arc<History>,
+    config: WorkloadConfig,
+) -> Result<usize> {
+    let rounds = config.tasks * config.txns_per_task / 2;
+    {
+        let txn = db.begin(&config);
+        for round in 0..rounds {
+            txn.put_cf(&db.user_cf(), format!("oncall{round}/a"), b"1")?;
+            txn.put_cf(&db.user_cf(), format!("oncall{round}/b"), b"1")?;
+        }
+        txn.commit()?;
+    }
+
+    for round in 0..rounds {
+        let mut handles = Vec::new();
+        for (me, other) in [("a", "b"), ("b", "a")] {
+            let db = db.clone();
+            let history = history.clone();
+            handles.push(tokio::spawn(async move {
+                let me = format!("oncall{round}/{me}");
+                let other = format!("oncall{round}/{other}");
+                let mut txn = history.begin(db.begin(&config));
+                let (Result::Ok(mine), Result::Ok(theirs)) = (
+                    txn.read(&db, me.as_bytes(), &config),
+                    txn.read(&db, other.as_bytes(), &config),
+                ) else {
+                    return;
+                };
+                tokio::task::yield_now().await;
+                if parse_counter(mine) + parse_counter(theirs) >= 2
+                    && txn.put_cf(&db.user_cf(), me.as_bytes(), b"0").is_ok()
+                {
+                    let _ = txn.commit();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await?;
+        }
+    }
+
+    let mut nobody_on_call = 0;
+    let txn = db.begin(&config);
+    for round in 0..rounds {
+        let on_call: u64 = ["a", "b"]
+            .iter()
+            .map(|who| {
+                txn.get_cf(&db.user_cf(), format!("oncall{round}/{who}"))
+                    .map(|v| parse_counter(v.map(|v| untag(&v).1)))
+            })
+            .sum::<Result<u64, Error>>()?;
+        if on_call == 0 {
+            nobody_on_call += 1;
+        }
+    }
+    Ok(nobody_on_call)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_optimistic_db, temp_transaction_db};
+
+    fn record(id: TxnId, reads: &[(&str, Option<TxnId>)], writes: &[&str]) -> TxnRecord {
+        TxnRecord {
+            id,
+            reads: reads
+                .iter()
+                .map(|(key, writer)| Read {
+                    key: key.as_bytes().to_vec(),
+                    value: None,
+                    writer: *writer,
+                })
+                .collect(),
+            writes: writes
+                .iter()
+                .map(|key| Write {
+                    key: key.as_bytes().to_vec(),
+                    value: b"v".to_vec(),
+                })
+                .collect(),
+            outcome: TxnOutcome::Committed,
+        }
+    }
+
+    #[test]
+    fn serial_history_has_no_anomalies() {
+        let report = check(&[
+            record(1, &[("x", None)], &["x"]),
+            record(2, &[("x", Some(1))], &["x"]),
+            record(3, &[("x", Some(2)), ("y", None)], &["y"]),
+        ]);
+        assert!(report.is_serializable(), "{report}");
+        assert_eq!(report.committed, 3);
+    }
+
+    #[test]
+    fn detects_lost_update() {
+        let report = check(&[
+            record(1, &[("x", None)], &["x"]),
+            record(2, &[("x", None)], &["x"]),
+        ]);
+        assert_eq!(report.lost_updates(), 1, "{report}");
+    }
+
+    #[test]
+    fn detects_write_skew() {
+        let report = check(&[
+            record(1, &[("x", None), ("y", None)], &["x"]),
+            record(2, &[("x", None), ("y", None)], &["y"]),
+        ]);
+        assert_eq!(report.write_skews(), 1, "{report}");
+    }
+
+    #[test]
+    fn detects_longer_cycles() {
+        // t1 -rw(x)-> t2 -rw(y)-> t3 -rw(z)-> t1
+        let report = check(&[
+            record(1, &[("x", None)], &["z"]),
+            record(2, &[("y", None)], &["x"]),
+            record(3, &[("z", None)], &["y"]),
+        ]);
+        assert_eq!(report.anomalies.len(), 1, "{report}");
+        let Anomaly::Cycle { cycle } = &report.anomalies[0] else {
+            panic!("expected a cycle: {report}");
+        };
+        assert_eq!(cycle.len(), 3);
+    }
+
+    #[test]
+    fn detects_aborted_read() {
+        let mut aborted = record(1, &[], &["x"]);
+        aborted.outcome = TxnOutcome::Aborted("rollback".to_string());
+        let report = check(&[aborted, record(2, &[("x", Some(1))], &[])]);
+        assert!(matches!(
+            report.anomalies[..],
+            [Anomaly::AbortedRead {
+                reader: 2,
+                writer: 1,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn recorder_catches_lost_update_in_transaction_db() {
+        let (_dir, db) = temp_transaction_db();
+        let history = History::default();
+        let config = WorkloadConfig::default();
+        let cf = db.user_cf();
+
+        let mut txn1 = history.begin(db.begin(&config));
+        let mut txn2 = history.begin(db.begin(&config));
+        txn1.get_cf(&cf, b"x").unwrap();
+        txn2.get_cf(&cf, b"x").unwrap();
+        txn1.put_cf(&cf, b"x", b"1").unwrap();
+        txn1.commit().unwrap();
+        txn2.put_cf(&cf, b"x", b"1").unwrap();
+        txn2.commit().unwrap();
+
+        let report = check(&history.records());
+        assert_eq!(report.lost_updates(), 1, "{report}");
+    }
+
+    #[test]
+    fn snapshot_prevents_lost_update_in_optimistic_db() {
+        let (_dir, db) = temp_optimistic_db();
+        let history = History::default();
+        let config = WorkloadConfig {
+            snapshot: true,
+            ..WorkloadConfig::default()
+        };
+        let cf = db.user_cf();
+
+        let mut txn1 = history.begin(db.begin(&config));
+        let mut txn2 = history.begin(db.begin(&config));
+        txn1.get_cf(&cf, b"x").unwrap();
+        txn2.get_cf(&cf, b"x").unwrap();
+        txn1.put_cf(&cf, b"x", b"1").unwrap();
+        txn1.commit().unwrap();
+        txn2.put_cf(&cf, b"x", b"1").unwrap();
+        assert!(txn2.commit().is_err(), "conflicting commit should fail");
+
+        let report = check(&history.records());
+        assert!(report.is_serializable(), "{report}");
+        assert_eq!(report.aborted, 1);
+    }
+
+    #[test]
+    fn snapshot_prevents_lost_update_in_transaction_db() {
+        let (_dir, db) = temp_transaction_db();
+        let history = History::default();
+        let config = WorkloadConfig {
+            snapshot: true,
+            ..WorkloadConfig::default()
+        };
+        let cf = db.user_cf();
+
+        let mut txn1 = history.begin(db.begin(&config));
+        let mut txn2 = history.begin(db.begin(&config));
+        txn1.get_cf(&cf, b"x").unwrap();
+        txn2.get_cf(&cf, b"x").unwrap();
+        txn1.put_cf(&cf, b"x", b"1").unwrap();
+        txn1.commit().unwrap();
+        // The write is validated against the snapshot when the lock is taken.
+        assert!(
+            txn2.put_cf(&cf, b"x", b"1").is_err(),
+            "stale write should fail"
+        );
+        txn2.rollback().unwrap();
+
+        let report = check(&history.records());
+        assert!(report.is_serializable(), "{report}");
+        assert_eq!(report.aborted, 1);
+    }
+
+    #[test]
+    fn recorder_catches_lost_update_in_optimistic_db() {
+        let (_dir, db) = temp_optimistic_db();
+        let history = History::default();
+        let config = WorkloadConfig::default();
+        let cf = db.user_cf();
+
+        let mut txn1 = history.begin(db.begin(&config));
+        let mut txn2 = history.begin(db.begin(&config));
+        txn1.get_cf(&cf, b"x").unwrap();
+        txn2.get_cf(&cf, b"x").unwrap();
+        txn1.put_cf(&cf, b"x", b"1").unwrap();
+        txn1.commit().unwrap();
+        // Without a snapshot the write is checked from when it was made, after txn1 committed.
+        txn2.put_cf(&cf, b"x", b"1").unwrap();
+        txn2.commit().unwrap();
+
+        let report = check(&history.records());
+        assert_eq!(report.lost_updates(), 1, "{report}");
+    }
+
+    #[tokio::test]
+    async fn locking_reads_keep_concurrent_counter_serializable() {
+        let (_dir, db) = temp_transaction_db();
+        let db = Arc::new(db);
+        let history = Arc::new(History::default());
+        let config = WorkloadConfig {
+            lock_reads: true,
+            ..WorkloadConfig::default()
+        };
+
+        let (value, committed) = counter_workload(db, history.clone(), config).await.unwrap();
+
+        let report = check(&history.records());
+        assert!(report.is_serializable(), "{report}");
+        assert_eq!(value, committed);
+    }
+
+    #[tokio::test]
+    async fn snapshots_keep_concurrent_optimistic_counter_serializable() {
+        let (_dir, db) = temp_optimistic_db();
+        let db = Arc::new(db);
+        let history = Arc::new(History::default());
+        let config = WorkloadConfig {
+            snapshot: true,
+            ..WorkloadConfig::default()
+        };
+
+        let (value, committed) = counter_workload(db, history.clone(), config).await.unwrap();
+
+        let report = check(&history.records());
+        assert!(report.is_serializable(), "{report}");
+        assert_eq!(value, committed);
+    }
+
+    #[tokio::test]
+    async fn recorder_catches_write_skew_in_workload() {
+        let (_dir, db) = temp_transaction_db();
+        let db = Arc::new(db);
+        let history = Arc::new(History::default());
+        let config = WorkloadConfig::default();
+
+        let nobody_on_call = write_skew_workload(db, history.clone(), config)
+            .await
+            .unwrap();
+
+        let report = check(&history.records());
+        assert!(nobody_on_call > 0);
+        assert_eq!(report.write_skews(), nobody_on_call, "{report}");
+        assert_eq!(report.lost_updates(), 0, "{report}");
+    }
+}
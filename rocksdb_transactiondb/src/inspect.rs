@@ -0,0 +1,339 @@
+//! Offline access to a database directory, backing the `inspect` binary.
+//!
+//! The files of a `TransactionDB` are regular RocksDB files, so they are opened as a plain
+//! `DB` with every column family they contain: read-only for reads, which works next to a
+//! running process, and read-write for writes, which requires nothing else to have it open.
+//! Writes made this way bypass transactions and their locks.
+//!
+//! Dumps are NDJSON, one `{"key": "<hex>", "value": "<hex>"}` object per record.
+use std::{
+    fmt,
+    io::{BufRead, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rocksdb::{BoundColumnFamily, Direction, IteratorMode, Options, ReadOptions, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::prefix_end;
+
+/// Integer properties reported for every column family.
+pub const CF_PROPERTIES: [&str; 5] = [
+    "rocksdb.estimate-num-keys",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.live-sst-files-size",
+    "rocksdb.size-all-mem-tables",
+    "rocksdb.num-live-versions",
+];
+
+/// Opens every column family of the database at `path` for reading.
+pub fn open_read_only(path: impl AsRef<Path>) -> Result<DB> {
+    let opts = Options::default();
+    let cfs = DB::list_cf(&opts, &path)?;
+    Ok(DB::open_cf_for_read_only(&opts, path, cfs, false)?)
+}
+
+/// Opens every column family of the database at `path` for writing.
+pub fn open(path: impl AsRef<Path>) -> Result<DB> {
+    let opts = Options::default();
+    let cfs = DB::list_cf(&opts, &path)?;
+    Ok(DB::open_cf(&opts, path, cfs)?)
+}
+
+pub fn cf<'a>(db: &'a DB, name: &str) -> Result<Arc<BoundColumnFamily<'a>>> {
+    db.cf_handle(name)
+        .ok_or_else(|| anyhow!("unknown column family {name}"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfInfo {
+    pub name: String,
+    /// Value of every [`CF_PROPERTIES`], `None` when RocksDB does not report it.
+    pub properties: Vec<(&'static str, Option<u64>)>,
+}
+
+impl fmt::Display for CfInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (name, value) in &self.properties {
+            let name = name.trim_start_matches("rocksdb.");
+            match value {
+                Some(value) => write!(f, " {name}={value}")?,
+                None => write!(f, " {name}=?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Column families of the database at `path` with their [`CF_PROPERTIES`].
+pub fn column_families(path: impl AsRef<Path>) -> Result<Vec<CfInfo>> {
+    let db = open_read_only(&path)?;
+    DB::list_cf(&Options::default(), &path)?
+        .into_iter()
+        .map(|name| -> Result<CfInfo> {
+            let handle = cf(&db, &name)?;
+            let properties = CF_PROPERTIES
+                .iter()
+                .map(|property| -> Result<_> {
+                    Ok((*property, db.property_int_value_cf(&handle, *property)?))
+                })
+                .collect::<Result<_>>()?;
+            Ok(CfInfo { name, properties })
+        })
+        .collect()
+}
+
+/// How keys and values are printed, and how arguments are parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hex,
+    /// Invalid UTF-8 is replaced when printing.
+    Utf8,
+    /// Values that are JSON are embedded as is, others are printed as UTF-8.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hex" => Ok(Format::Hex),
+            "utf8" => Ok(Format::Utf8),
+            "json" => Ok(Format::Json),
+            _ => bail!("unknown format {s:?}, expected hex, utf8 or json"),
+        }
+    }
+}
+
+impl Format {
+    pub fn print(self, bytes: &[u8]) -> serde_json::Value {
+        match self {
+            Format::Hex => hex(bytes).into(),
+            Format::Utf8 => String::from_utf8_lossy(bytes).into(),
+            Format::Json => serde_json::from_slice(bytes)
+                .unwrap_or_else(|_| String::from_utf8_lossy(bytes).into()),
+        }
+    }
+
+    /// Bytes of a command line argument, JSON arguments are stored as given.
+    pub fn parse(self, arg: &str) -> Result<Vec<u8>> {
+        match self {
+            Format::Hex => unhex(arg),
+            Format::Utf8 => Ok(arg.as_bytes().to_vec()),
+            Format::Json => {
+                serde_json::from_str::<serde_json::Value>(arg).context("invalid JSON")?;
+                Ok(arg.as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn unhex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        bail!("odd number of hex digits in {s:?}");
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .with_context(|| format!("invalid hex {s:?}"))
+        })
+        .collect()
+}
+
+/// Keys visited by [`scan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bounds {
+    All,
+    Prefix(Vec<u8>),
+    /// `[start, end)`, `end = None` is unbounded.
+    Range {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    },
+}
+
+/// Records of `cf` within `bounds`, in key order.
+pub fn scan<'a>(
+    db: &'a DB,
+    cf: &Arc<BoundColumnFamily<'a>>,
+    bounds: &Bounds,
+) -> impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>)>> + 'a {
+    let (start, end) = match bounds {
+        Bounds::All => (Vec::new(), None),
+        Bounds::Prefix(prefix) => (prefix.clone(), prefix_end(prefix)),
+        Bounds::Range { start, end } => (start.clone(), end.clone()),
+    };
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = end {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    db.iterator_cf_opt(
+        cf,
+        read_opts,
+        IteratorMode::From(&start, Direction::Forward),
+    )
+    .map(|item| item.map_err(anyhow::Error::from))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpRecord {
+    key: String,
+    value: String,
+}
+
+/// Writes every record of `cf` to `out` as NDJSON, returns the number of records.
+pub fn dump(db: &DB, cf_name: &str, mut out: impl Write) -> Result<usize> {
+    let handle = cf(db, cf_name)?;
+    let mut count = 0;
+    for item in scan(db, &handle, &Bounds::All) {
+        let (key, value) = item?;
+        let record = DumpRecord {
+            key: hex(&key),
+            value: hex(&value),
+        };
+        serde_json::to_writer(&mut out, &record)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Records read from NDJSON written by [`dump`] are written to `cf` in batches of
+/// `batch_size`, returns the number of records.
+pub fn load(db: &DB, cf_name: &str, input: impl BufRead, batch_size: usize) -> Result<usize> {
+    let handle = cf(db, cf_name)?;
+    let mut batch = WriteBatch::default();
+    let mut count = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: DumpRecord = serde_json::from_str(&line)
+            .with_context(|| format!("invalid record on line {}", index + 1))?;
+        batch.put_cf(&handle, unhex(&record.key)?, unhex(&record.value)?);
+        count += 1;
+        if batch.len() >= batch_size {
+            db.write(std::mem::take(&mut batch))?;
+        }
+    }
+    db.write(batch)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::temp_transaction_db, DBColumnFamilies};
+
+    fn seeded_dir() -> tempfile::TempDir {
+        let (dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        db.put_cf(&cf, b"order/1", br#"{"total":1}"#).unwrap();
+        db.put_cf(&cf, b"order/2", b"\xff").unwrap();
+        db.put_cf(&cf, b"user1", b"alice").unwrap();
+        dir
+    }
+
+    fn keys(db: &DB, bounds: &Bounds) -> Vec<String> {
+        let handle = cf(db, "User").unwrap();
+        scan(db, &handle, bounds)
+            .map(|item| String::from_utf8(item.unwrap().0.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Format::Hex.print(b"\x00\xffa"), "00ff61");
+        assert_eq!(Format::Hex.parse("00ff61").unwrap(), b"\x00\xffa");
+        assert!(Format::Hex.parse("0").is_err());
+        assert!(Format::Hex.parse("zz").is_err());
+        assert_eq!(Format::Utf8.print(b"a\xff"), "a\u{fffd}");
+        assert_eq!(
+            Format::Json.print(br#"{"total":1}"#),
+            serde_json::json!({"total": 1})
+        );
+        assert_eq!(Format::Json.print(b"alice"), "alice");
+        assert!(Format::Json.parse("{").is_err());
+        assert_eq!("utf8".parse::<Format>().unwrap(), Format::Utf8);
+    }
+
+    #[test]
+    fn lists_column_families() {
+        let dir = seeded_dir();
+        let cfs = column_families(dir.path()).unwrap();
+        let names: Vec<_> = cfs.iter().map(|cf| cf.name.as_str()).collect();
+        assert_eq!(names, vec!["default", "User", "Meta", "Namespace"]);
+        assert_eq!(cfs[1].properties.len(), CF_PROPERTIES.len());
+    }
+
+    #[test]
+    fn scans_by_prefix_and_range() {
+        let dir = seeded_dir();
+        let db = open_read_only(dir.path()).unwrap();
+
+        assert_eq!(keys(&db, &Bounds::All), vec!["order/1", "order/2", "user1"]);
+        assert_eq!(
+            keys(&db, &Bounds::Prefix(b"order/".to_vec())),
+            vec!["order/1", "order/2"]
+        );
+        assert_eq!(
+            keys(
+                &db,
+                &Bounds::Range {
+                    start: b"order/2".to_vec(),
+                    end: Some(b"user1".to_vec()),
+                }
+            ),
+            vec!["order/2"]
+        );
+        assert_eq!(
+            keys(
+                &db,
+                &Bounds::Range {
+                    start: b"p".to_vec(),
+                    end: None,
+                }
+            ),
+            vec!["user1"]
+        );
+    }
+
+    #[test]
+    fn dump_and_load_roundtrip() {
+        let dir = seeded_dir();
+        let mut ndjson = Vec::new();
+        {
+            let db = open_read_only(dir.path()).unwrap();
+            assert_eq!(dump(&db, "User", &mut ndjson).unwrap(), 3);
+        }
+        assert_eq!(
+            String::from_utf8(ndjson.clone()).unwrap().lines().next(),
+            Some(r#"{"key":"6f726465722f31","value":"7b22746f74616c223a317d"}"#)
+        );
+
+        let (target_dir, target) = temp_transaction_db();
+        drop(target);
+        let db = open(target_dir.path()).unwrap();
+        assert_eq!(load(&db, "User", ndjson.as_slice(), 2).unwrap(), 3);
+        assert_eq!(keys(&db, &Bounds::All), vec!["order/1", "order/2", "user1"]);
+        assert_eq!(
+            db.get_cf(&cf(&db, "User").unwrap(), b"order/2").unwrap(),
+            Some(b"\xff".to_vec())
+        );
+        assert!(load(&db, "User", &b"not json\n"[..], 2).is_err());
+    }
+}
@@ -0,0 +1,712 @@
+//! The keys of every namespace, in [`DBColumnFamilies::User`].
+//!
+//! A key is stored under its namespace id followed by `/`. Namespace ids can't contain `/`
+//! (see [`crate::namespace::validate_id`]), so the keys of a namespace are exactly those
+//! starting with its prefix and can be scanned or purged together.
+//!
+//! Values are stored as a record:
+//! - the version, a big endian `u64` bumped by every write, starting at 1;
+//! - the length of the content type, a big endian `u16`, 0 when there is none;
+//! - the content type;
+//! - the value itself.
+//!
+//! Deleting a key leaves its last version in [`DBColumnFamilies::Meta`], under `kv_deleted/`
+//! followed by the stored key, and writing the key again carries on from it. Versions of a key
+//! never repeat, so that a version seen once names a single content, for `ETag`s and
+//! preconditions. These tombstones are kept, one small record per deleted key.
+//!
+//! Writes lock their keys. Transactions of the store detect deadlocks, a write waiting for a
+//! lock fails once it times out or would deadlock, see [`is_contention`].
+use std::{fmt, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use rocksdb::{
+    BoundColumnFamily, Direction, ErrorKind, IteratorMode, ReadOptions, Transaction, TransactionDB,
+    TransactionOptions, WriteOptions,
+};
+
+use crate::{pinned::Snapshot, prefix_end, DBColumnFamilies};
+
+/// Length of the fixed part of a record: the version and the length of the content type.
+const HEADER_LEN: usize = 8 + 2;
+/// Prefix of the last version of deleted keys, in the Meta column family.
+const DELETED_PREFIX: &[u8] = b"kv_deleted/";
+
+/// Prefix of every key of `namespace`.
+pub fn namespace_prefix(namespace: &str) -> Vec<u8> {
+    [namespace.as_bytes(), b"/"].concat()
+}
+
+/// Key under which `key` of `namespace` is stored.
+pub fn encode_key(namespace: &str, key: &[u8]) -> Vec<u8> {
+    [namespace.as_bytes(), b"/", key].concat()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    pub content_type: Option<String>,
+    pub version: u64,
+}
+
+impl Entry {
+    pub(crate) fn encode(
+        version: u64,
+        content_type: Option<&str>,
+        value: &[u8],
+    ) -> Result<Vec<u8>> {
+        let content_type = content_type.unwrap_or_default();
+        let Ok(content_type_len) = u16::try_from(content_type.len()) else {
+            bail!("content type of {} bytes is too long", content_type.len());
+        };
+        let mut record = Vec::with_capacity(HEADER_LEN + content_type.len() + value.len());
+        record.extend_from_slice(&version.to_be_bytes());
+        record.extend_from_slice(&content_type_len.to_be_bytes());
+        record.extend_from_slice(content_type.as_bytes());
+        record.extend_from_slice(value);
+        Ok(record)
+    }
+
+    pub(crate) fn decode(record: &[u8]) -> Result<Self> {
+        let (version, rest) = record
+            .split_first_chunk::<8>()
+            .context("record too short for its version")?;
+        let (content_type_len, rest) = rest
+            .split_first_chunk::<2>()
+            .context("record too short for its content type length")?;
+        let content_type_len = usize::from(u16::from_be_bytes(*content_type_len));
+        if rest.len() < content_type_len {
+            bail!("record too short for its content type");
+        }
+        let (content_type, value) = rest.split_at(content_type_len);
+        Ok(Self {
+            value: value.to_vec(),
+            content_type: (content_type_len > 0)
+                .then(|| String::from_utf8(content_type.to_vec()))
+                .transpose()?,
+            version: u64::from_be_bytes(*version),
+        })
+    }
+}
+
+/// Outcome of [`KvStore::put`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    pub version: u64,
+    /// The key didn't exist before.
+    pub created: bool,
+}
+
+/// Keys of a namespace to scan, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRange {
+    /// Only keys starting with it.
+    pub prefix: Vec<u8>,
+    /// Only keys after it, excluded.
+    pub start_after: Option<Vec<u8>>,
+}
+
+/// Keys found by a [`scan`], with their entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub entries: Vec<(Vec<u8>, Entry)>,
+    /// There are more keys in the range after the last one.
+    pub more: bool,
+}
+
+/// Scans up to `limit` keys of `range` in `namespace`, at `snapshot` when given and at the
+/// latest state otherwise.
+pub fn scan(
+    db: &TransactionDB,
+    snapshot: Option<&Snapshot<'_>>,
+    namespace: &str,
+    range: &KeyRange,
+    limit: usize,
+) -> Result<Page> {
+    let lower = encode_key(namespace, &range.prefix);
+    let from = match &range.start_after {
+        // The smallest key after `start_after`.
+        Some(after) => [encode_key(namespace, after).as_slice(), b"\0"].concat(),
+        None => lower.clone(),
+    }
+    .max(lower.clone());
+
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = prefix_end(&lower) {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    if let Some(snapshot) = snapshot {
+        read_opts.set_snapshot(snapshot);
+    }
+    let prefix_len = namespace_prefix(namespace).len();
+    // One more than asked for tells whether there are more.
+    let mut entries = db
+        .iterator_cf_opt(
+            &DBColumnFamilies::User.cf_db(db),
+            read_opts,
+            IteratorMode::From(&from, Direction::Forward),
+        )
+        .take(limit + 1)
+        .map(|item| -> Result<(Vec<u8>, Entry)> {
+            let (key, record) = item?;
+            Ok((key[prefix_len..].to_vec(), Entry::decode(&record)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let more = entries.len() > limit;
+    entries.truncate(limit);
+    Ok(Page { entries, more })
+}
+
+/// What a key must hold for a transaction to go on, see [`Op::Check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    /// This value, `None` for a missing key.
+    Value(Option<Vec<u8>>),
+    /// This version, `None` for a missing key.
+    Version(Option<u64>),
+}
+
+impl Expected {
+    pub fn matches(&self, actual: Option<&Entry>) -> bool {
+        match self {
+            Self::Value(value) => actual.map(|entry| &entry.value) == value.as_ref(),
+            Self::Version(version) => actual.map(|entry| entry.version) == *version,
+        }
+    }
+}
+
+/// Operation of a transaction applied by [`KvStore::apply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Get {
+        key: Vec<u8>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        content_type: Option<String>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// Locks the key and fails the transaction with [`PreconditionFailed`] unless it holds
+    /// what's expected.
+    Check {
+        key: Vec<u8>,
+        expected: Expected,
+    },
+}
+
+/// Result of an [`Op`], in the same order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpResult {
+    Got(Option<Entry>),
+    Put(Written),
+    /// Whether the key existed.
+    Deleted(bool),
+    Checked,
+}
+
+/// Returned, through `anyhow`, when an [`Op::Check`] fails. Nothing was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreconditionFailed {
+    /// Position of the check among the operations.
+    pub index: usize,
+    pub key: Vec<u8>,
+    pub expected: Expected,
+    /// Version found, `None` when the key is missing.
+    pub actual_version: Option<u64>,
+}
+
+impl fmt::Display for PreconditionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "precondition of operation {} failed on key {}",
+            self.index,
+            String::from_utf8_lossy(&self.key)
+        )
+    }
+}
+
+impl std::error::Error for PreconditionFailed {}
+
+/// Whether `error` comes from a write that lost to concurrent transactions: its lock wait
+/// timed out, or it would have deadlocked. Nothing was written and retrying may succeed.
+pub fn is_contention(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<rocksdb::Error>().is_some_and(|error| {
+            matches!(
+                error.kind(),
+                // Deadlocks are reported as `Busy`.
+                ErrorKind::Busy | ErrorKind::TimedOut | ErrorKind::TryAgain
+            )
+        })
+    })
+}
+
+/// Column families the store writes to.
+struct Families<'db> {
+    user: Arc<BoundColumnFamily<'db>>,
+    meta: Arc<BoundColumnFamily<'db>>,
+}
+
+impl<'db> Families<'db> {
+    fn of(db: &'db TransactionDB) -> Self {
+        Self {
+            user: DBColumnFamilies::User.cf_db(db),
+            meta: DBColumnFamilies::Meta.cf_db(db),
+        }
+    }
+}
+
+fn deleted_key(key: &[u8]) -> Vec<u8> {
+    [DELETED_PREFIX, key].concat()
+}
+
+fn get_for_update_in(
+    txn: &Transaction<'_, TransactionDB>,
+    cfs: &Families<'_>,
+    key: &[u8],
+) -> Result<Option<Entry>> {
+    txn.get_for_update_cf(&cfs.user, key, true)?
+        .map(|record| Entry::decode(&record))
+        .transpose()
+}
+
+fn put_in(
+    txn: &Transaction<'_, TransactionDB>,
+    cfs: &Families<'_>,
+    key: &[u8],
+    value: &[u8],
+    content_type: Option<&str>,
+) -> Result<Written> {
+    let previous = get_for_update_in(txn, cfs, key)?;
+    let last = match &previous {
+        Some(previous) => previous.version,
+        None => {
+            // Only written under the lock of `key`, which is held.
+            let deleted = txn.get_cf(&cfs.meta, deleted_key(key))?;
+            match deleted {
+                Some(raw) => {
+                    txn.delete_cf(&cfs.meta, deleted_key(key))?;
+                    u64::from_be_bytes(
+                        raw.as_slice()
+                            .try_into()
+                            .context("deleted version is not a u64")?,
+                    )
+                }
+                None => 0,
+            }
+        }
+    };
+    let version = last + 1;
+    txn.put_cf(&cfs.user, key, Entry::encode(version, content_type, value)?)?;
+    Ok(Written {
+        version,
+        created: previous.is_none(),
+    })
+}
+
+fn delete_in(txn: &Transaction<'_, TransactionDB>, cfs: &Families<'_>, key: &[u8]) -> Result<bool> {
+    let Some(previous) = get_for_update_in(txn, cfs, key)? else {
+        return Ok(false);
+    };
+    txn.delete_cf(&cfs.user, key)?;
+    txn.put_cf(&cfs.meta, deleted_key(key), previous.version.to_be_bytes())?;
+    Ok(true)
+}
+
+/// Values of the keys of every namespace. Namespaces are expected to be validated by the
+/// caller, see [`crate::namespace::NamespaceRegistry`].
+pub struct KvStore {
+    db: Arc<TransactionDB>,
+}
+
+impl KvStore {
+    pub fn new(db: Arc<TransactionDB>) -> Self {
+        Self { db }
+    }
+
+    pub fn db(&self) -> &Arc<TransactionDB> {
+        &self.db
+    }
+
+    fn transaction(&self) -> Transaction<'_, TransactionDB> {
+        let mut txn_opts = TransactionOptions::default();
+        // Fail right away instead of waiting for the lock timeout.
+        txn_opts.set_deadlock_detect(true);
+        self.db.transaction_opt(&WriteOptions::default(), &txn_opts)
+    }
+
+    pub fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Entry>> {
+        self.db
+            .get_cf(
+                &DBColumnFamilies::User.cf_db(&self.db),
+                encode_key(namespace, key),
+            )?
+            .map(|record| Entry::decode(&record))
+            .transpose()
+    }
+
+    /// Writes `value`, locking the key to bump its version.
+    pub fn put(
+        &self,
+        namespace: &str,
+        key: &[u8],
+        value: &[u8],
+        content_type: Option<&str>,
+    ) -> Result<Written> {
+        let cfs = Families::of(&self.db);
+        let txn = self.transaction();
+        let written = put_in(&txn, &cfs, &encode_key(namespace, key), value, content_type)?;
+        txn.commit()?;
+        Ok(written)
+    }
+
+    /// Removes the key, returns whether it existed.
+    pub fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool> {
+        let cfs = Families::of(&self.db);
+        let txn = self.transaction();
+        let deleted = delete_in(&txn, &cfs, &encode_key(namespace, key))?;
+        txn.commit()?;
+        Ok(deleted)
+    }
+
+    /// Applies `ops` in order in a single transaction, returns their results. Reads see the
+    /// writes made before them. Either every write is committed or, when a check or anything
+    /// else fails, none is.
+    pub fn apply(&self, namespace: &str, ops: &[Op]) -> Result<Vec<OpResult>> {
+        let cfs = Families::of(&self.db);
+        let txn = self.transaction();
+        let mut results = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            results.push(match op {
+                Op::Get { key } => OpResult::Got(
+                    txn.get_cf(&cfs.user, encode_key(namespace, key))?
+                        .map(|record| Entry::decode(&record))
+                        .transpose()?,
+                ),
+                Op::Put {
+                    key,
+                    value,
+                    content_type,
+                } => OpResult::Put(put_in(
+                    &txn,
+                    &cfs,
+                    &encode_key(namespace, key),
+                    value,
+                    content_type.as_deref(),
+                )?),
+                Op::Delete { key } => {
+                    OpResult::Deleted(delete_in(&txn, &cfs, &encode_key(namespace, key))?)
+                }
+                Op::Check { key, expected } => {
+                    // Locked, so that it still holds at commit.
+                    let actual = get_for_update_in(&txn, &cfs, &encode_key(namespace, key))?;
+                    if !expected.matches(actual.as_ref()) {
+                        // Dropping the transaction rolls it back.
+                        return Err(PreconditionFailed {
+                            index,
+                            key: key.clone(),
+                            expected: expected.clone(),
+                            actual_version: actual.map(|entry| entry.version),
+                        }
+                        .into());
+                    }
+                    OpResult::Checked
+                }
+            });
+        }
+        txn.commit()?;
+        Ok(results)
+    }
+
+    /// Scans `range` at the latest state, see [`scan`].
+    pub fn scan(&self, namespace: &str, range: &KeyRange, limit: usize) -> Result<Page> {
+        scan(&self.db, None, namespace, range, limit)
+    }
+
+    /// Removes every key of `namespace`, returns how many there were. Their versions carry
+    /// on when the namespace is created again.
+    pub fn clear(&self, namespace: &str) -> Result<usize> {
+        let cfs = Families::of(&self.db);
+        let prefix = namespace_prefix(namespace);
+        let mut read_opts = ReadOptions::default();
+        if let Some(end) = prefix_end(&prefix) {
+            read_opts.set_iterate_upper_bound(end);
+        }
+        let txn = self.transaction();
+        // Writing to the transaction while iterating over it is not safe, collect first.
+        let keys = txn
+            .iterator_cf_opt(
+                &cfs.user,
+                read_opts,
+                IteratorMode::From(&prefix, Direction::Forward),
+            )
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut deleted = 0;
+        for key in &keys {
+            // Locks the key and reads its version again, it may have changed since the scan.
+            if delete_in(&txn, &cfs, key)? {
+                deleted += 1;
+            }
+        }
+        txn.commit()?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_transaction_db;
+
+    fn store() -> (tempfile::TempDir, KvStore) {
+        let (dir, db) = temp_transaction_db();
+        (dir, KvStore::new(Arc::new(db)))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for (content_type, value) in [
+            (Some("text/plain"), b"hello".as_slice()),
+            (None, b"".as_slice()),
+            (Some("application/octet-stream"), b"\x00\xff".as_slice()),
+        ] {
+            let record = Entry::encode(7, content_type, value).unwrap();
+            assert_eq!(
+                Entry::decode(&record).unwrap(),
+                Entry {
+                    value: value.to_vec(),
+                    content_type: content_type.map(str::to_owned),
+                    version: 7,
+                }
+            );
+        }
+
+        assert!(Entry::encode(1, Some(&"a".repeat(70_000)), b"").is_err());
+        assert!(Entry::decode(b"\x00\x00").is_err());
+        assert!(Entry::decode(b"\x00\x00\x00\x00\x00\x00\x00\x01\x00\x05abc").is_err());
+    }
+
+    #[test]
+    fn puts_gets_and_deletes_with_versions() {
+        let (_dir, store) = store();
+
+        assert_eq!(
+            store
+                .put("orders", b"1", b"first", Some("text/plain"))
+                .unwrap(),
+            Written {
+                version: 1,
+                created: true
+            }
+        );
+        assert_eq!(
+            store.put("orders", b"1", b"second", None).unwrap(),
+            Written {
+                version: 2,
+                created: false
+            }
+        );
+        assert_eq!(
+            store.get("orders", b"1").unwrap(),
+            Some(Entry {
+                value: b"second".to_vec(),
+                content_type: None,
+                version: 2,
+            })
+        );
+        assert_eq!(store.get("orders", b"2").unwrap(), None);
+
+        assert!(store.delete("orders", b"1").unwrap());
+        assert!(!store.delete("orders", b"1").unwrap());
+        assert_eq!(store.get("orders", b"1").unwrap(), None);
+        // Versions carry on after a deletion, a version names a single content.
+        assert_eq!(
+            store.put("orders", b"1", b"again", None).unwrap(),
+            Written {
+                version: 3,
+                created: true
+            }
+        );
+
+        assert_eq!(store.clear("orders").unwrap(), 1);
+        assert_eq!(
+            store.put("orders", b"1", b"cleared", None).unwrap().version,
+            4
+        );
+        assert_eq!(store.put("orders", b"2", b"new", None).unwrap().version, 1);
+    }
+
+    #[test]
+    fn scans_ranges_in_pages() {
+        let (_dir, store) = store();
+        for key in [b"a".as_slice(), b"b/1", b"b/2", b"b/3", b"c"] {
+            store.put("orders", key, key, None).unwrap();
+        }
+        store.put("orders_2", b"b/4", b"other", None).unwrap();
+        let keys = |page: &Page| -> Vec<Vec<u8>> {
+            page.entries.iter().map(|(key, _)| key.clone()).collect()
+        };
+
+        let page = store.scan("orders", &KeyRange::default(), 10).unwrap();
+        assert_eq!(keys(&page), [b"a".as_slice(), b"b/1", b"b/2", b"b/3", b"c"]);
+        assert_eq!(page.entries[1].1.value, b"b/1");
+        assert!(!page.more);
+
+        let range = KeyRange {
+            prefix: b"b/".to_vec(),
+            start_after: None,
+        };
+        let page = store.scan("orders", &range, 2).unwrap();
+        assert_eq!(keys(&page), [b"b/1".as_slice(), b"b/2"]);
+        assert!(page.more);
+        let range = KeyRange {
+            start_after: Some(b"b/2".to_vec()),
+            ..range
+        };
+        let page = store.scan("orders", &range, 2).unwrap();
+        assert_eq!(keys(&page), [b"b/3".as_slice()]);
+        assert!(!page.more);
+
+        // Starting before the prefix starts at the prefix.
+        let range = KeyRange {
+            prefix: b"b/".to_vec(),
+            start_after: Some(b"a".to_vec()),
+        };
+        assert_eq!(keys(&store.scan("orders", &range, 1).unwrap()), [b"b/1"]);
+    }
+
+    #[test]
+    fn contended_writes_fail_without_writing() {
+        let (_dir, store) = store();
+        store.put("orders", b"1", b"pending", None).unwrap();
+
+        let holder = store.db().transaction();
+        holder
+            .get_for_update_cf(
+                &DBColumnFamilies::User.cf_db(store.db()),
+                encode_key("orders", b"1"),
+                true,
+            )
+            .unwrap();
+        let error = store.put("orders", b"1", b"paid", None).unwrap_err();
+        assert!(is_contention(&error), "{error:#}");
+        let error = store
+            .apply(
+                "orders",
+                &[
+                    Op::Put {
+                        key: b"2".to_vec(),
+                        value: b"new".to_vec(),
+                        content_type: None,
+                    },
+                    Op::Delete { key: b"1".to_vec() },
+                ],
+            )
+            .unwrap_err();
+        assert!(is_contention(&error), "{error:#}");
+        assert!(!is_contention(&anyhow::anyhow!("other")));
+        drop(holder);
+
+        assert_eq!(
+            store.get("orders", b"1").unwrap().unwrap().value,
+            b"pending"
+        );
+        assert_eq!(store.get("orders", b"2").unwrap(), None);
+        assert!(store.delete("orders", b"1").unwrap());
+    }
+
+    #[test]
+    fn applies_transactions_atomically() {
+        let (_dir, store) = store();
+        store.put("orders", b"1", b"pending", None).unwrap();
+
+        let results = store
+            .apply(
+                "orders",
+                &[
+                    Op::Check {
+                        key: b"1".to_vec(),
+                        expected: Expected::Version(Some(1)),
+                    },
+                    Op::Check {
+                        key: b"2".to_vec(),
+                        expected: Expected::Value(None),
+                    },
+                    Op::Put {
+                        key: b"1".to_vec(),
+                        value: b"paid".to_vec(),
+                        content_type: Some("text/plain".into()),
+                    },
+                    Op::Get { key: b"1".to_vec() },
+                    Op::Delete { key: b"2".to_vec() },
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                OpResult::Checked,
+                OpResult::Checked,
+                OpResult::Put(Written {
+                    version: 2,
+                    created: false
+                }),
+                OpResult::Got(Some(Entry {
+                    value: b"paid".to_vec(),
+                    content_type: Some("text/plain".into()),
+                    version: 2,
+                })),
+                OpResult::Deleted(false),
+            ]
+        );
+
+        let error = store
+            .apply(
+                "orders",
+                &[
+                    Op::Put {
+                        key: b"2".to_vec(),
+                        value: b"new".to_vec(),
+                        content_type: None,
+                    },
+                    Op::Check {
+                        key: b"1".to_vec(),
+                        expected: Expected::Value(Some(b"pending".to_vec())),
+                    },
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<PreconditionFailed>(),
+            Some(&PreconditionFailed {
+                index: 1,
+                key: b"1".to_vec(),
+                expected: Expected::Value(Some(b"pending".to_vec())),
+                actual_version: Some(2),
+            })
+        );
+        assert_eq!(store.get("orders", b"2").unwrap(), None, "rolled back");
+        assert_eq!(store.get("orders", b"1").unwrap().unwrap().value, b"paid");
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        let (_dir, store) = store();
+        store.put("a", b"b/1", b"a", None).unwrap();
+        store.put("a_b", b"1", b"a_b", None).unwrap();
+        store.put("ab", b"1", b"ab", None).unwrap();
+
+        assert_eq!(store.get("a_b", b"1").unwrap().unwrap().value, b"a_b");
+        assert_eq!(store.get("a", b"1").unwrap(), None);
+
+        assert_eq!(store.clear("a").unwrap(), 1);
+        assert_eq!(store.get("a", b"b/1").unwrap(), None);
+        assert_eq!(store.get("a_b", b"1").unwrap().unwrap().value, b"a_b");
+        assert_eq!(store.get("ab", b"1").unwrap().unwrap().value, b"ab");
+    }
+}
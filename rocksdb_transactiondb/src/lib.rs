@@ -0,0 +1,132 @@
+#![warn(clippy::pedantic)]
+#![allow(clippy::similar_names)]
+#![allow(clippy::single_match_else)]
+#![allow(clippy::too_many_lines)]
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::missing_panics_doc)]
+#![allow(clippy::must_use_candidate)]
+use std::sync::Arc;
+
+use rocksdb::{BoundColumnFamily, OptimisticTransactionDB, TransactionDB};
+
+pub mod blob;
+pub mod bulk_load;
+pub mod history;
+pub mod inspect;
+pub mod kv;
+pub mod migration;
+pub mod model;
+pub mod namespace;
+pub mod pinned;
+pub mod prefix_delete;
+pub mod range_lock;
+pub mod report;
+pub mod schedule;
+pub mod secondary;
+pub mod tenant;
+pub mod ttl;
+pub mod txn_guard;
+pub mod txn_registry;
+
+pub trait OptionExtensions<T> {
+    fn expect_lazy<F: FnOnce() -> String>(self, msg_getter: F) -> T;
+}
+impl<T> OptionExtensions<T> for Option<T> {
+    fn expect_lazy<F: FnOnce() -> String>(self, msg_getter: F) -> T {
+        match self {
+            Some(t) => t,
+            None => {
+                let msg = msg_getter();
+                panic!("{}", msg);
+            }
+        }
+    }
+}
+
+#[derive(strum::AsRefStr, strum::Display, strum::EnumIter)]
+pub enum DBColumnFamilies {
+    User,
+    /// Internal bookkeeping, like the tenant registry.
+    Meta,
+    /// Namespace records, see [`namespace::NamespaceRegistry`].
+    Namespace,
+}
+
+impl DBColumnFamilies {
+    pub fn cf<'a>(&'a self, db: &'a OptimisticTransactionDB) -> Arc<BoundColumnFamily> {
+        db.cf_handle(self.as_ref())
+            .expect_lazy(|| format!("failed to get column family handle for {}", self.as_ref()))
+    }
+
+    pub fn cf_db<'a>(&'a self, db: &'a TransactionDB) -> Arc<BoundColumnFamily> {
+        db.cf_handle(self.as_ref())
+            .expect_lazy(|| format!("failed to get column family handle for {}", self.as_ref()))
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, `None` when there is none
+/// (the prefix is empty or only made of `0xff` bytes).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use std::path::Path;
+
+    use rocksdb::{
+        ColumnFamilyDescriptor, OptimisticTransactionDB, Options, TransactionDB,
+        TransactionDBOptions,
+    };
+    use strum::IntoEnumIterator;
+    use tempfile::TempDir;
+
+    use super::DBColumnFamilies;
+
+    /// Opens a `TransactionDB` with every `DBColumnFamilies` in a fresh temporary directory.
+    ///
+    /// The directory is removed when the returned `TempDir` is dropped, so keep it alive for
+    /// as long as the database.
+    pub fn temp_transaction_db() -> (TempDir, TransactionDB) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_transaction_db(dir.path());
+        (dir, db)
+    }
+
+    /// Opens or creates a `TransactionDB` with every `DBColumnFamilies` at `path`.
+    pub fn open_transaction_db(path: impl AsRef<Path>) -> TransactionDB {
+        let sm_column_families = DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default()));
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+
+        let txn_opts = TransactionDBOptions::default();
+
+        TransactionDB::open_cf_descriptors(&db_opts, &txn_opts, path, sm_column_families).unwrap()
+    }
+
+    /// Same as [`temp_transaction_db`] for an `OptimisticTransactionDB`.
+    pub fn temp_optimistic_db() -> (TempDir, OptimisticTransactionDB) {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sm_column_families = DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default()));
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+
+        let db =
+            OptimisticTransactionDB::open_cf_descriptors(&db_opts, dir.path(), sm_column_families)
+                .unwrap();
+
+        (dir, db)
+    }
+}
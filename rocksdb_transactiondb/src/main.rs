@@ -2,49 +2,39 @@
 #![allow(clippy::similar_names)]
 #![allow(clippy::single_match_else)]
 #![allow(clippy::too_many_lines)]
-use std::{fs, sync::Arc};
+use std::{
+    fs,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context, Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 use rocksdb::{
-    BoundColumnFamily, ColumnFamilyDescriptor, OptimisticTransactionDB, Options, TransactionDB,
-    TransactionDBOptions,
+    perf::MemoryUsageBuilder, ColumnFamilyDescriptor, Direction, ErrorKind, IteratorMode,
+    OptimisticTransactionDB, Options, ReadOptions, Transaction, TransactionDB,
+    TransactionDBOptions, WriteOptions,
+};
+use rocksdb_transactiondb::{
+    blob::{self, BlobConfig, WriteStats},
+    history::{check, counter_workload, write_skew_workload, Engine, History, WorkloadConfig},
+    migration::{migrations, Migrator},
+    model::{self, Op},
+    prefix_delete::delete_prefix_in_txn,
+    prefix_end,
+    range_lock::{KeyRange, LockMode, RangeLockManager},
+    schedule::explore,
+    secondary::{beat, Secondary},
+    tenant::{open_tenant_db, TenantRegistry},
+    ttl::SystemClock,
+    txn_guard::{leaked_transactions, GuardedTransactions},
+    txn_registry::{expiring_txn_opts, Reaped, TxnRegistry},
+    DBColumnFamilies,
 };
 use strum::IntoEnumIterator;
 use tokio::sync::oneshot;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-pub trait OptionExtensions<T> {
-    fn expect_lazy<F: FnOnce() -> String>(self, msg_getter: F) -> T;
-}
-impl<T> OptionExtensions<T> for Option<T> {
-    fn expect_lazy<F: FnOnce() -> String>(self, msg_getter: F) -> T {
-        match self {
-            Some(t) => t,
-            None => {
-                let msg = msg_getter();
-                panic!("{}", msg);
-            }
-        }
-    }
-}
-
-#[derive(strum::AsRefStr, strum::Display, strum::EnumIter)]
-pub enum DBColumnFamilies {
-    User,
-}
-
-impl DBColumnFamilies {
-    pub fn cf<'a>(&'a self, db: &'a OptimisticTransactionDB) -> Arc<BoundColumnFamily> {
-        db.cf_handle(self.as_ref())
-            .expect_lazy(|| format!("failed to get column family handle for {}", self.as_ref()))
-    }
-
-    pub fn cf_db<'a>(&'a self, db: &'a TransactionDB) -> Arc<BoundColumnFamily> {
-        db.cf_handle(self.as_ref())
-            .expect_lazy(|| format!("failed to get column family handle for {}", self.as_ref()))
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     {
@@ -58,20 +48,13 @@ async fn main() -> Result<()> {
     let path = ".rocksdb_storage";
     fs::create_dir_all(path)?;
 
-    let sm_column_families = DBColumnFamilies::iter()
-        .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default()));
     let mut db_opts = Options::default();
     db_opts.create_missing_column_families(true);
     db_opts.create_if_missing(true);
 
-    let txn_opts = TransactionDBOptions::default();
-
-    let db = Arc::new(TransactionDB::open_cf_descriptors(
-        &db_opts,
-        &txn_opts,
-        path,
-        sm_column_families,
-    )?);
+    let migrator = Migrator::new(migrations());
+    let db = Arc::new(migrator.open(&db_opts, path)?);
+    tracing::info!("{}", migrator.status(&db)?);
 
     // ################################################################
     // setup database
@@ -167,75 +150,32 @@ async fn main() -> Result<()> {
     }
 
     // ################################################################
-    // try overwrites to different keys in different transactions (with tasks)
+    // overwrites of the same key in different transactions, every interleaving explored step
+    // by step on a single thread
     // ################################################################
     {
-        let mut task_handles = vec![];
-        let db_task = db.clone();
-        let (tx, mut rx) = oneshot::channel();
-        task_handles.push(tokio::spawn(async move {
-            let result = async move {
-                let txn1 = db_task.transaction();
-
-                txn1.put_cf(
-                    &DBColumnFamilies::User.cf_db(&db_task),
-                    b"user1",
-                    b"user1-txn1",
-                )?;
-
-                txn1.commit()?;
-                tx.send(()).map_err(|e| anyhow!("send error: {e:?}"))?;
-
-                Ok(())
-            }
-            .await;
-
-            match result {
-                Result::Ok(()) => (),
-                Err(err) => panic!("txn1 error: {err:?}"),
-            }
-        }));
-
-        let db_task2 = db.clone();
-        task_handles.push(tokio::spawn(async move {
-            let result = async move {
-                tokio::select! {
-                    _ = &mut rx => (),
-                    () = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {
-                        panic!("timeout");
-                    }
-                }
-                let txn2 = db_task2.transaction();
+        let actors = [
+            vec![Op::Put { txn: 0, key: 1 }, Op::Commit { txn: 0 }],
+            vec![Op::Put { txn: 1, key: 1 }, Op::Commit { txn: 1 }],
+        ];
 
-                txn2.put_cf(
-                    &DBColumnFamilies::User.cf_db(&db_task2),
-                    b"user1",
-                    b"user1-txn2",
-                )
-                .context("cannot put in txn2")?;
-
-                txn2.commit()?;
-
-                Ok(())
-            }
-            .await;
-
-            match result {
-                Result::Ok(()) => (),
-                Err(err) => panic!("txn1 error: {err:?}"),
-            }
-        }));
-
-        for handle in task_handles {
-            handle.await?;
+        let executions = explore(&db, &actors)?;
+        let schedules: Vec<_> = executions.iter().map(|e| e.schedule.clone()).collect();
+        assert_eq!(
+            schedules,
+            vec![vec![0, 0, 1, 1], vec![1, 1, 0, 0]],
+            "the second put should wait for the first commit"
+        );
+        assert!(executions.iter().all(|e| !e.is_stuck()));
+        for execution in &executions {
+            // The put of the actor committing last, its first step.
+            let last = execution.schedule[2];
+            assert_eq!(
+                execution.committed.get(&model::key(1)),
+                Some(&model::value(2, last, 1)),
+                "the last commit wins"
+            );
         }
-
-        let raw_user = db
-            .get_cf(&DBColumnFamilies::User.cf_db(&db), b"user1")?
-            .expect("user1 not found");
-
-        let user = String::from_utf8(raw_user).unwrap();
-        assert_eq!("user1-txn2", user);
     }
 
     // ################################################################
@@ -261,5 +201,463 @@ async fn main() -> Result<()> {
         );
     }
 
+    // ################################################################
+    // ERROR: get_for_update only locks existing keys, inserts into a scanned prefix are phantoms
+    // ################################################################
+    {
+        db.put_cf(&DBColumnFamilies::User.cf_db(&db), b"order/1", b"1")?;
+
+        let txn1 = db.transaction();
+        let txn2 = db.transaction();
+
+        assert_eq!(lock_prefix_keys(&txn1, &db, b"order/")?, 1);
+        assert_eq!(lock_prefix_keys(&txn2, &db, b"order/")?, 1);
+
+        txn1.put_cf(&DBColumnFamilies::User.cf_db(&db), b"order/2", b"2")?;
+        let res = txn2.put_cf(&DBColumnFamilies::User.cf_db(&db), b"order/3", b"3");
+        assert!(res.is_ok(), "phantom insert in txn2 should work");
+        txn1.commit()?;
+        txn2.commit()?;
+
+        assert_eq!(lock_prefix_keys(&db.transaction(), &db, b"order/")?, 3);
+        db.delete_cf(&DBColumnFamilies::User.cf_db(&db), b"order/2")?;
+        db.delete_cf(&DBColumnFamilies::User.cf_db(&db), b"order/3")?;
+    }
+
+    // ################################################################
+    // ERROR: a range lock on the prefix prevents the phantom
+    // ################################################################
+    {
+        let manager = RangeLockManager::new(Duration::from_millis(100));
+        let orders = KeyRange::prefix(DBColumnFamilies::User, "order/");
+
+        let txn1 = db.transaction();
+        let lock1 = manager.lock(manager.owner(), orders.clone(), LockMode::Exclusive)?;
+        assert_eq!(lock_prefix_keys(&txn1, &db, b"order/")?, 1);
+
+        let res = manager.lock(manager.owner(), orders, LockMode::Exclusive);
+        assert!(res.is_err(), "range lock in txn2 should fail");
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            "Timeout waiting to lock range User[order/, order0)"
+        );
+
+        txn1.put_cf(&DBColumnFamilies::User.cf_db(&db), b"order/2", b"2")?;
+        txn1.commit()?;
+        drop(lock1);
+
+        db.delete_cf(&DBColumnFamilies::User.cf_db(&db), b"order/1")?;
+        db.delete_cf(&DBColumnFamilies::User.cf_db(&db), b"order/2")?;
+    }
+
+    // ################################################################
+    // an expired lock holder lets others proceed, and can no longer commit
+    // ################################################################
+    {
+        let txn1 = db.transaction_opt(
+            &WriteOptions::default(),
+            &expiring_txn_opts(Duration::from_millis(100)),
+        );
+        let txn2 = db.transaction();
+
+        txn1.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn1")?;
+        let started = Instant::now();
+        let res = txn2.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn2");
+        assert!(res.is_ok(), "put in txn2 should work once txn1 expired");
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "txn2 should wait for the expiration, not the lock timeout"
+        );
+        txn2.commit()?;
+
+        let res = txn1.commit();
+        assert!(res.is_err(), "commit of txn1 should fail");
+        assert_eq!(res.err().unwrap().kind(), ErrorKind::Expired);
+    }
+
+    // ################################################################
+    // ERROR: an expiration longer than the lock timeout still times out
+    // ################################################################
+    {
+        let txn1 = db.transaction_opt(
+            &WriteOptions::default(),
+            &expiring_txn_opts(Duration::from_secs(5)),
+        );
+        let txn2 = db.transaction();
+
+        txn1.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn1")?;
+        let res = txn2.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn2");
+        assert!(res.is_err(), "put in txn2 should fail");
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            "Operation timed out: Timeout waiting to lock key"
+        );
+    }
+
+    // ################################################################
+    // the reaper rolls back a forgotten transaction and releases its locks
+    // ################################################################
+    {
+        let registry = TxnRegistry::new(&db);
+        let txn1 = registry.begin("forgotten");
+        txn1.put_cf(DBColumnFamilies::User, b"user1", b"user1-txn1")?;
+        tracing::info!("{}", registry.active()[0]);
+
+        thread::scope(|s| -> Result<()> {
+            let registry = &registry;
+            let (stop, stopped) = mpsc::channel();
+            let reaper = s.spawn(move || {
+                registry.reap_every(
+                    Duration::from_millis(100),
+                    Duration::from_millis(10),
+                    &stopped,
+                )
+            });
+
+            let txn2 = db.transaction();
+            let res = txn2.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn2");
+            assert!(res.is_ok(), "put in txn2 should work once txn1 is reaped");
+            txn2.commit()?;
+
+            stop.send(())?;
+            assert_eq!(reaper.join().expect("reaper panicked"), 1);
+            Ok(())
+        })?;
+
+        let res = txn1.commit();
+        assert!(res.is_err(), "commit of txn1 should fail");
+        assert!(res.err().unwrap().is::<Reaped>());
+    }
+
+    // ################################################################
+    // aborting a task in the middle of a transaction rolls it back and reports the leak
+    // ################################################################
+    {
+        let leaked = leaked_transactions();
+        let db_task = db.clone();
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let txn1 = db_task.guarded_transaction();
+
+            txn1.put_cf(
+                &DBColumnFamilies::User.cf_db(&db_task),
+                b"user1",
+                b"user1-txn1",
+            )?;
+            tx.send(()).map_err(|e| anyhow!("send error: {e:?}"))?;
+
+            // Stands for a slow call made while holding the transaction.
+            std::future::pending::<()>().await;
+            txn1.commit()?;
+
+            Ok(())
+        });
+
+        rx.await?;
+        handle.abort();
+        let res = handle.await;
+        assert!(
+            res.err().unwrap().is_cancelled(),
+            "task should be cancelled"
+        );
+        assert_eq!(leaked_transactions(), leaked + 1);
+
+        let txn2 = db.transaction();
+        let res = txn2.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn2");
+        assert!(
+            res.is_ok(),
+            "put in txn2 should work once txn1 is rolled back"
+        );
+        txn2.commit()?;
+    }
+
+    // ################################################################
+    // a future cancelled by a timeout rolls its transaction back as well
+    // ################################################################
+    {
+        let leaked = leaked_transactions();
+        let res = tokio::time::timeout(Duration::from_millis(100), async {
+            let txn1 = db.guarded_transaction();
+
+            txn1.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"user1-txn1")?;
+            std::future::pending::<()>().await;
+            txn1.commit()?;
+
+            Ok(())
+        })
+        .await;
+        assert!(res.is_err(), "future should time out");
+        assert_eq!(leaked_transactions(), leaked + 1);
+
+        let raw_user = db
+            .get_cf(&DBColumnFamilies::User.cf_db(&db), b"user1")?
+            .expect("user1 not found");
+        assert_eq!(b"user1-txn2", raw_user.as_slice());
+    }
+
+    // ################################################################
+    // a secondary instance only sees commits of the primary once it caught up
+    // ################################################################
+    {
+        let secondary = Secondary::open(path, ".rocksdb_storage_secondary")?;
+        let user_cf = secondary
+            .db()
+            .cf_handle(DBColumnFamilies::User.as_ref())
+            .expect("User column family");
+
+        db.put_cf(
+            &DBColumnFamilies::User.cf_db(&db),
+            b"user1",
+            b"user1-primary",
+        )?;
+        beat(&db, &SystemClock)?;
+        let res = secondary.db().get_cf(&user_cf, b"user1")?;
+        assert_ne!(res.as_deref(), Some(b"user1-primary".as_slice()));
+
+        let lag = secondary.catch_up()?;
+        let res = secondary.db().get_cf(&user_cf, b"user1")?;
+        assert_eq!(res.as_deref(), Some(b"user1-primary".as_slice()));
+        tracing::info!("secondary caught up: {lag:?}");
+    }
+
+    // ################################################################
+    // record concurrent histories and check them for serializability
+    // ################################################################
+    {
+        let optimistic_db = Arc::new(OptimisticTransactionDB::open_cf_descriptors(
+            &db_opts,
+            ".rocksdb_storage_optimistic",
+            DBColumnFamilies::iter()
+                .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default())),
+        )?);
+
+        check_histories(db.clone()).await?;
+        check_histories(optimistic_db).await?;
+    }
+
+    // ################################################################
+    // column family per tenant vs key prefix per tenant
+    // ################################################################
+    compare_tenant_layouts(&db_opts)?;
+
+    // ################################################################
+    // large values inline vs in blob files, writes about 400 MB so only on demand
+    // ################################################################
+    if std::env::args().any(|arg| arg == "--compare-blob-storage") {
+        compare_blob_storage()?;
+    }
+
+    Ok(())
+}
+
+const TENANTS: usize = 100;
+const KEYS_PER_TENANT: usize = 100;
+
+/// Opens a fresh database at `path`, removing what a previous run left there.
+fn fresh_tenant_db(db_opts: &Options, path: &str) -> Result<Arc<TransactionDB>> {
+    if fs::exists(path)? {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(Arc::new(open_tenant_db(db_opts, path)?))
+}
+
+/// Approximate memory of the memtables and table readers of every column family of `db`.
+fn memory_usage(db: &TransactionDB) -> Result<u64> {
+    let mut builder = MemoryUsageBuilder::new()?;
+    builder.add_tx_db(db);
+    let usage = builder.build()?;
+    Ok(usage.approximate_mem_table_total() + usage.approximate_mem_table_readers_total())
+}
+
+/// Logs open time, memory and the cost of dropping one tenant, for tenants stored
+/// in their own column family and for tenants stored under a key prefix of `User`.
+fn compare_tenant_layouts(db_opts: &Options) -> Result<()> {
+    let value = vec![0; 100];
+    let tenant = |i: usize| format!("tenant{i:03}");
+
+    {
+        let path = ".rocksdb_storage_tenant_cf";
+        let registry = TenantRegistry::new(fresh_tenant_db(db_opts, path)?);
+        for i in 0..TENANTS {
+            registry.create(&tenant(i))?;
+            let cf = registry.cf(&tenant(i))?;
+            for k in 0..KEYS_PER_TENANT {
+                registry.db().put_cf(&cf, format!("key{k}"), &value)?;
+            }
+        }
+        let memory = memory_usage(registry.db())?;
+        drop(registry);
+
+        let start = Instant::now();
+        let registry = TenantRegistry::new(Arc::new(open_tenant_db(db_opts, path)?));
+        let open_time = start.elapsed();
+
+        let start = Instant::now();
+        registry.remove(&tenant(0))?;
+        let drop_time = start.elapsed();
+
+        tracing::info!(
+            "column family per tenant: open {open_time:?}, memory {memory} bytes, drop {drop_time:?}"
+        );
+    }
+
+    {
+        let path = ".rocksdb_storage_tenant_prefix";
+        let db = fresh_tenant_db(db_opts, path)?;
+        for i in 0..TENANTS {
+            let cf = DBColumnFamilies::User.cf_db(&db);
+            for k in 0..KEYS_PER_TENANT {
+                db.put_cf(&cf, format!("{}/key{k}", tenant(i)), &value)?;
+            }
+        }
+        let memory = memory_usage(&db)?;
+        drop(db);
+
+        let start = Instant::now();
+        let db = open_tenant_db(db_opts, path)?;
+        let open_time = start.elapsed();
+
+        let start = Instant::now();
+        let txn = db.transaction();
+        delete_prefix_in_txn(
+            &txn,
+            &DBColumnFamilies::User.cf_db(&db),
+            format!("{}/", tenant(0)).as_bytes(),
+        )?;
+        txn.commit()?;
+        let drop_time = start.elapsed();
+
+        tracing::info!(
+            "key prefix per tenant: open {open_time:?}, memory {memory} bytes, drop {drop_time:?}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Bytes written per value size, each key overwritten `BLOB_ROUNDS` times.
+const BLOB_DATA: usize = 16 * 1024 * 1024;
+const BLOB_ROUNDS: usize = 4;
+
+/// Logs the write amplification of transactional overwrites and the transactional read
+/// latency, for 1 KiB, 64 KiB and 1 MiB values stored inline and in blob files. The last
+/// memtable is never flushed and compactions may still be running, so the figures are
+/// approximate but comparable between the two layouts.
+fn compare_blob_storage() -> Result<()> {
+    let blob = BlobConfig {
+        min_blob_size: 1024,
+        ..BlobConfig::default()
+    };
+
+    for size in [1024, 64 * 1024, 1024 * 1024] {
+        for (layout, blob) in [("inline", None), ("blob files", Some(&blob))] {
+            let path = format!(
+                ".rocksdb_storage_blob_{size}_{}",
+                if blob.is_some() { "blob" } else { "inline" }
+            );
+            if fs::exists(&path)? {
+                fs::remove_dir_all(&path)?;
+            }
+            let mut db_opts = Options::default();
+            db_opts.create_missing_column_families(true);
+            db_opts.create_if_missing(true);
+            db_opts.enable_statistics();
+            let mut cf_opts = Options::default();
+            // Small memtables, for flushes and compactions to happen with little data.
+            cf_opts.set_write_buffer_size(4 * 1024 * 1024);
+            let db = TransactionDB::open_cf_descriptors(
+                &db_opts,
+                &TransactionDBOptions::default(),
+                &path,
+                DBColumnFamilies::iter().map(|cf| match cf {
+                    DBColumnFamilies::User => blob::descriptor(&cf, cf_opts.clone(), blob),
+                    _ => blob::descriptor(&cf, Options::default(), None),
+                }),
+            )?;
+            let cf = DBColumnFamilies::User.cf_db(&db);
+
+            let keys = BLOB_DATA / size;
+            for round in 0..BLOB_ROUNDS {
+                let value = vec![u8::try_from(round)?; size];
+                for k in 0..keys {
+                    let txn = db.transaction();
+                    txn.put_cf(&cf, format!("key{k:05}"), &value)?;
+                    txn.commit()?;
+                }
+            }
+            let stats = WriteStats::from_options(&db_opts);
+
+            let start = Instant::now();
+            let txn = db.transaction();
+            for k in 0..keys {
+                let value = txn.get_cf(&cf, format!("key{k:05}"))?;
+                assert_eq!(value.map(|v| v.len()), Some(size));
+            }
+            drop(txn);
+            let read_time = start.elapsed() / u32::try_from(keys)?;
+
+            tracing::info!(
+                "{size} byte values, {layout}: write amplification {:.2} ({stats:?}), read {read_time:?}",
+                stats.amplification((BLOB_DATA * BLOB_ROUNDS) as u64)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Scans `prefix` in `txn` and locks every key it finds, returns the number of keys.
+fn lock_prefix_keys(
+    txn: &Transaction<TransactionDB>,
+    db: &TransactionDB,
+    prefix: &[u8],
+) -> Result<usize> {
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = prefix_end(prefix) {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    let mut count = 0;
+    for item in txn.iterator_cf_opt(
+        &DBColumnFamilies::User.cf_db(db),
+        read_opts,
+        IteratorMode::From(prefix, Direction::Forward),
+    ) {
+        let (key, _) = item?;
+        txn.get_for_update_cf(&DBColumnFamilies::User.cf_db(db), &key, false)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+async fn check_histories<DB: Engine>(db: Arc<DB>) -> Result<()> {
+    for snapshot in [false, true] {
+        let config = WorkloadConfig {
+            snapshot,
+            ..WorkloadConfig::default()
+        };
+
+        let history = Arc::new(History::default());
+        let (value, committed) = counter_workload(db.clone(), history.clone(), config).await?;
+        let report = check(&history.records());
+        tracing::info!(
+            "{} snapshot={snapshot} counter: {value} after {committed} increments, {report}",
+            DB::NAME
+        );
+        for anomaly in report.anomalies.iter().take(3) {
+            tracing::debug!("{anomaly}");
+        }
+
+        let history = Arc::new(History::default());
+        let nobody_on_call = write_skew_workload(db.clone(), history.clone(), config).await?;
+        let report = check(&history.records());
+        tracing::info!(
+            "{} snapshot={snapshot} on-call: {nobody_on_call} rounds without doctor, {report}",
+            DB::NAME
+        );
+        for anomaly in report.anomalies.iter().take(3) {
+            tracing::debug!("{anomaly}");
+        }
+    }
+
     Ok(())
 }
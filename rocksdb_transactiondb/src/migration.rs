@@ -0,0 +1,549 @@
+//! On-disk schema evolution, tracked by a version stored in the Meta column family.
+//!
+//! A [`Migrator`] holds the ordered list of [`Migration`]s known to the binary, migration `n`
+//! bringing the schema from version `n - 1` to `n`. Opening a database through
+//! [`Migrator::open`] applies the pending migrations, and refuses databases with a schema
+//! newer than the binary knows before changing anything, column families included.
+//!
+//! Column families cannot be created or dropped in a transaction, so a migration runs in
+//! three phases:
+//! 1. its [`Step::AddColumnFamily`] steps, skipping column families that already exist;
+//! 2. its key and value rewrites, and the version bump, in a single transaction;
+//! 3. its [`Step::DropColumnFamily`] steps, skipping column families already gone.
+//!
+//! A crash during phase 1 or 2 leaves the version unchanged and the migration is run again on
+//! the next open. A crash during phase 3 leaves column families behind that nothing reads.
+use std::{collections::BTreeSet, fmt, path::Path};
+
+use anyhow::{bail, Context, Result};
+use rocksdb::{
+    ColumnFamilyDescriptor, IteratorMode, Options, TransactionDB, TransactionDBOptions, DB,
+};
+use strum::IntoEnumIterator;
+
+use crate::DBColumnFamilies;
+
+/// Key of the schema version in the Meta column family, the version is a big-endian `u64`.
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Returns the new key of a record, `None` to keep it.
+pub type RewriteKey = fn(&[u8]) -> Option<Vec<u8>>;
+/// Returns the new value of a record, `None` to keep it.
+pub type ReencodeValue = fn(&[u8]) -> Result<Option<Vec<u8>>>;
+
+pub enum Step {
+    AddColumnFamily(&'static str),
+    DropColumnFamily(&'static str),
+    /// Moves every record of the column family for which `rewrite` returns a new key.
+    RewriteKeys {
+        cf: &'static str,
+        rewrite: RewriteKey,
+    },
+    /// Replaces every value of the column family for which `reencode` returns a new value.
+    ReencodeValues {
+        cf: &'static str,
+        reencode: ReencodeValue,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::AddColumnFamily(cf) => write!(f, "add column family {cf}"),
+            Step::DropColumnFamily(cf) => write!(f, "drop column family {cf}"),
+            Step::RewriteKeys { cf, .. } => write!(f, "rewrite keys of {cf}"),
+            Step::ReencodeValues { cf, .. } => write!(f, "re-encode values of {cf}"),
+        }
+    }
+}
+
+pub struct Migration {
+    pub version: u64,
+    pub description: &'static str,
+    pub steps: Vec<Step>,
+}
+
+/// Migrations of this binary.
+pub fn migrations() -> Vec<Migration> {
+    vec![Migration {
+        version: 1,
+        description: "record the schema version",
+        steps: vec![],
+    }]
+}
+
+/// What a step did, or would do for a dry run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepReport {
+    pub version: u64,
+    pub step: String,
+    /// Records rewritten or re-encoded, column families added or dropped.
+    pub affected: usize,
+}
+
+impl fmt::Display for StepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "v{}: {} ({} affected)",
+            self.version, self.step, self.affected
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    /// Version of the database, 0 when no migration ever ran.
+    pub current: u64,
+    /// Latest version known to the binary.
+    pub target: u64,
+    /// Pending migrations, with their description.
+    pub pending: Vec<(u64, &'static str)>,
+}
+
+impl MigrationStatus {
+    pub fn is_up_to_date(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "schema version {} of {}", self.current, self.target)?;
+        if self.is_up_to_date() {
+            return write!(f, ", up to date");
+        }
+        write!(f, ", pending:")?;
+        for (version, description) in &self.pending {
+            write!(f, " v{version} ({description})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Schema version recorded in `db`, 0 when there is none.
+pub fn schema_version(db: &TransactionDB) -> Result<u64> {
+    decode_version(db.get_cf(&DBColumnFamilies::Meta.cf_db(db), SCHEMA_VERSION_KEY)?)
+}
+
+/// Schema version recorded in the database at `path` with the column families `existing`,
+/// read from a read-only open so that nothing is created.
+fn stored_schema_version(path: &Path, existing: &[String]) -> Result<u64> {
+    let meta = DBColumnFamilies::Meta.as_ref();
+    if !existing.iter().any(|name| name == meta) {
+        return Ok(0);
+    }
+    let db = DB::open_cf_for_read_only(&Options::default(), path, [meta], false)?;
+    let handle = db.cf_handle(meta).context("no Meta column family")?;
+    decode_version(db.get_cf(&handle, SCHEMA_VERSION_KEY)?)
+}
+
+fn decode_version(raw: Option<Vec<u8>>) -> Result<u64> {
+    match raw {
+        None => Ok(0),
+        Some(raw) => Ok(u64::from_be_bytes(
+            raw.as_slice()
+                .try_into()
+                .context("schema version is not a u64")?,
+        )),
+    }
+}
+
+pub struct Migrator {
+    migrations: Vec<Migration>,
+}
+
+impl Migrator {
+    /// Panics unless migration `n` has version `n`, starting at 1.
+    pub fn new(migrations: Vec<Migration>) -> Self {
+        for (index, migration) in migrations.iter().enumerate() {
+            assert_eq!(
+                migration.version,
+                index as u64 + 1,
+                "migrations must be ordered and numbered from 1"
+            );
+        }
+        Self { migrations }
+    }
+
+    pub fn target_version(&self) -> u64 {
+        self.migrations.len() as u64
+    }
+
+    /// Opens the `TransactionDB` at `path` with every column family it has and every
+    /// `DBColumnFamilies`, then applies the pending migrations. A schema newer than the binary
+    /// knows is refused before the missing column families are created.
+    pub fn open(&self, db_opts: &Options, path: impl AsRef<Path>) -> Result<TransactionDB> {
+        let path = path.as_ref();
+        // Listing fails when there is no database yet.
+        let existing = TransactionDB::list_cf(db_opts, path).unwrap_or_default();
+        self.check_known(stored_schema_version(path, &existing)?)?;
+        let names: BTreeSet<String> = DBColumnFamilies::iter()
+            .map(|cf| cf.as_ref().to_string())
+            .chain(existing)
+            .collect();
+        let db = TransactionDB::open_cf_descriptors(
+            db_opts,
+            &TransactionDBOptions::default(),
+            path,
+            names
+                .into_iter()
+                .map(|name| ColumnFamilyDescriptor::new(name, Options::default())),
+        )?;
+
+        for report in self.migrate(&db)? {
+            tracing::info!("migrated {report}");
+        }
+        Ok(db)
+    }
+
+    pub fn status(&self, db: &TransactionDB) -> Result<MigrationStatus> {
+        let current = schema_version(db)?;
+        self.check_known(current)?;
+        Ok(MigrationStatus {
+            current,
+            target: self.target_version(),
+            pending: self
+                .pending(current)
+                .map(|migration| (migration.version, migration.description))
+                .collect(),
+        })
+    }
+
+    /// Reports what the pending migrations would do, without changing anything.
+    ///
+    /// Rewrites of a column family added by the same migration report nothing, the column
+    /// family does not exist yet.
+    pub fn dry_run(&self, db: &TransactionDB) -> Result<Vec<StepReport>> {
+        let current = self.status(db)?.current;
+        let mut reports = Vec::new();
+        for migration in self.pending(current) {
+            for step in &migration.steps {
+                let affected = match step {
+                    Step::AddColumnFamily(cf) => usize::from(db.cf_handle(cf).is_none()),
+                    Step::DropColumnFamily(cf) => usize::from(db.cf_handle(cf).is_some()),
+                    Step::RewriteKeys { cf, rewrite } => {
+                        count_records(db, cf, |key, _| Ok(rewrite(key).is_some()))?
+                    }
+                    Step::ReencodeValues { cf, reencode } => {
+                        count_records(db, cf, |_, value| Ok(reencode(value)?.is_some()))?
+                    }
+                };
+                reports.push(StepReport {
+                    version: migration.version,
+                    step: step.to_string(),
+                    affected,
+                });
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Applies the pending migrations in order, stopping at the first failure.
+    pub fn migrate(&self, db: &TransactionDB) -> Result<Vec<StepReport>> {
+        let current = self.status(db)?.current;
+        let mut reports = Vec::new();
+        for migration in self.pending(current) {
+            reports.extend(
+                apply(db, migration).with_context(|| {
+                    format!("migration to version {} failed", migration.version)
+                })?,
+            );
+        }
+        Ok(reports)
+    }
+
+    fn check_known(&self, current: u64) -> Result<()> {
+        if current > self.target_version() {
+            bail!(
+                "database schema version {current} is newer than version {} known to this binary",
+                self.target_version()
+            );
+        }
+        Ok(())
+    }
+
+    fn pending(&self, current: u64) -> impl Iterator<Item = &Migration> {
+        self.migrations
+            .iter()
+            .filter(move |migration| migration.version > current)
+    }
+}
+
+/// Counts the records of `cf` matching `predicate`, 0 when `cf` does not exist.
+fn count_records(
+    db: &TransactionDB,
+    cf: &str,
+    predicate: impl Fn(&[u8], &[u8]) -> Result<bool>,
+) -> Result<usize> {
+    let Some(handle) = db.cf_handle(cf) else {
+        return Ok(0);
+    };
+    let mut count = 0;
+    for item in db.iterator_cf(&handle, IteratorMode::Start) {
+        let (key, value) = item?;
+        if predicate(&key, &value)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn apply(db: &TransactionDB, migration: &Migration) -> Result<Vec<StepReport>> {
+    let mut affected = vec![0; migration.steps.len()];
+
+    for (step, affected) in migration.steps.iter().zip(&mut affected) {
+        if let Step::AddColumnFamily(cf) = step {
+            if db.cf_handle(cf).is_none() {
+                db.create_cf(cf, &Options::default())?;
+                *affected = 1;
+            }
+        }
+    }
+
+    let txn = db.transaction();
+    for (step, affected) in migration.steps.iter().zip(&mut affected) {
+        match step {
+            Step::RewriteKeys { cf, rewrite } => {
+                let handle = db
+                    .cf_handle(cf)
+                    .with_context(|| format!("unknown column family {cf}"))?;
+                // Writing to the transaction while iterating over it is not safe, collect first.
+                let records = txn
+                    .iterator_cf(&handle, IteratorMode::Start)
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (key, value) in records {
+                    if let Some(new_key) = rewrite(&key) {
+                        txn.delete_cf(&handle, &key)?;
+                        txn.put_cf(&handle, new_key, value)?;
+                        *affected += 1;
+                    }
+                }
+            }
+            Step::ReencodeValues { cf, reencode } => {
+                let handle = db
+                    .cf_handle(cf)
+                    .with_context(|| format!("unknown column family {cf}"))?;
+                let records = txn
+                    .iterator_cf(&handle, IteratorMode::Start)
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (key, value) in records {
+                    if let Some(new_value) = reencode(&value)? {
+                        txn.put_cf(&handle, key, new_value)?;
+                        *affected += 1;
+                    }
+                }
+            }
+            Step::AddColumnFamily(_) | Step::DropColumnFamily(_) => {}
+        }
+    }
+    txn.put_cf(
+        &DBColumnFamilies::Meta.cf_db(db),
+        SCHEMA_VERSION_KEY,
+        migration.version.to_be_bytes(),
+    )?;
+    txn.commit()?;
+
+    for (step, affected) in migration.steps.iter().zip(&mut affected) {
+        if let Step::DropColumnFamily(cf) = step {
+            if db.cf_handle(cf).is_some() {
+                db.drop_cf(cf)?;
+                *affected = 1;
+            }
+        }
+    }
+
+    Ok(migration
+        .steps
+        .iter()
+        .zip(affected)
+        .map(|(step, affected)| StepReport {
+            version: migration.version,
+            step: step.to_string(),
+            affected,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn db_opts() -> Options {
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        db_opts
+    }
+
+    /// `user1` becomes `user/1`.
+    fn nest_user_keys(key: &[u8]) -> Option<Vec<u8>> {
+        let id = key.strip_prefix(b"user")?;
+        (!id.starts_with(b"/")).then(|| [b"user/", id].concat())
+    }
+
+    fn uppercase_values(value: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(Some(value.to_ascii_uppercase()))
+    }
+
+    fn reject_values(_value: &[u8]) -> Result<Option<Vec<u8>>> {
+        Err(anyhow!("cannot decode value"))
+    }
+
+    fn v2(reencode: ReencodeValue) -> Vec<Migration> {
+        let mut migrations = migrations();
+        migrations.push(Migration {
+            version: 2,
+            description: "nest user keys",
+            steps: vec![
+                Step::AddColumnFamily("Archive"),
+                Step::RewriteKeys {
+                    cf: "User",
+                    rewrite: nest_user_keys,
+                },
+                Step::ReencodeValues {
+                    cf: "User",
+                    reencode,
+                },
+            ],
+        });
+        migrations
+    }
+
+    /// A database migrated to version 1, with two users.
+    fn v1_db() -> (TempDir, TransactionDB) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Migrator::new(migrations())
+            .open(&db_opts(), dir.path())
+            .unwrap();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        db.put_cf(&cf, b"user1", b"alice").unwrap();
+        db.put_cf(&cf, b"user2", b"bob").unwrap();
+        (dir, db)
+    }
+
+    fn users(db: &TransactionDB) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.iterator_cf(&DBColumnFamilies::User.cf_db(db), IteratorMode::Start)
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fresh_database_is_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let migrator = Migrator::new(migrations());
+        let db = migrator.open(&db_opts(), dir.path()).unwrap();
+
+        let status = migrator.status(&db).unwrap();
+        assert!(status.is_up_to_date());
+        assert_eq!(status.to_string(), "schema version 1 of 1, up to date");
+        assert!(migrator.migrate(&db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_rewrites_keys_and_values() {
+        let (dir, db) = v1_db();
+        drop(db);
+
+        let migrator = Migrator::new(v2(uppercase_values));
+        let db = migrator.open(&db_opts(), dir.path()).unwrap();
+
+        assert_eq!(schema_version(&db).unwrap(), 2);
+        assert!(db.cf_handle("Archive").is_some());
+        assert_eq!(
+            users(&db),
+            vec![
+                (b"user/1".to_vec(), b"ALICE".to_vec()),
+                (b"user/2".to_vec(), b"BOB".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn added_column_families_are_reopened() {
+        let (dir, db) = v1_db();
+        Migrator::new(v2(uppercase_values)).migrate(&db).unwrap();
+        drop(db);
+
+        let mut migrations = v2(uppercase_values);
+        migrations.push(Migration {
+            version: 3,
+            description: "drop the archive",
+            steps: vec![Step::DropColumnFamily("Archive")],
+        });
+        let migrator = Migrator::new(migrations);
+        let db = migrator.open(&db_opts(), dir.path()).unwrap();
+        assert!(db.cf_handle("Archive").is_none());
+        assert_eq!(schema_version(&db).unwrap(), 3);
+    }
+
+    #[test]
+    fn dry_run_reports_without_changing_anything() {
+        let (_dir, db) = v1_db();
+        let migrator = Migrator::new(v2(uppercase_values));
+
+        let status = migrator.status(&db).unwrap();
+        assert_eq!(
+            status.to_string(),
+            "schema version 1 of 2, pending: v2 (nest user keys)"
+        );
+
+        let reports = migrator.dry_run(&db).unwrap();
+        let reports: Vec<_> = reports.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            reports,
+            vec![
+                "v2: add column family Archive (1 affected)",
+                "v2: rewrite keys of User (2 affected)",
+                "v2: re-encode values of User (2 affected)",
+            ]
+        );
+        assert_eq!(schema_version(&db).unwrap(), 1);
+        assert!(db.cf_handle("Archive").is_none());
+        assert_eq!(users(&db)[0], (b"user1".to_vec(), b"alice".to_vec()));
+    }
+
+    #[test]
+    fn failed_migration_leaves_data_and_version_unchanged() {
+        let (_dir, db) = v1_db();
+        let migrator = Migrator::new(v2(reject_values));
+
+        let err = migrator.migrate(&db).unwrap_err();
+        assert_eq!(err.to_string(), "migration to version 2 failed");
+        assert_eq!(schema_version(&db).unwrap(), 1);
+        assert_eq!(users(&db)[0], (b"user1".to_vec(), b"alice".to_vec()));
+
+        // Once fixed, the migration runs again from the start.
+        Migrator::new(v2(uppercase_values)).migrate(&db).unwrap();
+        assert_eq!(users(&db)[0], (b"user/1".to_vec(), b"ALICE".to_vec()));
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let (dir, db) = v1_db();
+        Migrator::new(v2(uppercase_values)).migrate(&db).unwrap();
+        // A column family the older binary would create.
+        db.drop_cf(DBColumnFamilies::Namespace.as_ref()).unwrap();
+        drop(db);
+        let column_families = TransactionDB::list_cf(&db_opts(), dir.path()).unwrap();
+
+        let err = Migrator::new(migrations())
+            .open(&db_opts(), dir.path())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "database schema version 2 is newer than version 1 known to this binary"
+        );
+        assert_eq!(
+            TransactionDB::list_cf(&db_opts(), dir.path()).unwrap(),
+            column_families
+        );
+        assert!(!column_families.contains(&DBColumnFamilies::Namespace.as_ref().to_string()));
+    }
+}
@@ -0,0 +1,408 @@
+//! Reference model of the pessimistic locking semantics of `TransactionDB`.
+//!
+//! An interleaving is a list of [`Op`]s issued by several transactions from a single thread.
+//! [`run`] replays it against a real `TransactionDB` with lock waits disabled (a lock timeout
+//! of 0), so an operation that would block reports [`Outcome::TimedOut`] right away instead of
+//! after the default one second. [`Model`] predicts the same outcomes from first principles:
+//!
+//! - `get_cf` takes no lock and sees the transaction's own writes, then the committed state.
+//! - `get_for_update_cf` takes a shared or exclusive lock on the key.
+//! - `put_cf` and `delete_cf` take an exclusive lock on the key.
+//! - a shared lock held only by the requesting transaction can be upgraded to exclusive.
+//! - commit and rollback release every lock held by the transaction.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use rocksdb::{
+    BoundColumnFamily, ErrorKind, Transaction, TransactionDB, TransactionOptions, WriteOptions,
+};
+
+use crate::DBColumnFamilies;
+
+/// Number of distinct keys used by generated interleavings.
+pub const KEYS: usize = 3;
+/// Number of concurrent transactions used by generated interleavings.
+pub const TXNS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Get {
+        txn: usize,
+        key: usize,
+    },
+    GetForUpdate {
+        txn: usize,
+        key: usize,
+        exclusive: bool,
+    },
+    Put {
+        txn: usize,
+        key: usize,
+    },
+    Delete {
+        txn: usize,
+        key: usize,
+    },
+    Commit {
+        txn: usize,
+    },
+    Rollback {
+        txn: usize,
+    },
+}
+
+impl Op {
+    pub fn txn(&self) -> usize {
+        match *self {
+            Op::Get { txn, .. }
+            | Op::GetForUpdate { txn, .. }
+            | Op::Put { txn, .. }
+            | Op::Delete { txn, .. }
+            | Op::Commit { txn }
+            | Op::Rollback { txn } => txn,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// A read returned this value.
+    Value(Option<Vec<u8>>),
+    /// A write, commit or rollback succeeded.
+    Done,
+    /// The operation needed a lock held by another transaction.
+    TimedOut,
+    /// The transaction was already committed or rolled back, the operation was not issued.
+    Skipped,
+}
+
+pub fn key(key: usize) -> Vec<u8> {
+    format!("user{key}").into_bytes()
+}
+
+/// Value written by the `Put` at position `step` of an interleaving, unique per step.
+pub fn value(step: usize, txn: usize, key: usize) -> Vec<u8> {
+    format!("user{key}-txn{txn}-step{step}").into_bytes()
+}
+
+/// State every interleaving starts from: each key holds its own name.
+pub fn initial_state() -> BTreeMap<Vec<u8>, Vec<u8>> {
+    (0..KEYS).map(|k| (key(k), key(k))).collect()
+}
+
+pub fn seed(db: &TransactionDB) -> Result<()> {
+    let txn = db.transaction();
+    for (k, v) in initial_state() {
+        txn.put_cf(&DBColumnFamilies::User.cf_db(db), k, v)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Reads every key of the `User` column family outside of any transaction.
+pub fn committed_state(db: &TransactionDB) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut state = BTreeMap::new();
+    for k in 0..KEYS {
+        if let Some(v) = db.get_cf(&DBColumnFamilies::User.cf_db(db), key(k))? {
+            state.insert(key(k), v);
+        }
+    }
+    Ok(state)
+}
+
+/// Options for transactions that fail immediately instead of waiting for a lock.
+pub fn no_wait_txn_opts() -> TransactionOptions {
+    let mut txn_opts = TransactionOptions::default();
+    txn_opts.set_lock_timeout(0);
+    txn_opts
+}
+
+enum Slot<'db> {
+    NotStarted,
+    Open(Transaction<'db, TransactionDB>),
+    Finished,
+}
+
+/// Replays `ops` against `db` and returns the outcome of every operation.
+///
+/// Transactions are started on their first operation. Transactions still open at the end are
+/// dropped without commit, which discards their writes and releases their locks.
+pub fn run(db: &TransactionDB, ops: &[Op]) -> Result<Vec<Outcome>> {
+    let mut runner = Runner::new(db);
+    ops.iter()
+        .enumerate()
+        .map(|(step, op)| runner.apply(step, *op))
+        .collect()
+}
+
+/// Applies operations one at a time against a `TransactionDB`, a transaction per `txn`.
+///
+/// Dropping the runner drops the transactions still open.
+pub struct Runner<'db> {
+    db: &'db TransactionDB,
+    cf: Arc<BoundColumnFamily<'db>>,
+    write_opts: WriteOptions,
+    txn_opts: TransactionOptions,
+    slots: Vec<Slot<'db>>,
+}
+
+impl<'db> Runner<'db> {
+    pub fn new(db: &'db TransactionDB) -> Self {
+        Self {
+            db,
+            cf: DBColumnFamilies::User.cf_db(db),
+            write_opts: WriteOptions::default(),
+            txn_opts: no_wait_txn_opts(),
+            slots: Vec::new(),
+        }
+    }
+
+    /// Applies the operation at position `step` of an interleaving. Transactions are started
+    /// on their first operation.
+    pub fn apply(&mut self, step: usize, op: Op) -> Result<Outcome> {
+        if self.slots.len() <= op.txn() {
+            self.slots.resize_with(op.txn() + 1, || Slot::NotStarted);
+        }
+        let slot = &mut self.slots[op.txn()];
+        match slot {
+            Slot::Finished => return Ok(Outcome::Skipped),
+            Slot::NotStarted => {
+                *slot = Slot::Open(self.db.transaction_opt(&self.write_opts, &self.txn_opts));
+            }
+            Slot::Open(_) => (),
+        }
+
+        let cf = &self.cf;
+        let res = match op {
+            Op::Commit { .. } | Op::Rollback { .. } => {
+                let Slot::Open(txn) = std::mem::replace(slot, Slot::Finished) else {
+                    unreachable!("transaction slot is open")
+                };
+                if matches!(op, Op::Commit { .. }) {
+                    txn.commit()
+                } else {
+                    txn.rollback()
+                }
+                .map(|()| Outcome::Done)
+            }
+            _ => {
+                let Slot::Open(txn) = slot else {
+                    unreachable!("transaction slot is open")
+                };
+                match op {
+                    Op::Get { key: k, .. } => txn.get_cf(cf, key(k)).map(Outcome::Value),
+                    Op::GetForUpdate {
+                        key: k, exclusive, ..
+                    } => txn
+                        .get_for_update_cf(cf, key(k), exclusive)
+                        .map(Outcome::Value),
+                    Op::Put { txn: t, key: k } => txn
+                        .put_cf(cf, key(k), value(step, t, k))
+                        .map(|()| Outcome::Done),
+                    Op::Delete { key: k, .. } => txn.delete_cf(cf, key(k)).map(|()| Outcome::Done),
+                    Op::Commit { .. } | Op::Rollback { .. } => unreachable!(),
+                }
+            }
+        };
+
+        match res {
+            Result::Ok(outcome) => Ok(outcome),
+            Err(err) if err.kind() == ErrorKind::TimedOut => Ok(Outcome::TimedOut),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct KeyLock {
+    exclusive: bool,
+    holders: BTreeSet<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+enum TxnState {
+    #[default]
+    Open,
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    committed: BTreeMap<Vec<u8>, Vec<u8>>,
+    states: BTreeMap<usize, TxnState>,
+    writes: BTreeMap<usize, BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+    locks: BTreeMap<usize, KeyLock>,
+}
+
+impl Model {
+    pub fn new(committed: BTreeMap<Vec<u8>, Vec<u8>>) -> Self {
+        Self {
+            committed,
+            states: BTreeMap::new(),
+            writes: BTreeMap::new(),
+            locks: BTreeMap::new(),
+        }
+    }
+
+    pub fn committed(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.committed
+    }
+
+    /// Predicts the outcome of the operation at position `step` of an interleaving.
+    pub fn apply(&mut self, step: usize, op: Op) -> Outcome {
+        let txn = op.txn();
+        if matches!(self.states.entry(txn).or_default(), TxnState::Finished) {
+            return Outcome::Skipped;
+        }
+
+        match op {
+            Op::Get { key: k, .. } => Outcome::Value(self.read(txn, k)),
+            Op::GetForUpdate {
+                key: k, exclusive, ..
+            } => {
+                if self.lock(txn, k, exclusive) {
+                    Outcome::Value(self.read(txn, k))
+                } else {
+                    Outcome::TimedOut
+                }
+            }
+            Op::Put { key: k, .. } => self.write(txn, k, Some(value(step, txn, k))),
+            Op::Delete { key: k, .. } => self.write(txn, k, None),
+            Op::Commit { .. } => {
+                for (k, v) in self.writes.remove(&txn).unwrap_or_default() {
+                    match v {
+                        Some(v) => self.committed.insert(k, v),
+                        None => self.committed.remove(&k),
+                    };
+                }
+                self.finish(txn);
+                Outcome::Done
+            }
+            Op::Rollback { .. } => {
+                self.writes.remove(&txn);
+                self.finish(txn);
+                Outcome::Done
+            }
+        }
+    }
+
+    fn read(&self, txn: usize, k: usize) -> Option<Vec<u8>> {
+        match self.writes.get(&txn).and_then(|w| w.get(&key(k))) {
+            Some(own) => own.clone(),
+            None => self.committed.get(&key(k)).cloned(),
+        }
+    }
+
+    fn write(&mut self, txn: usize, k: usize, v: Option<Vec<u8>>) -> Outcome {
+        if !self.lock(txn, k, true) {
+            return Outcome::TimedOut;
+        }
+        self.writes.entry(txn).or_default().insert(key(k), v);
+        Outcome::Done
+    }
+
+    /// Tries to take the lock on key `k` for `txn`, returns false when it would block.
+    fn lock(&mut self, txn: usize, k: usize, exclusive: bool) -> bool {
+        let lock = self.locks.entry(k).or_default();
+        if lock.holders.contains(&txn) {
+            // Already held in a sufficient mode, or the only shared holder upgrading.
+            if !exclusive || lock.exclusive || lock.holders.len() == 1 {
+                lock.exclusive |= exclusive;
+                return true;
+            }
+            return false;
+        }
+        if !lock.holders.is_empty() && (lock.exclusive || exclusive) {
+            return false;
+        }
+        lock.exclusive = exclusive;
+        lock.holders.insert(txn);
+        true
+    }
+
+    fn finish(&mut self, txn: usize) {
+        self.states.insert(txn, TxnState::Finished);
+        for lock in self.locks.values_mut() {
+            lock.holders.remove(&txn);
+        }
+        self.locks.retain(|_, lock| !lock.holders.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::test_utils::temp_transaction_db;
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0..TXNS, 0..KEYS).prop_map(|(txn, key)| Op::Get { txn, key }),
+            3 => (0..TXNS, 0..KEYS, any::<bool>())
+                .prop_map(|(txn, key, exclusive)| Op::GetForUpdate { txn, key, exclusive }),
+            4 => (0..TXNS, 0..KEYS).prop_map(|(txn, key)| Op::Put { txn, key }),
+            2 => (0..TXNS, 0..KEYS).prop_map(|(txn, key)| Op::Delete { txn, key }),
+            1 => (0..TXNS).prop_map(|txn| Op::Commit { txn }),
+            1 => (0..TXNS).prop_map(|txn| Op::Rollback { txn }),
+        ]
+    }
+
+    fn expected(ops: &[Op]) -> (Vec<Outcome>, BTreeMap<Vec<u8>, Vec<u8>>) {
+        let mut model = Model::new(initial_state());
+        let outcomes = ops
+            .iter()
+            .enumerate()
+            .map(|(step, op)| model.apply(step, *op))
+            .collect();
+        (outcomes, model.committed().clone())
+    }
+
+    proptest! {
+        #[test]
+        fn transaction_db_matches_model(ops in prop::collection::vec(op_strategy(), 1..40)) {
+            let (_dir, db) = temp_transaction_db();
+            seed(&db).unwrap();
+
+            let outcomes = run(&db, &ops).unwrap();
+            let (expected_outcomes, expected_state) = expected(&ops);
+
+            prop_assert_eq!(outcomes, expected_outcomes);
+            prop_assert_eq!(committed_state(&db).unwrap(), expected_state);
+        }
+    }
+
+    #[test]
+    fn shared_lock_upgrade_times_out_with_other_holder() {
+        let ops = [
+            Op::GetForUpdate {
+                txn: 0,
+                key: 0,
+                exclusive: false,
+            },
+            Op::GetForUpdate {
+                txn: 1,
+                key: 0,
+                exclusive: false,
+            },
+            Op::Put { txn: 0, key: 0 },
+            Op::Rollback { txn: 1 },
+            Op::Put { txn: 0, key: 0 },
+            Op::Commit { txn: 0 },
+        ];
+        let (_dir, db) = temp_transaction_db();
+        seed(&db).unwrap();
+
+        let outcomes = run(&db, &ops).unwrap();
+        assert_eq!(outcomes[2], Outcome::TimedOut);
+        assert_eq!(outcomes[4], Outcome::Done);
+        assert_eq!(outcomes, expected(&ops).0);
+        assert_eq!(
+            committed_state(&db).unwrap().get(&key(0)),
+            Some(&value(4, 0, 0))
+        );
+    }
+}
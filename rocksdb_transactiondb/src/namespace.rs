@@ -0,0 +1,211 @@
+//! Namespaces, the unit of isolation of the key-value service.
+//!
+//! [`NamespaceRegistry`] keeps one JSON record per namespace in
+//! [`DBColumnFamilies::Namespace`], keyed by the namespace id. The id is what clients put in
+//! URLs, so it is restricted to ASCII letters, digits, `-` and `_`; the name is free text.
+//! Records are created in a transaction that locks the id first, so two concurrent creations
+//! of the same namespace can't both succeed.
+use std::{collections::BTreeMap, fmt, path::Path, sync::Arc};
+
+use anyhow::{bail, Result};
+use rocksdb::{ColumnFamilyDescriptor, IteratorMode, Options, TransactionDB, TransactionDBOptions};
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+
+use crate::{
+    ttl::{Clock, SystemClock},
+    DBColumnFamilies,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub id: String,
+    pub name: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Returned, through `anyhow`, when creating a namespace that exists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlreadyExists {
+    pub id: String,
+}
+
+impl fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace {} already exists", self.id)
+    }
+}
+
+impl std::error::Error for AlreadyExists {}
+
+pub fn validate_id(id: &str) -> Result<()> {
+    if id.is_empty()
+        || !id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        bail!("invalid namespace id {id:?}");
+    }
+    Ok(())
+}
+
+/// Opens or creates the `TransactionDB` at `path` with every `DBColumnFamilies`.
+pub fn open_db(path: impl AsRef<Path>) -> Result<TransactionDB> {
+    let mut db_opts = Options::default();
+    db_opts.create_missing_column_families(true);
+    db_opts.create_if_missing(true);
+    Ok(TransactionDB::open_cf_descriptors(
+        &db_opts,
+        &TransactionDBOptions::default(),
+        path,
+        DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default())),
+    )?)
+}
+
+pub struct NamespaceRegistry {
+    db: Arc<TransactionDB>,
+    clock: Arc<dyn Clock>,
+}
+
+impl NamespaceRegistry {
+    pub fn new(db: Arc<TransactionDB>) -> Self {
+        Self::with_clock(db, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(db: Arc<TransactionDB>, clock: Arc<dyn Clock>) -> Self {
+        Self { db, clock }
+    }
+
+    pub fn db(&self) -> &Arc<TransactionDB> {
+        &self.db
+    }
+
+    /// Records a new namespace, fails with [`AlreadyExists`] when `id` is taken.
+    pub fn create(
+        &self,
+        id: &str,
+        name: &str,
+        labels: BTreeMap<String, String>,
+    ) -> Result<Namespace> {
+        validate_id(id)?;
+        let cf = DBColumnFamilies::Namespace.cf_db(&self.db);
+        let txn = self.db.transaction();
+        if txn.get_for_update_cf(&cf, id, true)?.is_some() {
+            return Err(AlreadyExists { id: id.to_owned() }.into());
+        }
+
+        let namespace = Namespace {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            created_at: self.clock.now_millis(),
+            labels,
+        };
+        txn.put_cf(&cf, id, serde_json::to_vec(&namespace)?)?;
+        txn.commit()?;
+        Ok(namespace)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Namespace>> {
+        self.db
+            .get_cf(&DBColumnFamilies::Namespace.cf_db(&self.db), id)?
+            .map(|record| -> Result<Namespace> { Ok(serde_json::from_slice(&record)?) })
+            .transpose()
+    }
+
+    /// Every namespace, in id order.
+    pub fn list(&self) -> Result<Vec<Namespace>> {
+        self.db
+            .iterator_cf(
+                &DBColumnFamilies::Namespace.cf_db(&self.db),
+                IteratorMode::Start,
+            )
+            .map(|item| -> Result<Namespace> {
+                let (_, record) = item?;
+                Ok(serde_json::from_slice(&record)?)
+            })
+            .collect()
+    }
+
+    /// Removes the record of `id`, returns whether it existed.
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        let cf = DBColumnFamilies::Namespace.cf_db(&self.db);
+        let txn = self.db.transaction();
+        if txn.get_for_update_cf(&cf, id, true)?.is_none() {
+            return Ok(false);
+        }
+        txn.delete_cf(&cf, id)?;
+        txn.commit()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::temp_transaction_db, ttl::ManualClock};
+
+    fn registry() -> (tempfile::TempDir, NamespaceRegistry) {
+        let (dir, db) = temp_transaction_db();
+        let registry =
+            NamespaceRegistry::with_clock(Arc::new(db), Arc::new(ManualClock::new(1_000)));
+        (dir, registry)
+    }
+
+    #[test]
+    fn creates_gets_lists_and_deletes() {
+        let (_dir, registry) = registry();
+        let labels = BTreeMap::from([("team".to_owned(), "storage".to_owned())]);
+
+        let orders = registry.create("orders", "Orders", labels.clone()).unwrap();
+        assert_eq!(
+            orders,
+            Namespace {
+                id: "orders".into(),
+                name: "Orders".into(),
+                created_at: 1_000,
+                labels,
+            }
+        );
+        registry.create("carts", "Carts", BTreeMap::new()).unwrap();
+
+        assert_eq!(registry.get("orders").unwrap(), Some(orders));
+        assert_eq!(registry.get("users").unwrap(), None);
+        let ids: Vec<_> = registry
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|namespace| namespace.id)
+            .collect();
+        assert_eq!(ids, vec!["carts", "orders"]);
+
+        assert!(registry.delete("orders").unwrap());
+        assert!(!registry.delete("orders").unwrap());
+        assert_eq!(registry.get("orders").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_duplicates_and_invalid_ids() {
+        let (_dir, registry) = registry();
+
+        registry
+            .create("orders", "Orders", BTreeMap::new())
+            .unwrap();
+        let error = registry
+            .create("orders", "Other", BTreeMap::new())
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AlreadyExists>(),
+            Some(&AlreadyExists {
+                id: "orders".into()
+            })
+        );
+        assert_eq!(registry.get("orders").unwrap().unwrap().name, "Orders");
+
+        assert!(registry.create("", "Empty", BTreeMap::new()).is_err());
+        assert!(registry.create("a/b", "Slash", BTreeMap::new()).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Snapshots kept across calls, for example to page through a listing over several requests.
+//!
+//! A `SnapshotWithThreadMode` borrows its database, so it can't be stored next to the
+//! `Arc<TransactionDB>` it comes from. Every pinned snapshot lives instead on a thread of its
+//! own, which owns a clone of the `Arc`, takes the snapshot and runs the reads sent to it
+//! until the snapshot is released or its time is up. Pinning holds back compaction of
+//! everything overwritten since, so pins are bounded both in number and in time.
+//!
+//! A [`PinId`] carries a secret next to its sequence number, so that the id of a pin handed
+//! to a client can't be forged from another one to read at or release it.
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rocksdb::{SnapshotWithThreadMode, TransactionDB};
+
+pub type Snapshot<'db> = SnapshotWithThreadMode<'db, TransactionDB>;
+
+type Read = Box<dyn FnOnce(&TransactionDB, &Snapshot<'_>) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PinId {
+    pub seq: u64,
+    /// Keyed hash of `seq`, only known to whoever got the id from [`SnapshotPins::pin`].
+    pub secret: u64,
+}
+
+impl fmt::Display for PinId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.seq)
+    }
+}
+
+/// Returned, through `anyhow`, when reading at a snapshot released or past its time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotExpired {
+    pub id: PinId,
+}
+
+impl fmt::Display for SnapshotExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "snapshot {} expired", self.id)
+    }
+}
+
+impl std::error::Error for SnapshotExpired {}
+
+/// Returned, through `anyhow`, when pinning while `max_pinned` snapshots are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooManySnapshots {
+    pub max_pinned: usize,
+}
+
+impl fmt::Display for TooManySnapshots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} snapshots are already pinned", self.max_pinned)
+    }
+}
+
+impl std::error::Error for TooManySnapshots {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PinConfig {
+    /// How long a snapshot stays pinned after it's taken, whether it's used or not.
+    pub ttl: Duration,
+    /// Snapshots pinned at once, one thread each.
+    pub max_pinned: usize,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            max_pinned: 64,
+        }
+    }
+}
+
+type Pins = Arc<Mutex<HashMap<PinId, mpsc::Sender<Read>>>>;
+
+pub struct SnapshotPins {
+    db: Arc<TransactionDB>,
+    config: PinConfig,
+    next_id: AtomicU64,
+    /// Random keys the secrets of the ids are hashed with.
+    secrets: RandomState,
+    pins: Pins,
+}
+
+impl SnapshotPins {
+    pub fn new(db: Arc<TransactionDB>, config: PinConfig) -> Self {
+        Self {
+            db,
+            config,
+            next_id: AtomicU64::new(0),
+            secrets: RandomState::new(),
+            pins: Arc::default(),
+        }
+    }
+
+    /// Takes a snapshot, fails with [`TooManySnapshots`] when `max_pinned` are.
+    pub fn pin(&self) -> Result<PinId> {
+        let seq = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let id = PinId {
+            seq,
+            secret: self.secrets.hash_one(seq),
+        };
+        let (reads, received) = mpsc::channel::<Read>();
+        {
+            // Reserves the slot, the lock is not held while the snapshot is taken: the thread
+            // takes it to unpin, even when taking the snapshot panics.
+            let mut pins = self.pins.lock().unwrap();
+            if pins.len() >= self.config.max_pinned {
+                return Err(TooManySnapshots {
+                    max_pinned: self.config.max_pinned,
+                }
+                .into());
+            }
+            pins.insert(id, reads);
+        }
+
+        let db = self.db.clone();
+        let deadline = Instant::now() + self.config.ttl;
+        let guard = PinGuard {
+            pins: self.pins.clone(),
+            id,
+        };
+        let (taken, on_taken) = mpsc::sync_channel(1);
+        let spawned = thread::Builder::new()
+            .name(format!("snapshot-pin-{}", id.seq))
+            .spawn(move || {
+                let _guard = guard;
+                let snapshot = db.snapshot();
+                let _ = taken.send(());
+                // Ends when released, since the sender is dropped, or at the deadline.
+                while let Ok(read) =
+                    received.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    read(&db, &snapshot);
+                }
+                tracing::debug!("unpinned snapshot {id}");
+            });
+        if let Err(error) = spawned {
+            self.release(id);
+            return Err(error.into());
+        }
+        // Writes made once pinned must not be seen. Fails when the thread panicked, its guard
+        // freed the slot.
+        on_taken.recv()?;
+        Ok(id)
+    }
+
+    /// Runs `read` at the snapshot `id` on its thread, fails with [`SnapshotExpired`] when it
+    /// was released, its time is up or the secret of `id` is not its own.
+    pub fn with<R: Send + 'static>(
+        &self,
+        id: PinId,
+        read: impl FnOnce(&TransactionDB, &Snapshot<'_>) -> R + Send + 'static,
+    ) -> Result<R> {
+        let reads = self
+            .pins
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(SnapshotExpired { id })?;
+        let (reply, replied) = mpsc::sync_channel(1);
+        reads
+            .send(Box::new(move |db, snapshot| {
+                // The caller is gone when it was cancelled.
+                let _ = reply.send(read(db, snapshot));
+            }))
+            .map_err(|_| SnapshotExpired { id })?;
+        drop(reads);
+        // The thread dropped the read when it reached its deadline meanwhile.
+        Ok(replied.recv().map_err(|_| SnapshotExpired { id })?)
+    }
+
+    /// Releases the snapshot `id` once the reads sent to it are done, does nothing when the
+    /// secret of `id` is not its own.
+    pub fn release(&self, id: PinId) {
+        self.pins.lock().unwrap().remove(&id);
+    }
+
+    /// Snapshots currently pinned.
+    pub fn pinned(&self) -> usize {
+        self.pins.lock().unwrap().len()
+    }
+}
+
+/// Forgets the pin when its thread ends, even by a panic of one of its reads.
+struct PinGuard {
+    pins: Pins,
+    id: PinId,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.pins.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::temp_transaction_db, DBColumnFamilies};
+
+    fn pins(config: PinConfig) -> (tempfile::TempDir, SnapshotPins) {
+        let (dir, db) = temp_transaction_db();
+        (dir, SnapshotPins::new(Arc::new(db), config))
+    }
+
+    fn get_at(pins: &SnapshotPins, id: PinId, key: &'static [u8]) -> Result<Option<Vec<u8>>> {
+        pins.with(id, move |db, snapshot| {
+            snapshot.get_cf(&DBColumnFamilies::User.cf_db(db), key)
+        })?
+        .map_err(Into::into)
+    }
+
+    #[test]
+    fn reads_see_the_pinned_state_until_released() {
+        let (_dir, pins) = pins(PinConfig::default());
+        let cf = DBColumnFamilies::User.cf_db(&pins.db);
+        pins.db.put_cf(&cf, b"k", b"v1").unwrap();
+
+        let id = pins.pin().unwrap();
+        pins.db.put_cf(&cf, b"k", b"v2").unwrap();
+        assert_eq!(get_at(&pins, id, b"k").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(get_at(&pins, id, b"k").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(pins.pinned(), 1);
+
+        pins.release(id);
+        let error = get_at(&pins, id, b"k").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SnapshotExpired>(),
+            Some(&SnapshotExpired { id })
+        );
+        assert_eq!(pins.pinned(), 0);
+    }
+
+    #[test]
+    fn ids_with_another_secret_are_refused() {
+        let (_dir, pins) = pins(PinConfig::default());
+
+        let id = pins.pin().unwrap();
+        let other = pins.pin().unwrap();
+        assert_ne!(id.secret, other.secret);
+        let forged = PinId {
+            seq: id.seq,
+            secret: other.secret,
+        };
+        let error = get_at(&pins, forged, b"k").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SnapshotExpired>(),
+            Some(&SnapshotExpired { id: forged })
+        );
+
+        pins.release(forged);
+        assert_eq!(pins.pinned(), 2);
+        assert_eq!(get_at(&pins, id, b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn pins_are_bounded_in_time_and_number() {
+        let (_dir, pins) = pins(PinConfig {
+            ttl: Duration::from_millis(50),
+            max_pinned: 2,
+        });
+
+        let first = pins.pin().unwrap();
+        pins.pin().unwrap();
+        let error = pins.pin().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TooManySnapshots>(),
+            Some(&TooManySnapshots { max_pinned: 2 })
+        );
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(get_at(&pins, first, b"k")
+            .unwrap_err()
+            .is::<SnapshotExpired>());
+        assert_eq!(pins.pinned(), 0);
+        pins.pin().unwrap();
+    }
+
+    #[test]
+    fn concurrent_pins_stay_bounded() {
+        let (_dir, pins) = pins(PinConfig {
+            max_pinned: 3,
+            ..PinConfig::default()
+        });
+
+        let pinned = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| pins.pin())).collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+        assert_eq!(pinned, 3);
+        assert_eq!(pins.pinned(), 3);
+    }
+}
@@ -0,0 +1,228 @@
+//! Deleting every key under a prefix, to purge a tenant stored under a key prefix.
+//!
+//! [`delete_prefix_range`] writes a single range tombstone. The binding only exposes
+//! `delete_range_cf` on `OptimisticTransactionDB` (and plain `DB`s), not on `TransactionDB`
+//! nor inside a transaction. The tombstone is written outside of any transaction:
+//! - it takes no key lock and is applied right away, whatever open transactions are doing;
+//! - an open transaction that read a key with `get_for_update`, or wrote it, fails to commit
+//!   with `Busy` when the key still has a version in the memtable: conflict checking finds the
+//!   tombstone covering that version;
+//! - writes to keys without a version in the memtable don't conflict, they are committed after
+//!   the tombstone and reappear in the purged prefix;
+//! - snapshots taken before it still see the deleted keys;
+//! - the deleted keys stay on disk until compaction, reads skip them by range.
+//!
+//! [`delete_prefix_in_txn`] is the fallback for `TransactionDB`: it scans the prefix and
+//! deletes every key it finds inside the caller's transaction. Every deleted key is locked
+//! until the transaction ends, so concurrent writers to those keys wait, but a key inserted
+//! into the prefix in the meantime is a phantom and survives the purge (see
+//! [`crate::range_lock`] to prevent it). Reads have to skip one point tombstone per deleted
+//! key until compaction, the benchmarks compare both.
+use anyhow::Result;
+use rocksdb::{
+    AsColumnFamilyRef, Direction, IteratorMode, OptimisticTransactionDB, ReadOptions, Transaction,
+    WriteBatchWithTransaction,
+};
+
+use crate::prefix_end;
+
+/// Deletes every key starting with `prefix` in `cf` with a range tombstone.
+///
+/// A prefix without end, empty or only made of `0xff` bytes, is deleted up to the last key
+/// found when the deletion starts: keys written after it sorting above that one survive.
+pub fn delete_prefix_range(
+    db: &OptimisticTransactionDB,
+    cf: &impl AsColumnFamilyRef,
+    prefix: &[u8],
+) -> Result<()> {
+    if let Some(end) = prefix_end(prefix) {
+        db.delete_range_cf(cf, prefix, end.as_slice())?;
+        return Ok(());
+    }
+
+    // The prefix is empty or only made of `0xff` bytes: every key from the prefix onwards
+    // matches it, delete up to the last key and then the last key itself, in one batch so
+    // that no read sees the range deleted without the last key.
+    let mut iter = db.raw_iterator_cf(cf);
+    iter.seek_to_last();
+    iter.status()?;
+    let Some(last) = iter.key().map(<[u8]>::to_vec) else {
+        return Ok(());
+    };
+    if last.as_slice() >= prefix {
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        batch.delete_range_cf(cf, prefix, last.as_slice());
+        batch.delete_cf(cf, &last);
+        db.write(batch)?;
+    }
+    Ok(())
+}
+
+/// Deletes every key starting with `prefix` in `cf` inside `txn`, returns the number of keys
+/// deleted. Nothing is deleted until `txn` commits.
+pub fn delete_prefix_in_txn<DB>(
+    txn: &Transaction<'_, DB>,
+    cf: &impl AsColumnFamilyRef,
+    prefix: &[u8],
+) -> Result<usize> {
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = prefix_end(prefix) {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    // Writing to the transaction while iterating over its own writes is not safe, collect the
+    // keys first.
+    let keys = txn
+        .iterator_cf_opt(
+            cf,
+            read_opts,
+            IteratorMode::From(prefix, Direction::Forward),
+        )
+        .map(|item| item.map(|(key, _)| key))
+        .collect::<Result<Vec<_>, _>>()?;
+    for key in &keys {
+        txn.delete_cf(cf, key)?;
+    }
+    Ok(keys.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::{ErrorKind, WriteOptions};
+
+    use super::*;
+    use crate::{
+        model::no_wait_txn_opts,
+        test_utils::{temp_optimistic_db, temp_transaction_db},
+        DBColumnFamilies,
+    };
+
+    const KEYS: [&[u8]; 5] = [b"a/1", b"a/2", b"ab", b"b/1", b"\xff\xff"];
+
+    fn keys(db: &OptimisticTransactionDB) -> Vec<Vec<u8>> {
+        db.iterator_cf(&DBColumnFamilies::User.cf(db), IteratorMode::Start)
+            .map(|item| item.unwrap().0.to_vec())
+            .collect()
+    }
+
+    fn seed(db: &OptimisticTransactionDB) {
+        for key in KEYS {
+            db.put_cf(&DBColumnFamilies::User.cf(db), key, b"v")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn range_delete_only_removes_prefix() {
+        let (_dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        seed(&db);
+
+        delete_prefix_range(&db, &cf, b"a/").unwrap();
+        assert_eq!(
+            keys(&db),
+            vec![b"ab".to_vec(), b"b/1".to_vec(), b"\xff\xff".to_vec()]
+        );
+
+        delete_prefix_range(&db, &cf, b"\xff").unwrap();
+        assert_eq!(keys(&db), vec![b"ab".to_vec(), b"b/1".to_vec()]);
+
+        delete_prefix_range(&db, &cf, b"").unwrap();
+        assert!(keys(&db).is_empty());
+        delete_prefix_range(&db, &cf, b"").unwrap();
+    }
+
+    #[test]
+    fn snapshot_still_sees_range_deleted_keys() {
+        let (_dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        seed(&db);
+
+        let snapshot = db.snapshot();
+        delete_prefix_range(&db, &cf, b"a/").unwrap();
+
+        assert_eq!(db.get_cf(&cf, b"a/1").unwrap(), None);
+        assert_eq!(snapshot.get_cf(&cf, b"a/1").unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn range_delete_conflicts_with_transactions_on_existing_keys() {
+        let (_dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        seed(&db);
+
+        let reader = db.transaction();
+        reader.get_for_update_cf(&cf, b"a/1", true).unwrap();
+        reader.put_cf(&cf, b"b/2", b"reader").unwrap();
+        let writer = db.transaction();
+        writer.put_cf(&cf, b"a/2", b"writer").unwrap();
+
+        delete_prefix_range(&db, &cf, b"a/").unwrap();
+
+        assert_eq!(reader.commit().unwrap_err().kind(), ErrorKind::Busy);
+        assert_eq!(writer.commit().unwrap_err().kind(), ErrorKind::Busy);
+        assert_eq!(
+            keys(&db),
+            vec![b"ab".to_vec(), b"b/1".to_vec(), b"\xff\xff".to_vec()]
+        );
+    }
+
+    #[test]
+    fn writes_to_new_keys_reappear_after_range_delete() {
+        let (_dir, db) = temp_optimistic_db();
+        let cf = DBColumnFamilies::User.cf(&db);
+        seed(&db);
+
+        let txn = db.transaction();
+        txn.put_cf(&cf, b"a/3", b"txn").unwrap();
+        delete_prefix_range(&db, &cf, b"a/").unwrap();
+        // Its uncommitted write is not deleted, the transaction still reads it.
+        assert_eq!(txn.get_cf(&cf, b"a/3").unwrap(), Some(b"txn".to_vec()));
+
+        txn.commit().unwrap();
+        assert_eq!(db.get_cf(&cf, b"a/1").unwrap(), None);
+        assert_eq!(db.get_cf(&cf, b"a/3").unwrap(), Some(b"txn".to_vec()));
+    }
+
+    #[test]
+    fn txn_delete_locks_existing_keys_but_not_phantoms() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        db.put_cf(&cf, b"order/1", b"1").unwrap();
+        db.put_cf(&cf, b"order/2", b"2").unwrap();
+
+        let txn1 = db.transaction();
+        assert_eq!(delete_prefix_in_txn(&txn1, &cf, b"order/").unwrap(), 2);
+
+        let txn2 = db.transaction_opt(&WriteOptions::default(), &no_wait_txn_opts());
+        assert!(
+            txn2.put_cf(&cf, b"order/1", b"txn2").is_err(),
+            "deleted keys are locked"
+        );
+        txn2.put_cf(&cf, b"order/3", b"3").unwrap();
+        txn2.commit().unwrap();
+
+        // Not deleted until commit.
+        assert_eq!(db.get_cf(&cf, b"order/1").unwrap(), Some(b"1".to_vec()));
+        txn1.commit().unwrap();
+
+        let remaining: Vec<_> = db
+            .iterator_cf(&cf, IteratorMode::Start)
+            .map(|item| item.unwrap().0.to_vec())
+            .collect();
+        assert_eq!(remaining, vec![b"order/3".to_vec()], "the phantom survives");
+    }
+
+    #[test]
+    fn txn_delete_sees_own_writes() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        db.put_cf(&cf, b"order/1", b"1").unwrap();
+
+        let txn = db.transaction();
+        txn.put_cf(&cf, b"order/2", b"2").unwrap();
+        assert_eq!(delete_prefix_in_txn(&txn, &cf, b"order/").unwrap(), 2);
+        txn.commit().unwrap();
+
+        assert_eq!(db.iterator_cf(&cf, IteratorMode::Start).count(), 0);
+    }
+}
@@ -0,0 +1,453 @@
+//! Application-level range locks layered on top of the transaction API.
+//!
+//! `get_for_update_cf` locks single keys, and only the keys a transaction names. A transaction
+//! that scans a prefix and then inserts based on what it saw cannot stop another transaction
+//! from inserting a new key into the same prefix in the meantime: the second insert is a
+//! phantom for the first transaction, and both commit.
+//!
+//! [`RangeLockManager`] closes that gap in-process. Transactions lock the key range (or prefix)
+//! before scanning it and keep the [`RangeLockGuard`] until they commit or roll back. Ranges
+//! needed together should be taken with [`RangeLockManager::lock_all`], which acquires them in
+//! a global order so that two transactions can never wait on each other.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::prefix_end;
+
+/// Half-open key range `[start, end)` of a column family, `end = None` is unbounded.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeyRange {
+    pub cf: String,
+    pub start: Vec<u8>,
+    pub end: Option<Vec<u8>>,
+}
+
+impl KeyRange {
+    pub fn new(cf: impl AsRef<str>, start: impl Into<Vec<u8>>, end: Option<Vec<u8>>) -> Self {
+        Self {
+            cf: cf.as_ref().to_string(),
+            start: start.into(),
+            end,
+        }
+    }
+
+    /// Every key starting with `prefix`.
+    pub fn prefix(cf: impl AsRef<str>, prefix: impl Into<Vec<u8>>) -> Self {
+        let start = prefix.into();
+        let end = prefix_end(&start);
+        Self::new(cf, start, end)
+    }
+
+    /// The single key `key`.
+    pub fn key(cf: impl AsRef<str>, key: impl Into<Vec<u8>>) -> Self {
+        let start = key.into();
+        let mut end = start.clone();
+        end.push(0);
+        Self::new(cf, start, Some(end))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && self.end.as_deref().is_none_or(|end| key < end)
+    }
+
+    pub fn overlaps(&self, other: &KeyRange) -> bool {
+        let starts_before_other_ends = other
+            .end
+            .as_deref()
+            .is_none_or(|end| self.start.as_slice() < end);
+        let ends_after_other_starts = self
+            .end
+            .as_deref()
+            .is_none_or(|end| other.start.as_slice() < end);
+        self.cf == other.cf && starts_before_other_ends && ends_after_other_starts
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Compatible with other shared locks, for scans.
+    Shared,
+    /// Incompatible with any other lock, for scans followed by inserts.
+    Exclusive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeLockError {
+    /// A conflicting lock was still held by another owner when the timeout expired.
+    Timeout { range: KeyRange },
+}
+
+impl fmt::Display for RangeLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeLockError::Timeout { range } => write!(
+                f,
+                "Timeout waiting to lock range {}[{}, {})",
+                range.cf,
+                String::from_utf8_lossy(&range.start),
+                range
+                    .end
+                    .as_deref()
+                    .map_or("..".into(), String::from_utf8_lossy)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RangeLockError {}
+
+/// Identifies the transaction holding locks, locks of the same owner never conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockOwner(u64);
+
+#[derive(Debug)]
+struct HeldLock {
+    id: u64,
+    owner: LockOwner,
+    range: KeyRange,
+    mode: LockMode,
+}
+
+#[derive(Debug)]
+pub struct RangeLockManager {
+    timeout: Duration,
+    next_id: AtomicU64,
+    held: Mutex<Vec<HeldLock>>,
+    released: Condvar,
+}
+
+impl RangeLockManager {
+    /// Creates a manager where lock attempts give up after `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: AtomicU64::new(0),
+            held: Mutex::new(Vec::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Allocates an owner, typically one per transaction.
+    pub fn owner(&self) -> LockOwner {
+        LockOwner(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Locks `range`, waiting up to the manager timeout for conflicting locks to be released.
+    pub fn lock(
+        &self,
+        owner: LockOwner,
+        range: KeyRange,
+        mode: LockMode,
+    ) -> Result<RangeLockGuard<'_>, RangeLockError> {
+        self.lock_all(owner, vec![(range, mode)])
+    }
+
+    /// Locks every range in a global order (column family, start, end), so that callers
+    /// locking overlapping sets of ranges cannot deadlock. Either every range is locked or
+    /// none is.
+    pub fn lock_all(
+        &self,
+        owner: LockOwner,
+        mut ranges: Vec<(KeyRange, LockMode)>,
+    ) -> Result<RangeLockGuard<'_>, RangeLockError> {
+        ranges.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut guard = RangeLockGuard {
+            manager: self,
+            ids: Vec::with_capacity(ranges.len()),
+        };
+        let deadline = Instant::now() + self.timeout;
+        for (range, mode) in ranges {
+            // Dropping the guard on error releases the ranges locked so far.
+            guard.ids.push(self.acquire(owner, range, mode, deadline)?);
+        }
+        Ok(guard)
+    }
+
+    fn acquire(
+        &self,
+        owner: LockOwner,
+        range: KeyRange,
+        mode: LockMode,
+        deadline: Instant,
+    ) -> Result<u64, RangeLockError> {
+        let mut held = self.held.lock().unwrap();
+        loop {
+            let conflict = held.iter().any(|lock| {
+                lock.owner != owner
+                    && lock.range.overlaps(&range)
+                    && (lock.mode == LockMode::Exclusive || mode == LockMode::Exclusive)
+            });
+            if !conflict {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RangeLockError::Timeout { range });
+            }
+            held = self.released.wait_timeout(held, deadline - now).unwrap().0;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        held.push(HeldLock {
+            id,
+            owner,
+            range,
+            mode,
+        });
+        Ok(id)
+    }
+
+    fn release(&self, ids: &[u64]) {
+        if ids.is_empty() {
+            return;
+        }
+        self.held
+            .lock()
+            .unwrap()
+            .retain(|lock| !ids.contains(&lock.id));
+        self.released.notify_all();
+    }
+}
+
+/// Locks held by one [`RangeLockManager::lock_all`] call, released on drop.
+#[derive(Debug)]
+pub struct RangeLockGuard<'a> {
+    manager: &'a RangeLockManager,
+    ids: Vec<u64>,
+}
+
+impl Drop for RangeLockGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.release(&self.ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use rocksdb::{Direction, IteratorMode, ReadOptions, Transaction, TransactionDB};
+
+    use super::*;
+    use crate::{test_utils::temp_transaction_db, DBColumnFamilies};
+
+    const LIMIT: usize = 2;
+
+    fn scan_prefix(txn: &Transaction<TransactionDB>, db: &TransactionDB, prefix: &[u8]) -> usize {
+        let mut read_opts = ReadOptions::default();
+        if let Some(end) = prefix_end(prefix) {
+            read_opts.set_iterate_upper_bound(end);
+        }
+        txn.iterator_cf_opt(
+            &DBColumnFamilies::User.cf_db(db),
+            read_opts,
+            IteratorMode::From(prefix, Direction::Forward),
+        )
+        .map(|item| {
+            let (key, _) = item.unwrap();
+            // Lock what we saw, which is all `get_for_update_cf` can do.
+            txn.get_for_update_cf(&DBColumnFamilies::User.cf_db(db), &key, false)
+                .unwrap();
+        })
+        .count()
+    }
+
+    fn orders(db: &TransactionDB) -> usize {
+        scan_prefix(&db.transaction(), db, b"order/")
+    }
+
+    fn seed(db: &TransactionDB) {
+        db.put_cf(&DBColumnFamilies::User.cf_db(db), b"order/1", b"1")
+            .unwrap();
+    }
+
+    #[test]
+    fn key_ranges_overlap() {
+        let orders = KeyRange::prefix("User", "order/");
+        assert!(orders.contains(b"order/1"));
+        assert!(!orders.contains(b"orders"));
+        assert!(orders.overlaps(&KeyRange::key("User", "order/7")));
+        assert!(orders.overlaps(&KeyRange::new("User", "a", None)));
+        assert!(!orders.overlaps(&KeyRange::prefix("User", "user/")));
+        assert!(!orders.overlaps(&KeyRange::prefix("Meta", "order/")));
+        assert!(!KeyRange::key("User", "a").overlaps(&KeyRange::key("User", "a\0")));
+    }
+
+    #[test]
+    fn shared_locks_are_compatible() {
+        let manager = RangeLockManager::new(Duration::from_millis(10));
+        let range = KeyRange::prefix("User", "order/");
+
+        let _shared1 = manager
+            .lock(manager.owner(), range.clone(), LockMode::Shared)
+            .unwrap();
+        let _shared2 = manager
+            .lock(manager.owner(), range.clone(), LockMode::Shared)
+            .unwrap();
+        let exclusive = manager.lock(manager.owner(), range, LockMode::Exclusive);
+        assert!(matches!(exclusive, Err(RangeLockError::Timeout { .. })));
+    }
+
+    #[test]
+    fn phantom_without_range_lock() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        seed(&db);
+
+        let txn1 = db.transaction();
+        let txn2 = db.transaction();
+
+        // Both check the limit, each sees a single order.
+        assert_eq!(scan_prefix(&txn1, &db, b"order/"), 1);
+        assert_eq!(scan_prefix(&txn2, &db, b"order/"), 1);
+
+        // Inserting new keys does not conflict with the shared locks on existing keys.
+        txn1.put_cf(&cf, b"order/2", b"2").unwrap();
+        txn2.put_cf(&cf, b"order/3", b"3").unwrap();
+        txn1.commit().unwrap();
+        txn2.commit().unwrap();
+
+        assert_eq!(orders(&db), LIMIT + 1, "the limit was broken by a phantom");
+    }
+
+    #[test]
+    fn range_lock_prevents_phantom() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let manager = RangeLockManager::new(Duration::from_millis(50));
+        seed(&db);
+        let range = KeyRange::prefix(DBColumnFamilies::User, "order/");
+
+        let owner1 = manager.owner();
+        let owner2 = manager.owner();
+
+        let txn1 = db.transaction();
+        let lock1 = manager
+            .lock(owner1, range.clone(), LockMode::Exclusive)
+            .unwrap();
+        assert_eq!(scan_prefix(&txn1, &db, b"order/"), 1);
+
+        let res = manager.lock(owner2, range.clone(), LockMode::Exclusive);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Timeout waiting to lock range User[order/, order0)"
+        );
+
+        txn1.put_cf(&cf, b"order/2", b"2").unwrap();
+        txn1.commit().unwrap();
+        drop(lock1);
+
+        let txn2 = db.transaction();
+        let _lock2 = manager.lock(owner2, range, LockMode::Exclusive).unwrap();
+        assert_eq!(scan_prefix(&txn2, &db, b"order/"), LIMIT, "limit reached");
+
+        assert_eq!(orders(&db), LIMIT);
+    }
+
+    #[test]
+    fn concurrent_inserts_respect_limit_with_range_lock() {
+        let (_dir, db) = temp_transaction_db();
+        let manager = RangeLockManager::new(Duration::from_secs(5));
+        seed(&db);
+
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let db = &db;
+                let manager = &manager;
+                s.spawn(move || {
+                    let txn = db.transaction();
+                    let _lock = manager
+                        .lock(
+                            manager.owner(),
+                            KeyRange::prefix(DBColumnFamilies::User, "order/"),
+                            LockMode::Exclusive,
+                        )
+                        .unwrap();
+                    if scan_prefix(&txn, db, b"order/") < LIMIT {
+                        txn.put_cf(
+                            &DBColumnFamilies::User.cf_db(db),
+                            format!("order/t{i}"),
+                            b"x",
+                        )
+                        .unwrap();
+                    }
+                    txn.commit().unwrap();
+                });
+            }
+        });
+
+        assert_eq!(orders(&db), LIMIT);
+    }
+
+    #[test]
+    fn lock_all_orders_ranges_to_avoid_deadlock() {
+        let manager = RangeLockManager::new(Duration::from_secs(5));
+        let a = (KeyRange::prefix("User", "a/"), LockMode::Exclusive);
+        let b = (KeyRange::prefix("User", "b/"), LockMode::Exclusive);
+        let barrier = Barrier::new(2);
+
+        std::thread::scope(|s| {
+            for ranges in [vec![a.clone(), b.clone()], vec![b, a]] {
+                let manager = &manager;
+                let barrier = &barrier;
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        barrier.wait();
+                        let owner = manager.owner();
+                        manager.lock_all(owner, ranges.clone()).unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn locking_in_opposite_order_one_by_one_times_out() {
+        let manager = RangeLockManager::new(Duration::from_millis(10));
+        let (owner1, owner2) = (manager.owner(), manager.owner());
+
+        let _a = manager
+            .lock(owner1, KeyRange::prefix("User", "a/"), LockMode::Exclusive)
+            .unwrap();
+        let _b = manager
+            .lock(owner2, KeyRange::prefix("User", "b/"), LockMode::Exclusive)
+            .unwrap();
+
+        // owner1 now waits for owner2 which would wait for owner1: only the timeout ends it.
+        assert!(manager
+            .lock(owner1, KeyRange::prefix("User", "b/"), LockMode::Exclusive)
+            .is_err());
+        assert!(manager
+            .lock(owner2, KeyRange::prefix("User", "a/"), LockMode::Exclusive)
+            .is_err());
+    }
+
+    #[test]
+    fn failed_lock_all_releases_acquired_ranges() {
+        let manager = RangeLockManager::new(Duration::from_millis(10));
+        let (owner1, owner2) = (manager.owner(), manager.owner());
+
+        let _b = manager
+            .lock(owner2, KeyRange::prefix("User", "b/"), LockMode::Exclusive)
+            .unwrap();
+        let res = manager.lock_all(
+            owner1,
+            vec![
+                (KeyRange::prefix("User", "a/"), LockMode::Exclusive),
+                (KeyRange::prefix("User", "b/"), LockMode::Exclusive),
+            ],
+        );
+        assert!(res.is_err());
+
+        manager
+            .lock(owner2, KeyRange::prefix("User", "a/"), LockMode::Exclusive)
+            .unwrap();
+    }
+}
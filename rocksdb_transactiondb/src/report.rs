@@ -0,0 +1,484 @@
+//! Observed locking, snapshot and isolation behaviour, backing the `report` binary.
+//!
+//! Every scenario interleaves two transactions, T1 and T2, from a single thread and records
+//! the outcome of the steps that matter, for instance the `put_cf` of T2 after T1 took a shared
+//! lock. [`generate`] runs every scenario against every [`Engine`], with and without a
+//! transaction snapshot, and collects a matrix that renders as Markdown or serializes as JSON.
+//! Nothing in it is predicted: a cell is what RocksDB did on this machine.
+//!
+//! `TransactionDB` transactions use a short lock timeout, so a step that would block reports
+//! `LockTimeout` quickly. `OptimisticTransactionDB` never blocks, conflicts show up at commit.
+use std::{fmt, path::Path};
+
+use anyhow::{bail, Result};
+use rocksdb::{
+    ColumnFamilyDescriptor, Direction, ErrorKind, IteratorMode, OptimisticTransactionDB, Options,
+    ReadOptions, TransactionDB, TransactionDBOptions,
+};
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    history::{Engine, WorkloadConfig},
+    prefix_end, DBColumnFamilies,
+};
+
+const KEY: &[u8] = b"user1";
+const SEEDED: &[u8] = b"user1";
+const PREFIX: &[u8] = b"order/";
+const EXISTING: &[u8] = b"order/1";
+const PHANTOM: &[u8] = b"order/2";
+const LOCK_TIMEOUT_MS: i64 = 50;
+
+/// What a step did.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Observed {
+    Ok,
+    /// A read returned this value, `None` when the key was not found.
+    Value(Option<String>),
+    /// Another transaction held a conflicting lock.
+    LockTimeout,
+    /// Write conflict detected against the transaction snapshot or at optimistic commit.
+    Busy,
+    /// Conflict checking could not tell, the memtable history was too short.
+    TryAgain,
+    Error(String),
+}
+
+impl Observed {
+    fn done(res: Result<(), rocksdb::Error>) -> Self {
+        match res {
+            Ok(()) => Observed::Ok,
+            Err(err) => err.into(),
+        }
+    }
+
+    fn read(res: Result<Option<Vec<u8>>, rocksdb::Error>) -> Self {
+        match res {
+            Ok(value) => Observed::Value(value.map(|v| String::from_utf8_lossy(&v).into_owned())),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+impl From<rocksdb::Error> for Observed {
+    fn from(err: rocksdb::Error) -> Self {
+        match err.kind() {
+            ErrorKind::TimedOut => Observed::LockTimeout,
+            ErrorKind::Busy => Observed::Busy,
+            ErrorKind::TryAgain => Observed::TryAgain,
+            _ => Observed::Error(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Observed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Observed::Ok => write!(f, "ok"),
+            Observed::Value(Some(value)) => write!(f, "`{value}`"),
+            Observed::Value(None) => write!(f, "not found"),
+            Observed::LockTimeout => write!(f, "LockTimeout"),
+            Observed::Busy => write!(f, "Busy"),
+            Observed::TryAgain => write!(f, "TryAgain"),
+            Observed::Error(err) => write!(f, "error: {err}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Observation {
+    pub step: &'static str,
+    pub observed: Observed,
+}
+
+fn observe(step: &'static str, observed: Observed) -> Observation {
+    Observation { step, observed }
+}
+
+/// An engine and its transaction options, a column of the matrix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Setup {
+    pub engine: &'static str,
+    pub snapshot: bool,
+}
+
+impl fmt::Display for Setup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.engine)?;
+        if self.snapshot {
+            write!(f, " + snapshot")?;
+        }
+        Ok(())
+    }
+}
+
+/// A scenario, a row of the matrix, with what every [`Setup`] observed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Row {
+    pub scenario: &'static str,
+    pub observations: Vec<Vec<Observation>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub setups: Vec<Setup>,
+    pub rows: Vec<Row>,
+}
+
+impl Report {
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::from("| scenario |");
+        for setup in &self.setups {
+            markdown.push_str(&format!(" {setup} |"));
+        }
+        markdown.push_str("\n|---|");
+        markdown.push_str(&"---|".repeat(self.setups.len()));
+        markdown.push('\n');
+        for row in &self.rows {
+            markdown.push_str(&format!("| {} |", row.scenario));
+            for observations in &row.observations {
+                let cell: Vec<_> = observations
+                    .iter()
+                    .map(|o| format!("{} → {}", o.step, o.observed))
+                    .collect();
+                markdown.push_str(&format!(" {} |", cell.join("<br>")));
+            }
+            markdown.push('\n');
+        }
+        markdown
+    }
+}
+
+/// Markers around the generated table in the README.
+pub const README_START: &str = "<!-- isolation-report:start -->";
+pub const README_END: &str = "<!-- isolation-report:end -->";
+
+/// Replaces what is between [`README_START`] and [`README_END`] in `readme` by `markdown`.
+pub fn splice_readme(readme: &str, markdown: &str) -> Result<String> {
+    let (Some(start), Some(end)) = (readme.find(README_START), readme.find(README_END)) else {
+        bail!("missing {README_START} or {README_END} markers");
+    };
+    if end < start {
+        bail!("{README_END} before {README_START}");
+    }
+    Ok(format!(
+        "{}{README_START}\n\n{markdown}\n{}",
+        &readme[..start],
+        &readme[end..]
+    ))
+}
+
+type Scenario<DB> = fn(&DB, &WorkloadConfig) -> Result<Vec<Observation>>;
+
+/// Every scenario, described by the steps leading to the observed ones.
+fn scenarios<DB: Engine>() -> [(&'static str, Scenario<DB>); 11] {
+    [
+        ("T1 get; T2 put", get_then_put::<DB>),
+        ("T1 put; T2 get", put_then_get::<DB>),
+        ("T1 put; T2 put", put_then_put::<DB>),
+        ("T1 get_for_update(shared); T2 get", |db, config| {
+            lock_then_get(db, config, false)
+        }),
+        (
+            "T1 get_for_update(shared); T2 get_for_update(shared)",
+            |db, config| lock_then_lock(db, config, false, false),
+        ),
+        (
+            "T1 get_for_update(exclusive); T2 get_for_update(shared)",
+            |db, config| lock_then_lock(db, config, true, false),
+        ),
+        ("T1 get_for_update(shared); T2 put", |db, config| {
+            lock_then_put(db, config, false)
+        }),
+        ("T1 get_for_update(exclusive); T2 put", |db, config| {
+            lock_then_put(db, config, true)
+        }),
+        (
+            "T1 get; T2 put, commit; T1 get (non-repeatable read)",
+            non_repeatable_read::<DB>,
+        ),
+        (
+            "T1 get; T2 put, commit; T1 put, commit (lost update)",
+            lost_update::<DB>,
+        ),
+        (
+            "T1 get_for_update(exclusive) of every `order/` key; T2 insert `order/2` (phantom)",
+            phantom::<DB>,
+        ),
+    ]
+}
+
+fn seed<DB: Engine>(db: &DB) -> Result<()> {
+    let cf = db.user_cf();
+    let txn = db.begin(&WorkloadConfig::default());
+    txn.put_cf(&cf, KEY, SEEDED)?;
+    txn.put_cf(&cf, EXISTING, b"1")?;
+    txn.delete_cf(&cf, PHANTOM)?;
+    txn.commit()?;
+    Ok(())
+}
+
+fn get_then_put<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_cf(&cf, KEY)?;
+    Ok(vec![
+        observe("T2 put", Observed::done(t2.put_cf(&cf, KEY, b"t2"))),
+        observe("T2 commit", Observed::done(t2.commit())),
+        observe("T1 commit", Observed::done(t1.commit())),
+    ])
+}
+
+fn put_then_get<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.put_cf(&cf, KEY, b"t1")?;
+    Ok(vec![observe("T2 get", Observed::read(t2.get_cf(&cf, KEY)))])
+}
+
+fn put_then_put<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.put_cf(&cf, KEY, b"t1")?;
+    Ok(vec![
+        observe("T2 put", Observed::done(t2.put_cf(&cf, KEY, b"t2"))),
+        observe("T1 commit", Observed::done(t1.commit())),
+        observe("T2 commit", Observed::done(t2.commit())),
+    ])
+}
+
+fn lock_then_get<DB: Engine>(
+    db: &DB,
+    config: &WorkloadConfig,
+    exclusive: bool,
+) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_for_update_cf(&cf, KEY, exclusive)?;
+    Ok(vec![observe("T2 get", Observed::read(t2.get_cf(&cf, KEY)))])
+}
+
+fn lock_then_lock<DB: Engine>(
+    db: &DB,
+    config: &WorkloadConfig,
+    exclusive1: bool,
+    exclusive2: bool,
+) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_for_update_cf(&cf, KEY, exclusive1)?;
+    Ok(vec![observe(
+        "T2 get_for_update",
+        Observed::read(t2.get_for_update_cf(&cf, KEY, exclusive2)),
+    )])
+}
+
+fn lock_then_put<DB: Engine>(
+    db: &DB,
+    config: &WorkloadConfig,
+    exclusive: bool,
+) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_for_update_cf(&cf, KEY, exclusive)?;
+    Ok(vec![
+        observe("T2 put", Observed::done(t2.put_cf(&cf, KEY, b"t2"))),
+        observe("T2 commit", Observed::done(t2.commit())),
+        observe("T1 commit", Observed::done(t1.commit())),
+    ])
+}
+
+fn non_repeatable_read<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_cf(&cf, KEY)?;
+    t2.put_cf(&cf, KEY, b"t2")?;
+    Ok(vec![
+        observe("T2 commit", Observed::done(t2.commit())),
+        observe("T1 get", Observed::read(t1.get_cf(&cf, KEY))),
+    ])
+}
+
+fn lost_update<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    t1.get_cf(&cf, KEY)?;
+    t2.put_cf(&cf, KEY, b"t2")?;
+    Ok(vec![
+        observe("T2 commit", Observed::done(t2.commit())),
+        observe("T1 put", Observed::done(t1.put_cf(&cf, KEY, b"t1"))),
+        observe("T1 commit", Observed::done(t1.commit())),
+    ])
+}
+
+fn phantom<DB: Engine>(db: &DB, config: &WorkloadConfig) -> Result<Vec<Observation>> {
+    let cf = db.user_cf();
+    let (t1, t2) = (db.begin(config), db.begin(config));
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = prefix_end(PREFIX) {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    let keys = t1
+        .iterator_cf_opt(
+            &cf,
+            read_opts,
+            IteratorMode::From(PREFIX, Direction::Forward),
+        )
+        .map(|item| item.map(|(key, _)| key))
+        .collect::<Result<Vec<_>, _>>()?;
+    for key in &keys {
+        t1.get_for_update_cf(&cf, key, true)?;
+    }
+    Ok(vec![
+        observe("T2 put", Observed::done(t2.put_cf(&cf, PHANTOM, b"2"))),
+        observe("T2 commit", Observed::done(t2.commit())),
+        observe("T1 commit", Observed::done(t1.commit())),
+    ])
+}
+
+/// Rows of every scenario for `db`, a column per entry of `configs`.
+fn observe_engine<DB: Engine>(
+    db: &DB,
+    configs: &[WorkloadConfig],
+) -> Result<Vec<Vec<Vec<Observation>>>> {
+    scenarios::<DB>()
+        .into_iter()
+        .map(|(_, scenario)| {
+            configs
+                .iter()
+                .map(|config| {
+                    seed(db)?;
+                    scenario(db, config)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Runs every scenario against a `TransactionDB` and an `OptimisticTransactionDB` created
+/// in `dir`, which must not contain databases already.
+pub fn generate(dir: &Path) -> Result<Report> {
+    let mut db_opts = Options::default();
+    db_opts.create_missing_column_families(true);
+    db_opts.create_if_missing(true);
+    let descriptors = || {
+        DBColumnFamilies::iter()
+            .map(|cf| ColumnFamilyDescriptor::new(cf.as_ref(), Options::default()))
+    };
+    let pessimistic = TransactionDB::open_cf_descriptors(
+        &db_opts,
+        &TransactionDBOptions::default(),
+        dir.join("pessimistic"),
+        descriptors(),
+    )?;
+    let optimistic = OptimisticTransactionDB::open_cf_descriptors(
+        &db_opts,
+        dir.join("optimistic"),
+        descriptors(),
+    )?;
+
+    let configs: Vec<_> = [false, true]
+        .into_iter()
+        .map(|snapshot| WorkloadConfig {
+            snapshot,
+            lock_timeout_ms: LOCK_TIMEOUT_MS,
+            ..WorkloadConfig::default()
+        })
+        .collect();
+    let setups = [TransactionDB::NAME, OptimisticTransactionDB::NAME]
+        .into_iter()
+        .flat_map(|engine| {
+            configs.iter().map(move |config| Setup {
+                engine,
+                snapshot: config.snapshot,
+            })
+        })
+        .collect();
+
+    let mut columns = observe_engine(&pessimistic, &configs)?;
+    for (row, optimistic_row) in columns
+        .iter_mut()
+        .zip(observe_engine(&optimistic, &configs)?)
+    {
+        row.extend(optimistic_row);
+    }
+    let rows = scenarios::<TransactionDB>()
+        .into_iter()
+        .zip(columns)
+        .map(|((scenario, _), observations)| Row {
+            scenario,
+            observations,
+        })
+        .collect();
+
+    Ok(Report { setups, rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell<'a>(report: &'a Report, scenario: &str, setup: &str) -> &'a [Observation] {
+        let column = report
+            .setups
+            .iter()
+            .position(|s| s.to_string() == setup)
+            .unwrap();
+        let row = report
+            .rows
+            .iter()
+            .find(|row| row.scenario == scenario)
+            .unwrap();
+        &row.observations[column]
+    }
+
+    #[test]
+    fn reports_every_scenario_for_every_setup() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = generate(dir.path()).unwrap();
+
+        assert_eq!(report.setups.len(), 4);
+        assert_eq!(report.rows.len(), scenarios::<TransactionDB>().len());
+        assert!(report.rows.iter().all(|row| row.observations.len() == 4));
+
+        // Same behaviours as the scenarios of `main.rs`.
+        assert_eq!(
+            cell(
+                &report,
+                "T1 get_for_update(shared); T2 put",
+                "TransactionDB"
+            )[0],
+            observe("T2 put", Observed::LockTimeout)
+        );
+        assert_eq!(
+            cell(&report, "T1 put; T2 get", "TransactionDB")[0],
+            observe("T2 get", Observed::Value(Some("user1".into())))
+        );
+        assert_eq!(
+            cell(&report, "T1 put; T2 put", "OptimisticTransactionDB"),
+            [
+                observe("T2 put", Observed::Ok),
+                observe("T1 commit", Observed::Ok),
+                observe("T2 commit", Observed::Busy),
+            ]
+        );
+
+        let markdown = report.to_markdown();
+        assert_eq!(markdown.lines().count(), report.rows.len() + 2);
+        assert!(markdown.starts_with("| scenario | TransactionDB | TransactionDB + snapshot |"));
+        assert!(serde_json::to_string(&report).is_ok());
+    }
+
+    #[test]
+    fn splices_table_into_readme() {
+        let readme = format!("# title\n{README_START}\nold\n{README_END}\nrest\n");
+        let spliced = splice_readme(&readme, "| new |\n").unwrap();
+        assert_eq!(
+            spliced,
+            format!("# title\n{README_START}\n\n| new |\n\n{README_END}\nrest\n")
+        );
+        assert_eq!(splice_readme(&spliced, "| new |\n").unwrap(), spliced);
+        assert!(splice_readme("# title\n", "| new |\n").is_err());
+    }
+}
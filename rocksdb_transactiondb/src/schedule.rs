@@ -0,0 +1,255 @@
+//! Deterministic scheduling of transactions run by several logical actors.
+//!
+//! An actor runs one transaction through a fixed program of [`Op`]s, whose `txn` is the index
+//! of the actor. A schedule lists which actor takes each step. Everything runs on the calling
+//! thread with [`Runner`], so an execution is reproducible from its schedule alone, unlike tasks
+//! coordinated with channels and sleeps.
+//!
+//! An operation that needs a lock held by another actor blocks the actor: locks are requested
+//! without waiting, the failed attempt has no effect, and the actor retries the same operation
+//! when it is scheduled again. [`explore`] enumerates every execution of a small scenario by
+//! branching, at each step, on every actor able to make progress. When none is, the execution
+//! is stuck: the actors left wait on each other (a deadlock) or on an actor whose program ended
+//! without commit or rollback.
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use rocksdb::TransactionDB;
+
+use crate::model::{committed_state, seed, Op, Outcome, Runner};
+
+/// A complete run of a scenario.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    /// Actor of every step, blocked attempts excluded.
+    pub schedule: Vec<usize>,
+    /// Operation and outcome of every step.
+    pub steps: Vec<(Op, Outcome)>,
+    /// Actors that could not run their whole program.
+    pub stuck: Vec<usize>,
+    /// Committed state once the transactions still open are dropped.
+    pub committed: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Execution {
+    pub fn is_stuck(&self) -> bool {
+        !self.stuck.is_empty()
+    }
+}
+
+/// Runs `actors` against `db` following `schedule`, after resetting `db` to
+/// [`crate::model::initial_state`]. Returns `None` when the schedule picks an actor whose
+/// program is over, or an actor that blocks.
+pub fn replay(
+    db: &TransactionDB,
+    actors: &[Vec<Op>],
+    schedule: &[usize],
+) -> Result<Option<Execution>> {
+    check_actors(actors)?;
+    seed(db)?;
+
+    let mut positions = vec![0; actors.len()];
+    let mut steps = Vec::with_capacity(schedule.len());
+    {
+        let mut runner = Runner::new(db);
+        for &actor in schedule {
+            let Some(&op) = actors.get(actor).and_then(|ops| ops.get(positions[actor])) else {
+                return Ok(None);
+            };
+            let outcome = runner.apply(steps.len(), op)?;
+            if outcome == Outcome::TimedOut {
+                return Ok(None);
+            }
+            positions[actor] += 1;
+            steps.push((op, outcome));
+        }
+    }
+
+    Ok(Some(Execution {
+        schedule: schedule.to_vec(),
+        steps,
+        stuck: Vec::new(),
+        committed: committed_state(db)?,
+    }))
+}
+
+/// Every execution of `actors` against `db`, in lexicographic order of their schedules.
+pub fn explore(db: &TransactionDB, actors: &[Vec<Op>]) -> Result<Vec<Execution>> {
+    check_actors(actors)?;
+    let Some(root) = replay(db, actors, &[])? else {
+        unreachable!("the empty schedule is always valid")
+    };
+
+    let mut executions = Vec::new();
+    let mut pending = vec![root];
+    while let Some(execution) = pending.pop() {
+        let mut children = Vec::new();
+        for actor in 0..actors.len() {
+            let mut schedule = execution.schedule.clone();
+            schedule.push(actor);
+            if let Some(child) = replay(db, actors, &schedule)? {
+                children.push(child);
+            }
+        }
+
+        if children.is_empty() {
+            let mut execution = execution;
+            execution.stuck = (0..actors.len())
+                .filter(|&actor| {
+                    execution.schedule.iter().filter(|&&a| a == actor).count() < actors[actor].len()
+                })
+                .collect();
+            executions.push(execution);
+        }
+        // Popped in order of their last actor.
+        pending.extend(children.into_iter().rev());
+    }
+    Ok(executions)
+}
+
+fn check_actors(actors: &[Vec<Op>]) -> Result<()> {
+    for (actor, ops) in actors.iter().enumerate() {
+        if let Some(op) = ops.iter().find(|op| op.txn() != actor) {
+            bail!("actor {actor} runs {op:?} of another transaction");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{initial_state, key, value, Model},
+        test_utils::temp_transaction_db,
+    };
+
+    fn put_commit(txn: usize, key: usize) -> Vec<Op> {
+        vec![Op::Put { txn, key }, Op::Commit { txn }]
+    }
+
+    /// Every execution matches the model, step by step and in the end.
+    fn assert_matches_model(executions: &[Execution]) {
+        for execution in executions {
+            let mut model = Model::new(initial_state());
+            for (step, (op, outcome)) in execution.steps.iter().enumerate() {
+                assert_eq!(&model.apply(step, *op), outcome, "{execution:?}");
+            }
+            assert_eq!(model.committed(), &execution.committed, "{execution:?}");
+        }
+    }
+
+    #[test]
+    fn enumerates_every_interleaving_of_independent_actors() {
+        let (_dir, db) = temp_transaction_db();
+        let actors = [put_commit(0, 0), put_commit(1, 1)];
+
+        let executions = explore(&db, &actors).unwrap();
+        let schedules: Vec<_> = executions.iter().map(|e| e.schedule.clone()).collect();
+        assert_eq!(
+            schedules,
+            vec![
+                vec![0, 0, 1, 1],
+                vec![0, 1, 0, 1],
+                vec![0, 1, 1, 0],
+                vec![1, 0, 0, 1],
+                vec![1, 0, 1, 0],
+                vec![1, 1, 0, 0],
+            ]
+        );
+        assert!(executions.iter().all(|e| !e.is_stuck()));
+        assert_matches_model(&executions);
+    }
+
+    #[test]
+    fn writer_of_a_locked_key_waits_for_commit() {
+        let (_dir, db) = temp_transaction_db();
+        let actors = [put_commit(0, 0), put_commit(1, 0)];
+
+        let executions = explore(&db, &actors).unwrap();
+        let schedules: Vec<_> = executions.iter().map(|e| e.schedule.clone()).collect();
+        assert_eq!(schedules, vec![vec![0, 0, 1, 1], vec![1, 1, 0, 0]]);
+        // The last writer wins.
+        assert_eq!(executions[0].committed[&key(0)], value(2, 1, 0));
+        assert_eq!(executions[1].committed[&key(0)], value(2, 0, 0));
+        assert_matches_model(&executions);
+
+        // Replaying a schedule gives the same execution.
+        assert_eq!(
+            replay(&db, &actors, &[0, 0, 1, 1]).unwrap().as_ref(),
+            Some(&executions[0])
+        );
+        assert_eq!(replay(&db, &actors, &[0, 1]).unwrap(), None);
+        assert_eq!(replay(&db, &actors, &[0, 0, 0]).unwrap(), None);
+    }
+
+    #[test]
+    fn shared_locks_block_writers_until_released() {
+        let (_dir, db) = temp_transaction_db();
+        let actors = [
+            vec![
+                Op::GetForUpdate {
+                    txn: 0,
+                    key: 0,
+                    exclusive: false,
+                },
+                Op::Commit { txn: 0 },
+            ],
+            vec![
+                Op::GetForUpdate {
+                    txn: 1,
+                    key: 0,
+                    exclusive: false,
+                },
+                Op::Put { txn: 1, key: 0 },
+                Op::Commit { txn: 1 },
+            ],
+        ];
+
+        let executions = explore(&db, &actors).unwrap();
+        assert!(executions.iter().all(|e| !e.is_stuck()));
+        let schedules: Vec<_> = executions.iter().map(|e| e.schedule.clone()).collect();
+        // Once both hold the shared lock, actor 1 can only write after actor 0 commits.
+        assert_eq!(
+            schedules,
+            vec![
+                vec![0, 0, 1, 1, 1],
+                vec![0, 1, 0, 1, 1],
+                vec![1, 0, 0, 1, 1],
+                vec![1, 1, 1, 0, 0],
+            ]
+        );
+        assert_matches_model(&executions);
+    }
+
+    #[test]
+    fn finds_deadlocks() {
+        let (_dir, db) = temp_transaction_db();
+        let actors = [
+            vec![
+                Op::Put { txn: 0, key: 0 },
+                Op::Put { txn: 0, key: 1 },
+                Op::Commit { txn: 0 },
+            ],
+            vec![
+                Op::Put { txn: 1, key: 1 },
+                Op::Put { txn: 1, key: 0 },
+                Op::Commit { txn: 1 },
+            ],
+        ];
+
+        let executions = explore(&db, &actors).unwrap();
+        let stuck: Vec<_> = executions.iter().filter(|e| e.is_stuck()).collect();
+        assert_eq!(stuck.len(), 2);
+        assert!(stuck.iter().all(|e| e.stuck == vec![0, 1]));
+        assert!(stuck.iter().all(|e| e.committed == initial_state()));
+        assert_eq!(executions.len(), 4);
+        assert_matches_model(&executions);
+    }
+
+    #[test]
+    fn rejects_ops_of_other_actors() {
+        let (_dir, db) = temp_transaction_db();
+        assert!(explore(&db, &[put_commit(1, 0)]).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Secondary instances, to serve reads from other processes without touching the primary.
+//!
+//! A read-only instance ([`crate::inspect::open_read_only`]) sees the store as it was when it
+//! was opened. A [`Secondary`] keeps its own directory for its info logs and can catch up with
+//! the primary `TransactionDB` by replaying the primary's new WAL entries and files, as often as
+//! it likes, while the primary keeps writing. It never sees uncommitted transactions: only
+//! committed writes reach the WAL.
+//!
+//! Sequence numbers are not exposed by the `TransactionDB` binding, so the lag is measured in
+//! time: the primary writes a heartbeat with its clock into [`DBColumnFamilies::Meta`] with
+//! [`beat`], and the secondary compares the last heartbeat it has caught up with to its own
+//! clock. That assumes clocks close enough together, as for processes on the same host.
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use rocksdb::{Options, TransactionDB, DB};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    ttl::{Clock, SystemClock},
+    DBColumnFamilies,
+};
+
+/// Key of the primary heartbeat in [`DBColumnFamilies::Meta`], big-endian milliseconds.
+pub const HEARTBEAT_KEY: &[u8] = b"heartbeat";
+
+/// Records the time of `clock` as the primary heartbeat.
+pub fn beat(db: &TransactionDB, clock: &dyn Clock) -> Result<()> {
+    db.put_cf(
+        &DBColumnFamilies::Meta.cf_db(db),
+        HEARTBEAT_KEY,
+        clock.now_millis().to_be_bytes(),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lag {
+    /// Latest sequence number applied by the secondary.
+    pub sequence: u64,
+    /// Age of the last heartbeat applied, `None` before the first one.
+    pub behind: Option<Duration>,
+}
+
+pub struct Secondary {
+    db: DB,
+    clock: Arc<dyn Clock>,
+}
+
+impl Secondary {
+    /// Opens every column family of the primary at `primary_path`, keeping the secondary's own
+    /// files in `secondary_path`.
+    pub fn open(primary_path: impl AsRef<Path>, secondary_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_clock(primary_path, secondary_path, Arc::new(SystemClock))
+    }
+
+    pub fn open_with_clock(
+        primary_path: impl AsRef<Path>,
+        secondary_path: impl AsRef<Path>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let mut opts = Options::default();
+        // Required by secondaries, which must be able to open any file of the primary.
+        opts.set_max_open_files(-1);
+        let cfs = DB::list_cf(&opts, &primary_path)?;
+        let db = DB::open_cf_as_secondary(&opts, primary_path, secondary_path, cfs)?;
+        Ok(Self { db, clock })
+    }
+
+    /// Reads go through this handle, writes fail.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// Applies what the primary committed since the last catch-up.
+    pub fn catch_up(&self) -> Result<Lag> {
+        self.db.try_catch_up_with_primary()?;
+        self.lag()
+    }
+
+    /// Lag as of the last catch-up.
+    pub fn lag(&self) -> Result<Lag> {
+        let meta = self
+            .db
+            .cf_handle(DBColumnFamilies::Meta.as_ref())
+            .ok_or_else(|| anyhow!("missing column family {}", DBColumnFamilies::Meta))?;
+        let heartbeat = self
+            .db
+            .get_cf(&meta, HEARTBEAT_KEY)?
+            .map(|value| -> Result<u64> {
+                let millis = value.try_into().map_err(|_| anyhow!("invalid heartbeat"))?;
+                Ok(u64::from_be_bytes(millis))
+            })
+            .transpose()?;
+        Ok(Lag {
+            sequence: self.db.latest_sequence_number(),
+            behind: heartbeat
+                .map(|at| Duration::from_millis(self.clock.now_millis().saturating_sub(at))),
+        })
+    }
+}
+
+/// Background task catching a [`Secondary`] up with its primary at a fixed interval.
+pub struct CatchUp {
+    stop: oneshot::Sender<()>,
+    lag: watch::Receiver<Option<Lag>>,
+    handle: JoinHandle<Result<usize>>,
+}
+
+impl CatchUp {
+    /// Spawns the task on the current tokio runtime, the first catch-up runs right away.
+    pub fn spawn(secondary: Arc<Secondary>, interval: Duration) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let (lag_tx, lag) = watch::channel(None);
+        let handle = tokio::spawn(catch_up_until_stopped(secondary, interval, lag_tx, stopped));
+        Self { stop, lag, handle }
+    }
+
+    /// Lag after the latest catch-up, `None` before the first one.
+    pub fn lag(&self) -> watch::Receiver<Option<Lag>> {
+        self.lag.clone()
+    }
+
+    /// Stops the task, returns how many catch-ups it made.
+    pub async fn stop(self) -> Result<usize> {
+        // The task is gone when it failed, its error is returned below.
+        let _ = self.stop.send(());
+        self.handle.await?
+    }
+}
+
+async fn catch_up_until_stopped(
+    secondary: Arc<Secondary>,
+    interval: Duration,
+    lag: watch::Sender<Option<Lag>>,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<usize> {
+    let mut ticks = tokio::time::interval(interval);
+    let mut catch_ups = 0;
+    loop {
+        tokio::select! {
+            _ = &mut stopped => return Ok(catch_ups),
+            _ = ticks.tick() => {
+                let latest = secondary.catch_up()?;
+                tracing::debug!("secondary caught up: {latest:?}");
+                lag.send_replace(Some(latest));
+                catch_ups += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        io::{BufRead, BufReader, Write},
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::{
+        test_utils::{open_transaction_db, temp_transaction_db},
+        ttl::ManualClock,
+    };
+
+    /// Environment variable giving [`writer_process`] the primary path.
+    const PRIMARY_ENV: &str = "SECONDARY_TEST_PRIMARY";
+
+    fn user1(secondary: &Secondary) -> Option<Vec<u8>> {
+        let cf = secondary
+            .db()
+            .cf_handle(DBColumnFamilies::User.as_ref())
+            .unwrap();
+        secondary.db().get_cf(&cf, b"user1").unwrap()
+    }
+
+    #[test]
+    fn catches_up_with_commits_only() {
+        let (dir, db) = temp_transaction_db();
+        let secondary_dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(1_000));
+        let cf = DBColumnFamilies::User.cf_db(&db);
+
+        db.put_cf(&cf, b"user1", b"v1").unwrap();
+        let secondary =
+            Secondary::open_with_clock(dir.path(), secondary_dir.path(), clock.clone()).unwrap();
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()));
+        assert_eq!(secondary.lag().unwrap().behind, None);
+
+        let txn = db.transaction();
+        txn.put_cf(&cf, b"user1", b"v2").unwrap();
+        beat(&db, clock.as_ref()).unwrap();
+        secondary.catch_up().unwrap();
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()), "not committed yet");
+
+        txn.commit().unwrap();
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()), "not caught up yet");
+        clock.advance(Duration::from_millis(250));
+        let lag = secondary.catch_up().unwrap();
+        assert_eq!(user1(&secondary), Some(b"v2".to_vec()));
+        assert_eq!(lag.behind, Some(Duration::from_millis(250)));
+
+        assert!(secondary
+            .db()
+            .put_cf(
+                &secondary
+                    .db()
+                    .cf_handle(DBColumnFamilies::User.as_ref())
+                    .unwrap(),
+                b"user1",
+                b"secondary"
+            )
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn background_catch_up_publishes_lag() {
+        let (dir, db) = temp_transaction_db();
+        let secondary_dir = tempfile::tempdir().unwrap();
+        let secondary = Arc::new(Secondary::open(dir.path(), secondary_dir.path()).unwrap());
+
+        let catch_up = CatchUp::spawn(secondary.clone(), Duration::from_millis(10));
+        let mut lag = catch_up.lag();
+        beat(&db, &SystemClock).unwrap();
+        db.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"v1")
+            .unwrap();
+
+        let caught_up = tokio::time::timeout(
+            Duration::from_secs(5),
+            lag.wait_for(|latest| latest.is_some_and(|latest| latest.behind.is_some())),
+        )
+        .await
+        .expect("secondary should catch up")
+        .unwrap()
+        .unwrap();
+        assert!(caught_up.sequence > 0);
+        assert!(catch_up.stop().await.unwrap() > 0);
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()));
+    }
+
+    /// Writer half of [`reader_process_observes_writer_process`], run in a child process.
+    #[test]
+    #[ignore = "run as a child process by reader_process_observes_writer_process"]
+    fn writer_process() {
+        let primary = env::var(PRIMARY_ENV).unwrap();
+        let db = open_transaction_db(&primary);
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let mut stdin = std::io::stdin().lines();
+
+        for value in ["v1", "v2"] {
+            let txn = db.transaction();
+            txn.put_cf(&cf, b"user1", value).unwrap();
+            txn.commit().unwrap();
+            beat(&db, &SystemClock).unwrap();
+            println!("committed {value}");
+            // Wait for the reader before going on, or exiting.
+            stdin.next().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn reader_process_observes_writer_process() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary_dir = tempfile::tempdir().unwrap();
+
+        let mut writer = Command::new(env::current_exe().unwrap())
+            .args([
+                "secondary::tests::writer_process",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env(PRIMARY_ENV, primary.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut to_writer = writer.stdin.take().unwrap();
+        let mut from_writer = BufReader::new(writer.stdout.take().unwrap()).lines();
+        let mut wait_for = |line: &str| {
+            from_writer
+                .by_ref()
+                .map(Result::unwrap)
+                // libtest may print the test name on the same line.
+                .find(|l| l.ends_with(line))
+                .unwrap_or_else(|| panic!("writer exited before {line:?}"));
+        };
+
+        wait_for("committed v1");
+        let secondary = Secondary::open(primary.path(), secondary_dir.path()).unwrap();
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()));
+        writeln!(to_writer).unwrap();
+
+        wait_for("committed v2");
+        assert_eq!(user1(&secondary), Some(b"v1".to_vec()), "not caught up yet");
+        let lag = secondary.catch_up().unwrap();
+        assert_eq!(user1(&secondary), Some(b"v2".to_vec()));
+        assert!(lag.behind.is_some());
+        writeln!(to_writer).unwrap();
+
+        assert!(writer.wait().unwrap().success());
+    }
+}
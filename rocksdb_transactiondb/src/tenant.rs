@@ -0,0 +1,303 @@
+//! One column family per tenant, created and dropped at runtime.
+//!
+//! With the `multi-threaded-cf` feature, `TransactionDB::create_cf` and `TransactionDB::drop_cf`
+//! take `&self`, so tenants can come and go through a shared `Arc<TransactionDB>` without
+//! reopening the database. [`TenantRegistry`] records every tenant column family in the
+//! [`DBColumnFamilies::Meta`] column family, and [`open_tenant_db`] uses that record to reopen
+//! the database with its tenants.
+//!
+//! A tenant is created by creating its column family and then registering it, and dropped by
+//! unregistering it and then dropping its column family. A crash in between leaves at worst an
+//! unregistered column family, which [`open_tenant_db`] drops on the next open.
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+use rocksdb::{
+    BoundColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, ReadOptions,
+    TransactionDB, TransactionDBOptions, DB,
+};
+use strum::IntoEnumIterator;
+
+use crate::{prefix_end, DBColumnFamilies};
+
+/// Prefix of the registry keys in the Meta column family, followed by the tenant name.
+const REGISTRY_PREFIX: &[u8] = b"tenant/";
+/// Prefix of tenant column family names, followed by the tenant name.
+const CF_PREFIX: &str = "tenant_";
+
+/// Name of the column family holding the data of `tenant`.
+pub fn tenant_cf_name(tenant: &str) -> String {
+    format!("{CF_PREFIX}{tenant}")
+}
+
+fn registry_key(tenant: &str) -> Vec<u8> {
+    [REGISTRY_PREFIX, tenant.as_bytes()].concat()
+}
+
+fn validate_tenant(tenant: &str) -> Result<()> {
+    if tenant.is_empty()
+        || !tenant
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        bail!("invalid tenant name {tenant:?}");
+    }
+    Ok(())
+}
+
+/// Tenants registered in the Meta column family, in name order.
+fn registered_tenants(db: &TransactionDB) -> Result<Vec<String>> {
+    let mut read_opts = ReadOptions::default();
+    if let Some(end) = prefix_end(REGISTRY_PREFIX) {
+        read_opts.set_iterate_upper_bound(end);
+    }
+    db.iterator_cf_opt(
+        &DBColumnFamilies::Meta.cf_db(db),
+        read_opts,
+        IteratorMode::From(REGISTRY_PREFIX, Direction::Forward),
+    )
+    .map(|item| -> Result<String> {
+        let (key, _) = item?;
+        Ok(String::from_utf8(key[REGISTRY_PREFIX.len()..].to_vec())?)
+    })
+    .collect()
+}
+
+/// Opens the `TransactionDB` at `path` with every `DBColumnFamilies` and every registered
+/// tenant column family, dropping tenant column families left unregistered by a crash.
+///
+/// `db_opts` should allow creating missing column families, so that the Meta column family
+/// is added to databases created before it existed.
+pub fn open_tenant_db(db_opts: &Options, path: impl AsRef<Path>) -> Result<TransactionDB> {
+    let path = path.as_ref();
+    // Listing fails when there is no database yet, which means there is no tenant either.
+    let tenant_cfs: Vec<String> = DB::list_cf(db_opts, path)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| name.starts_with(CF_PREFIX))
+        .collect();
+
+    let column_families = DBColumnFamilies::iter()
+        .map(|cf| cf.as_ref().to_string())
+        .chain(tenant_cfs.iter().cloned())
+        .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+    let db = TransactionDB::open_cf_descriptors(
+        db_opts,
+        &TransactionDBOptions::default(),
+        path,
+        column_families,
+    )?;
+
+    let registered: BTreeSet<String> = registered_tenants(&db)?
+        .iter()
+        .map(|tenant| tenant_cf_name(tenant))
+        .collect();
+    for name in tenant_cfs.iter().filter(|name| !registered.contains(*name)) {
+        tracing::warn!("dropping unregistered tenant column family {name}");
+        db.drop_cf(name)?;
+    }
+
+    Ok(db)
+}
+
+/// Creates and drops tenant column families on a shared database.
+pub struct TenantRegistry {
+    db: Arc<TransactionDB>,
+    /// Serializes creations and drops, so that the registry and the column families agree.
+    lifecycle: Mutex<()>,
+}
+
+impl TenantRegistry {
+    /// Manages the tenants of `db`, which should come from [`open_tenant_db`] so that the
+    /// registered tenants have their column family open.
+    pub fn new(db: Arc<TransactionDB>) -> Self {
+        Self {
+            db,
+            lifecycle: Mutex::new(()),
+        }
+    }
+
+    pub fn db(&self) -> &Arc<TransactionDB> {
+        &self.db
+    }
+
+    /// Registered tenants, in name order.
+    pub fn tenants(&self) -> Result<Vec<String>> {
+        registered_tenants(&self.db)
+    }
+
+    pub fn exists(&self, tenant: &str) -> Result<bool> {
+        Ok(self
+            .db
+            .get_cf(
+                &DBColumnFamilies::Meta.cf_db(&self.db),
+                registry_key(tenant),
+            )?
+            .is_some())
+    }
+
+    /// Creates the column family of `tenant` and registers it.
+    pub fn create(&self, tenant: &str) -> Result<()> {
+        validate_tenant(tenant)?;
+        let _lifecycle = self.lifecycle.lock().unwrap();
+        if self.exists(tenant)? {
+            bail!("tenant {tenant} already exists");
+        }
+
+        let name = tenant_cf_name(tenant);
+        self.db
+            .create_cf(&name, &Options::default())
+            .with_context(|| format!("failed to create column family {name}"))?;
+        self.db.put_cf(
+            &DBColumnFamilies::Meta.cf_db(&self.db),
+            registry_key(tenant),
+            name,
+        )?;
+        Ok(())
+    }
+
+    /// Unregisters `tenant` and drops its column family, returns whether it existed.
+    ///
+    /// The data is gone from reads right away, its files are deleted once the last
+    /// `BoundColumnFamily` handle of the tenant is released.
+    pub fn remove(&self, tenant: &str) -> Result<bool> {
+        let _lifecycle = self.lifecycle.lock().unwrap();
+        if !self.exists(tenant)? {
+            return Ok(false);
+        }
+
+        self.db.delete_cf(
+            &DBColumnFamilies::Meta.cf_db(&self.db),
+            registry_key(tenant),
+        )?;
+        let name = tenant_cf_name(tenant);
+        self.db
+            .drop_cf(&name)
+            .with_context(|| format!("failed to drop column family {name}"))?;
+        Ok(true)
+    }
+
+    /// Column family of `tenant`.
+    pub fn cf(&self, tenant: &str) -> Result<Arc<BoundColumnFamily<'_>>> {
+        self.db
+            .cf_handle(&tenant_cf_name(tenant))
+            .with_context(|| format!("unknown tenant {tenant}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_opts() -> Options {
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        db_opts
+    }
+
+    fn open(path: &Path) -> TenantRegistry {
+        TenantRegistry::new(Arc::new(open_tenant_db(&db_opts(), path).unwrap()))
+    }
+
+    #[test]
+    fn create_and_drop_without_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = open(dir.path());
+
+        registry.create("acme").unwrap();
+        registry.create("globex").unwrap();
+        registry
+            .db()
+            .put_cf(&registry.cf("acme").unwrap(), b"k", b"acme")
+            .unwrap();
+        registry
+            .db()
+            .put_cf(&registry.cf("globex").unwrap(), b"k", b"globex")
+            .unwrap();
+        assert_eq!(registry.tenants().unwrap(), vec!["acme", "globex"]);
+        assert_eq!(
+            registry
+                .db()
+                .get_cf(&registry.cf("acme").unwrap(), b"k")
+                .unwrap(),
+            Some(b"acme".to_vec())
+        );
+
+        assert!(registry.remove("acme").unwrap());
+        assert!(!registry.remove("acme").unwrap());
+        assert!(registry.cf("acme").is_err());
+        assert_eq!(registry.tenants().unwrap(), vec!["globex"]);
+
+        // A dropped tenant can be created again, empty.
+        registry.create("acme").unwrap();
+        assert_eq!(
+            registry
+                .db()
+                .get_cf(&registry.cf("acme").unwrap(), b"k")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn create_rejects_duplicates_and_invalid_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = open(dir.path());
+
+        registry.create("acme").unwrap();
+        assert_eq!(
+            registry.create("acme").unwrap_err().to_string(),
+            "tenant acme already exists"
+        );
+        assert!(registry.create("").is_err());
+        assert!(registry.create("a/b").is_err());
+    }
+
+    #[test]
+    fn tenants_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let registry = open(dir.path());
+            registry.create("acme").unwrap();
+            registry.create("globex").unwrap();
+            registry.remove("globex").unwrap();
+            registry
+                .db()
+                .put_cf(&registry.cf("acme").unwrap(), b"k", b"v")
+                .unwrap();
+        }
+
+        let registry = open(dir.path());
+        assert_eq!(registry.tenants().unwrap(), vec!["acme"]);
+        assert_eq!(
+            registry
+                .db()
+                .get_cf(&registry.cf("acme").unwrap(), b"k")
+                .unwrap(),
+            Some(b"v".to_vec())
+        );
+        assert!(registry.cf("globex").is_err());
+    }
+
+    #[test]
+    fn unregistered_column_family_is_dropped_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let registry = open(dir.path());
+            // A crash between creating the column family and registering it.
+            registry
+                .db()
+                .create_cf(tenant_cf_name("orphan"), &Options::default())
+                .unwrap();
+        }
+
+        open(dir.path());
+        let names = DB::list_cf(&db_opts(), dir.path()).unwrap();
+        assert!(!names.contains(&tenant_cf_name("orphan")));
+    }
+}
@@ -0,0 +1,450 @@
+//! Expiring records: a marker and an expiry timestamp in front of every value.
+//!
+//! Values without the marker are plain values and never expire, so expiring and plain
+//! records can share a column family. A plain value that happens to start with the marker
+//! would be misread, the marker is made of bytes unlikely at the start of a value.
+//!
+//! Expired records disappear in three steps:
+//! - [`Ttl::get_cf`] hides them as soon as the [`Clock`] passes their expiry;
+//! - [`Sweeper`] deletes them in the background, each in a transaction that checks again that
+//!   the record is still expired, so a record refreshed in the meantime is kept;
+//! - the [`Ttl::compaction_filter`] drops what is left when RocksDB compacts the files holding
+//!   them. Compaction filters do not run on flush, and the binding only exposes manual
+//!   compaction on `OptimisticTransactionDB`.
+//!
+//! Time comes from a [`Clock`] so that tests can move it with a [`ManualClock`].
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use rocksdb::{
+    compaction_filter::Decision, AsColumnFamilyRef, IteratorMode, Transaction, TransactionDB,
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// Expiry stored for records that never expire.
+const NEVER: u64 = u64::MAX;
+/// Marks values written by [`encode`], its last byte is the version of the format.
+const MAGIC: &[u8] = b"\xffttl\x01";
+/// Length of the marker and the expiry.
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+/// Milliseconds since the Unix epoch.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        u64::try_from(since_epoch.as_millis()).unwrap_or(NEVER)
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now_millis: u64) -> Self {
+        Self(AtomicU64::new(now_millis))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(
+            u64::try_from(by.as_millis()).unwrap_or(NEVER),
+            Ordering::SeqCst,
+        );
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Prefixes `value` with the marker and its expiry, `None` never expires.
+pub fn encode(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(HEADER_LEN + value.len());
+    raw.extend_from_slice(MAGIC);
+    raw.extend_from_slice(&expires_at.unwrap_or(NEVER).to_be_bytes());
+    raw.extend_from_slice(value);
+    raw
+}
+
+/// Splits a value written by [`encode`] into its expiry and the user value. Values without
+/// the marker are returned whole, without expiry. Fails on a marker without a full expiry.
+pub fn decode(raw: &[u8]) -> Result<(Option<u64>, &[u8])> {
+    let Some(rest) = raw.strip_prefix(MAGIC) else {
+        return Ok((None, raw));
+    };
+    let Some((expires_at, value)) = rest.split_first_chunk::<8>() else {
+        bail!("value of {} bytes has a truncated expiry header", raw.len());
+    };
+    let expires_at = u64::from_be_bytes(*expires_at);
+    Ok(((expires_at != NEVER).then_some(expires_at), value))
+}
+
+/// Whether a value is expired at `now_millis`, values without expiry never are.
+pub fn is_expired(raw: &[u8], now_millis: u64) -> Result<bool> {
+    let (expires_at, _) = decode(raw)?;
+    Ok(expires_at.is_some_and(|expires_at| expires_at <= now_millis))
+}
+
+/// Reads and writes expiring records through transactions.
+#[derive(Clone)]
+pub struct Ttl {
+    clock: Arc<dyn Clock>,
+}
+
+impl Ttl {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Writes `value` expiring `ttl` from now, `None` never expires.
+    pub fn put_cf<DB>(
+        &self,
+        txn: &Transaction<'_, DB>,
+        cf: &impl AsColumnFamilyRef,
+        key: impl AsRef<[u8]>,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let expires_at = ttl.map(|ttl| {
+            self.clock
+                .now_millis()
+                .saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(NEVER))
+        });
+        txn.put_cf(cf, key, encode(value, expires_at))?;
+        Ok(())
+    }
+
+    /// Reads `key`, hiding the record once expired.
+    pub fn get_cf<DB>(
+        &self,
+        txn: &Transaction<'_, DB>,
+        cf: &impl AsColumnFamilyRef,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(raw) = txn.get_cf(cf, key)? else {
+            return Ok(None);
+        };
+        if is_expired(&raw, self.clock.now_millis())? {
+            return Ok(None);
+        }
+        Ok(Some(decode(&raw)?.1.to_vec()))
+    }
+
+    /// Compaction filter dropping expired records, to install with
+    /// `Options::set_compaction_filter` on the column families holding them. Values without
+    /// an expiry header, or with a truncated one, are kept.
+    pub fn compaction_filter(&self) -> impl FnMut(u32, &[u8], &[u8]) -> Decision + Send + 'static {
+        let clock = self.clock.clone();
+        move |_level, _key, value| match is_expired(value, clock.now_millis()) {
+            Ok(true) => Decision::Remove,
+            Ok(false) | Err(_) => Decision::Keep,
+        }
+    }
+
+    /// Deletes the expired records of column family `cf`, returns how many were deleted.
+    ///
+    /// Expired keys are collected from a scan, then every one of them is deleted in its own
+    /// transaction after checking under lock that it is still expired. Values that can't be
+    /// decoded are logged and kept. The scan blocks, run it off the async workers.
+    pub fn sweep(&self, db: &TransactionDB, cf: &str) -> Result<usize> {
+        let handle = db
+            .cf_handle(cf)
+            .with_context(|| format!("unknown column family {cf}"))?;
+        let now = self.clock.now_millis();
+        let mut expired = Vec::new();
+        for item in db.iterator_cf(&handle, IteratorMode::Start) {
+            let (key, raw) = item?;
+            match is_expired(&raw, now) {
+                Ok(true) => expired.push(key),
+                Ok(false) => {}
+                Err(err) => {
+                    tracing::warn!("not sweeping {cf}/{}: {err}", String::from_utf8_lossy(&key))
+                }
+            }
+        }
+
+        let mut deleted = 0;
+        for key in expired {
+            let txn = db.transaction();
+            let still_expired = match txn.get_for_update_cf(&handle, &key, true)? {
+                Some(raw) => is_expired(&raw, self.clock.now_millis()).unwrap_or(false),
+                None => false,
+            };
+            if still_expired {
+                txn.delete_cf(&handle, &key)?;
+                deleted += 1;
+            }
+            txn.commit()?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Background task running [`Ttl::sweep`] on a column family at a fixed interval.
+pub struct Sweeper {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Result<usize>>,
+}
+
+impl Sweeper {
+    /// Spawns the sweeper on the current tokio runtime, the first sweep runs right away.
+    pub fn spawn(
+        db: Arc<TransactionDB>,
+        ttl: Ttl,
+        cf: impl Into<String>,
+        interval: Duration,
+    ) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let handle = tokio::spawn(sweep_until_stopped(db, ttl, cf.into(), interval, stopped));
+        Self { stop, handle }
+    }
+
+    /// Stops the sweeper, returns how many records it deleted.
+    pub async fn stop(self) -> Result<usize> {
+        // The task is gone when a sweep panicked, its error is returned below.
+        let _ = self.stop.send(());
+        self.handle.await?
+    }
+}
+
+async fn sweep_until_stopped(
+    db: Arc<TransactionDB>,
+    ttl: Ttl,
+    cf: String,
+    interval: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<usize> {
+    let mut ticks = tokio::time::interval(interval);
+    let mut deleted = 0;
+    loop {
+        tokio::select! {
+            _ = &mut stopped => return Ok(deleted),
+            _ = ticks.tick() => {
+                // The scan reads the whole column family, keep it off the async workers.
+                let sweep = tokio::task::spawn_blocking({
+                    let (db, ttl, cf) = (db.clone(), ttl.clone(), cf.clone());
+                    move || ttl.sweep(&db, &cf)
+                });
+                // A failed sweep, a lock timeout for instance, is retried at the next tick.
+                match sweep.await? {
+                    Ok(swept) => {
+                        if swept > 0 {
+                            tracing::debug!("swept {swept} expired records from {cf}");
+                        }
+                        deleted += swept;
+                    }
+                    Err(err) => tracing::warn!("failed to sweep {cf}: {err:#}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::{ColumnFamilyDescriptor, OptimisticTransactionDB, Options};
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::{test_utils::temp_transaction_db, DBColumnFamilies};
+
+    fn ttl() -> (Arc<ManualClock>, Ttl) {
+        let clock = Arc::new(ManualClock::new(1_000));
+        (clock.clone(), Ttl::new(clock))
+    }
+
+    #[test]
+    fn codec_roundtrip() {
+        assert_eq!(
+            decode(&encode(b"v", Some(42))).unwrap(),
+            (Some(42), &b"v"[..])
+        );
+        assert_eq!(decode(&encode(b"", None)).unwrap(), (None, &b""[..]));
+        assert_eq!(decode(b"short").unwrap(), (None, &b"short"[..]));
+        assert!(decode(b"\xffttl\x01\0\0").is_err());
+
+        assert!(!is_expired(&encode(b"v", Some(42)), 41).unwrap());
+        assert!(is_expired(&encode(b"v", Some(42)), 42).unwrap());
+        assert!(!is_expired(&encode(b"v", None), u64::MAX - 1).unwrap());
+        // Read as an expiry of 1 without the marker.
+        assert!(!is_expired(b"\0\0\0\0\0\0\0\x01plain", 42).unwrap());
+    }
+
+    #[test]
+    fn reads_hide_expired_records() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let (clock, ttl) = ttl();
+
+        let txn = db.transaction();
+        ttl.put_cf(&txn, &cf, b"session", b"s", Some(Duration::from_secs(10)))
+            .unwrap();
+        ttl.put_cf(&txn, &cf, b"user", b"u", None).unwrap();
+        txn.commit().unwrap();
+
+        clock.advance(Duration::from_secs(9));
+        let txn = db.transaction();
+        assert_eq!(
+            ttl.get_cf(&txn, &cf, b"session").unwrap(),
+            Some(b"s".to_vec())
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(ttl.get_cf(&txn, &cf, b"session").unwrap(), None);
+        assert_eq!(ttl.get_cf(&txn, &cf, b"user").unwrap(), Some(b"u".to_vec()));
+        assert!(
+            db.get_cf(&cf, b"session").unwrap().is_some(),
+            "hidden, not deleted"
+        );
+    }
+
+    #[test]
+    fn sweep_deletes_expired_records_only() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let (clock, ttl) = ttl();
+
+        let txn = db.transaction();
+        ttl.put_cf(&txn, &cf, b"a", b"a", Some(Duration::from_secs(1)))
+            .unwrap();
+        ttl.put_cf(&txn, &cf, b"b", b"b", Some(Duration::from_secs(5)))
+            .unwrap();
+        ttl.put_cf(&txn, &cf, b"c", b"c", None).unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(ttl.sweep(&db, "User").unwrap(), 0);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(ttl.sweep(&db, "User").unwrap(), 1);
+        assert_eq!(db.get_cf(&cf, b"a").unwrap(), None);
+        assert!(db.get_cf(&cf, b"b").unwrap().is_some());
+        assert!(db.get_cf(&cf, b"c").unwrap().is_some());
+    }
+
+    #[test]
+    fn sweep_keeps_plain_and_undecodable_values() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let (clock, ttl) = ttl();
+
+        let txn = db.transaction();
+        ttl.put_cf(&txn, &cf, b"a", b"a", Some(Duration::from_secs(1)))
+            .unwrap();
+        txn.commit().unwrap();
+        db.put_cf(&cf, b"plain", b"\0\0\0\0\0\0\0\x01plain")
+            .unwrap();
+        db.put_cf(&cf, b"short", b"x").unwrap();
+        db.put_cf(&cf, b"truncated", b"\xffttl\x01\0").unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(ttl.sweep(&db, "User").unwrap(), 1);
+        assert_eq!(db.get_cf(&cf, b"a").unwrap(), None);
+        for key in [&b"plain"[..], b"short", b"truncated"] {
+            assert!(db.get_cf(&cf, key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn compaction_drops_expired_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let (clock, ttl) = ttl();
+        let mut user_opts = Options::default();
+        user_opts.set_compaction_filter("ttl", ttl.compaction_filter());
+        let column_families = DBColumnFamilies::iter().map(|cf| {
+            let opts = match cf {
+                DBColumnFamilies::User => user_opts.clone(),
+                _ => Options::default(),
+            };
+            ColumnFamilyDescriptor::new(cf.as_ref(), opts)
+        });
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        let db =
+            OptimisticTransactionDB::open_cf_descriptors(&db_opts, dir.path(), column_families)
+                .unwrap();
+        let cf = DBColumnFamilies::User.cf(&db);
+
+        let txn = db.transaction();
+        ttl.put_cf(&txn, &cf, b"a", b"a", Some(Duration::from_secs(1)))
+            .unwrap();
+        ttl.put_cf(&txn, &cf, b"b", b"b", None).unwrap();
+        txn.put_cf(&cf, b"plain", b"\0\0\0\0\0\0\0\x01plain")
+            .unwrap();
+        txn.commit().unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+
+        assert_eq!(db.get_cf(&cf, b"a").unwrap(), None);
+        assert!(db.get_cf(&cf, b"b").unwrap().is_some());
+        let txn = db.transaction();
+        assert_eq!(
+            ttl.get_cf(&txn, &cf, b"plain").unwrap().as_deref(),
+            Some(&b"\0\0\0\0\0\0\0\x01plain"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn sweeper_deletes_in_background() {
+        let (_dir, db) = temp_transaction_db();
+        let db = Arc::new(db);
+        let (clock, ttl) = ttl();
+
+        let txn = db.transaction();
+        ttl.put_cf(
+            &txn,
+            &DBColumnFamilies::User.cf_db(&db),
+            b"a",
+            b"a",
+            Some(Duration::from_secs(1)),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+        // Neither stops the sweeper.
+        db.put_cf(&DBColumnFamilies::User.cf_db(&db), b"short", b"x")
+            .unwrap();
+        db.put_cf(
+            &DBColumnFamilies::User.cf_db(&db),
+            b"truncated",
+            b"\xffttl\x01",
+        )
+        .unwrap();
+
+        let sweeper = Sweeper::spawn(db.clone(), ttl, "User", Duration::from_millis(5));
+        clock.advance(Duration::from_secs(2));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while db
+                .get_cf(&DBColumnFamilies::User.cf_db(&db), b"a")
+                .unwrap()
+                .is_some()
+            {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("the sweeper did not delete the expired record");
+
+        assert_eq!(sweeper.stop().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,267 @@
+//! Transactions that are always committed or rolled back on purpose.
+//!
+//! Dropping a `Transaction` destroys it, which discards its writes and releases its locks, but
+//! says nothing about it: a scenario that forgets to commit and an async task cancelled while
+//! holding a transaction (aborted, or losing a `select!` or a timeout) look exactly like an
+//! explicit rollback. [`TxnGuard`] rolls back explicitly on drop instead, counts the leak and
+//! logs a warning with the place where the transaction was created. Test suites can make
+//! leaks fatal with [`set_panic_on_leak`].
+use std::{
+    ops::Deref,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+};
+
+use rocksdb::{OptimisticTransactionDB, Transaction, TransactionDB};
+
+static LEAKED: AtomicU64 = AtomicU64::new(0);
+static PANIC_ON_LEAK: AtomicBool = AtomicBool::new(false);
+
+/// Number of [`TxnGuard`]s dropped without commit or rollback since the process started.
+pub fn leaked_transactions() -> u64 {
+    LEAKED.load(Ordering::Relaxed)
+}
+
+/// Makes guards created from now on panic when they leak, unless they override it with
+/// [`TxnGuard::panic_on_leak`]. Nothing panics while the thread is already panicking.
+pub fn set_panic_on_leak(enabled: bool) {
+    PANIC_ON_LEAK.store(enabled, Ordering::Relaxed);
+}
+
+/// Transaction rolled back and reported when dropped without commit or rollback.
+///
+/// Dereferences to the transaction for reads and writes.
+pub struct TxnGuard<'db, DB> {
+    /// `None` once committed or rolled back.
+    txn: Option<Transaction<'db, DB>>,
+    created_at: &'static Location<'static>,
+    panic_on_leak: bool,
+}
+
+impl<'db, DB> TxnGuard<'db, DB> {
+    /// Guards `txn`, the caller is reported as its creation site.
+    #[track_caller]
+    pub fn new(txn: Transaction<'db, DB>) -> Self {
+        Self {
+            txn: Some(txn),
+            created_at: Location::caller(),
+            panic_on_leak: PANIC_ON_LEAK.load(Ordering::Relaxed),
+        }
+    }
+
+    #[must_use]
+    pub fn panic_on_leak(mut self, enabled: bool) -> Self {
+        self.panic_on_leak = enabled;
+        self
+    }
+
+    pub fn created_at(&self) -> &'static Location<'static> {
+        self.created_at
+    }
+
+    pub fn commit(mut self) -> Result<(), rocksdb::Error> {
+        self.take().commit()
+    }
+
+    pub fn rollback(mut self) -> Result<(), rocksdb::Error> {
+        self.take().rollback()
+    }
+
+    fn take(&mut self) -> Transaction<'db, DB> {
+        self.txn
+            .take()
+            .expect("transaction is only taken when consuming the guard")
+    }
+}
+
+impl<'db, DB> Deref for TxnGuard<'db, DB> {
+    type Target = Transaction<'db, DB>;
+
+    fn deref(&self) -> &Self::Target {
+        self.txn
+            .as_ref()
+            .expect("transaction is only taken when consuming the guard")
+    }
+}
+
+impl<DB> Drop for TxnGuard<'_, DB> {
+    fn drop(&mut self) {
+        let Some(txn) = self.txn.take() else {
+            return;
+        };
+        LEAKED.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = txn.rollback() {
+            tracing::warn!("failed to roll back leaked transaction: {err}");
+        }
+        drop(txn);
+
+        let message = format!(
+            "transaction created at {} dropped without commit or rollback, rolled back",
+            self.created_at
+        );
+        if self.panic_on_leak && !thread::panicking() {
+            panic!("{message}");
+        }
+        tracing::warn!("{message}");
+    }
+}
+
+/// Guarded counterpart of `transaction()`.
+pub trait GuardedTransactions: Sized {
+    #[track_caller]
+    fn guarded_transaction(&self) -> TxnGuard<'_, Self>;
+}
+
+impl GuardedTransactions for TransactionDB {
+    #[track_caller]
+    fn guarded_transaction(&self) -> TxnGuard<'_, Self> {
+        TxnGuard::new(self.transaction())
+    }
+}
+
+impl GuardedTransactions for OptimisticTransactionDB {
+    #[track_caller]
+    fn guarded_transaction(&self) -> TxnGuard<'_, Self> {
+        TxnGuard::new(self.transaction())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use rocksdb::WriteOptions;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::{
+        model::no_wait_txn_opts,
+        test_utils::{temp_optimistic_db, temp_transaction_db},
+        DBColumnFamilies,
+    };
+
+    fn assert_unlocked(db: &TransactionDB) {
+        let txn = db.transaction_opt(&WriteOptions::default(), &no_wait_txn_opts());
+        txn.put_cf(&DBColumnFamilies::User.cf_db(db), b"user1", b"next")
+            .expect("user1 should not be locked");
+    }
+
+    #[test]
+    fn commit_and_rollback_are_not_leaks() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+
+        let txn = db.guarded_transaction().panic_on_leak(true);
+        txn.put_cf(&cf, b"user1", b"1").unwrap();
+        txn.commit().unwrap();
+
+        let txn = db.guarded_transaction().panic_on_leak(true);
+        txn.put_cf(&cf, b"user1", b"2").unwrap();
+        txn.rollback().unwrap();
+
+        assert_eq!(db.get_cf(&cf, b"user1").unwrap(), Some(b"1".to_vec()));
+        assert_unlocked(&db);
+
+        let (_dir, db) = temp_optimistic_db();
+        let txn = db.guarded_transaction().panic_on_leak(true);
+        txn.put_cf(&DBColumnFamilies::User.cf(&db), b"user1", b"1")
+            .unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn leak_is_rolled_back_and_counted() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+        let leaked = leaked_transactions();
+
+        let txn = db.guarded_transaction().panic_on_leak(false);
+        let line = line!() - 1;
+        assert_eq!(txn.created_at().file(), file!());
+        assert_eq!(txn.created_at().line(), line);
+        txn.put_cf(&cf, b"user1", b"1").unwrap();
+        drop(txn);
+
+        // Other tests may leak concurrently.
+        assert!(leaked_transactions() > leaked);
+        assert_eq!(db.get_cf(&cf, b"user1").unwrap(), None);
+        assert_unlocked(&db);
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped without commit or rollback")]
+    fn panics_on_leak_when_asked() {
+        let (_dir, db) = temp_transaction_db();
+        let _txn = db.guarded_transaction().panic_on_leak(true);
+    }
+
+    #[test]
+    fn does_not_panic_while_panicking() {
+        let (_dir, db) = temp_transaction_db();
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let txn = db.guarded_transaction().panic_on_leak(true);
+            txn.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"1")
+                .unwrap();
+            panic!("scenario failed");
+        }));
+
+        assert_eq!(
+            *res.unwrap_err().downcast::<&str>().unwrap(),
+            "scenario failed"
+        );
+        assert_unlocked(&db);
+    }
+
+    #[tokio::test]
+    async fn aborted_task_rolls_back() {
+        let (_dir, db) = temp_transaction_db();
+        let db = Arc::new(db);
+        let leaked = leaked_transactions();
+
+        let (locked, on_locked) = oneshot::channel();
+        let db_task = db.clone();
+        let handle = tokio::spawn(async move {
+            let txn = db_task.guarded_transaction().panic_on_leak(false);
+            txn.put_cf(&DBColumnFamilies::User.cf_db(&db_task), b"user1", b"1")
+                .unwrap();
+            locked.send(()).unwrap();
+            std::future::pending::<()>().await;
+            txn.commit().unwrap();
+        });
+
+        on_locked.await.unwrap();
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+
+        assert!(leaked_transactions() > leaked);
+        assert_eq!(
+            db.get_cf(&DBColumnFamilies::User.cf_db(&db), b"user1")
+                .unwrap(),
+            None
+        );
+        assert_unlocked(&db);
+    }
+
+    #[tokio::test]
+    async fn timed_out_future_rolls_back() {
+        let (_dir, db) = temp_transaction_db();
+
+        let res = tokio::time::timeout(Duration::from_millis(10), async {
+            let txn = db.guarded_transaction().panic_on_leak(false);
+            txn.put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"1")
+                .unwrap();
+            std::future::pending::<()>().await;
+            txn.commit().unwrap();
+        })
+        .await;
+
+        assert!(res.is_err());
+        assert_unlocked(&db);
+    }
+}
@@ -0,0 +1,483 @@
+//! In-process registry of open transactions and a reaper for the stuck ones.
+//!
+//! A `TransactionDB` transaction keeps its key locks until it is committed, rolled back or
+//! dropped, so a crashed task or a forgotten transaction blocks every writer of those keys.
+//! RocksDB's own answer is [`expiring_txn_opts`]: once a transaction has expired, the next
+//! transaction waiting for one of its locks takes it over, and committing the expired
+//! transaction fails with `ErrorKind::Expired`. Nothing happens until someone waits though,
+//! and the expiration has to be chosen when the transaction begins.
+//!
+//! [`TxnRegistry::begin`] returns a [`TrackedTxn`] whose transaction is shared with the
+//! registry, which records its owner, age and the keys it locked. [`TxnRegistry::reap`] rolls
+//! back and drops the transactions older than a limit, releasing their locks right away; the
+//! owner's next call fails with [`Reaped`]. Transactions in the middle of an operation are
+//! skipped until the operation returns.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use rocksdb::{BoundColumnFamily, Transaction, TransactionDB, TransactionOptions, WriteOptions};
+
+/// Options for transactions whose locks can be taken over by other transactions once
+/// `expiration` has elapsed since they began.
+pub fn expiring_txn_opts(expiration: Duration) -> TransactionOptions {
+    let mut txn_opts = TransactionOptions::default();
+    txn_opts.set_expiration(i64::try_from(expiration.as_millis()).unwrap_or(i64::MAX));
+    txn_opts
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TxnId(u64);
+
+impl fmt::Display for TxnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// What the registry knows about an open transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxnInfo {
+    pub id: TxnId,
+    pub owner: String,
+    pub started: Instant,
+    /// `(column family, key)` locked by a write or a `get_for_update_cf`.
+    pub keys: BTreeSet<(String, Vec<u8>)>,
+}
+
+impl TxnInfo {
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl fmt::Display for TxnInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transaction {} of {}, open for {:?}, locking",
+            self.id,
+            self.owner,
+            self.age()
+        )?;
+        if self.keys.is_empty() {
+            write!(f, " nothing")?;
+        }
+        for (cf, key) in &self.keys {
+            write!(f, " {cf}/{}", String::from_utf8_lossy(key))?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by a [`TrackedTxn`] rolled back by the reaper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reaped {
+    pub id: TxnId,
+    pub owner: String,
+}
+
+impl fmt::Display for Reaped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transaction {} of {} was rolled back by the reaper",
+            self.id, self.owner
+        )
+    }
+}
+
+impl std::error::Error for Reaped {}
+
+/// `None` once committed, rolled back or reaped.
+type Slot<'db> = Arc<Mutex<Option<Transaction<'db, TransactionDB>>>>;
+
+struct Entry<'db> {
+    info: TxnInfo,
+    txn: Slot<'db>,
+}
+
+pub struct TxnRegistry<'db> {
+    db: &'db TransactionDB,
+    next_id: AtomicU64,
+    active: Mutex<HashMap<TxnId, Entry<'db>>>,
+}
+
+impl<'db> TxnRegistry<'db> {
+    pub fn new(db: &'db TransactionDB) -> Self {
+        Self {
+            db,
+            next_id: AtomicU64::new(0),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Begins a transaction on behalf of `owner`, a task or request name shown when reaped.
+    pub fn begin(&self, owner: impl Into<String>) -> TrackedTxn<'_, 'db> {
+        self.begin_opt(
+            owner,
+            &WriteOptions::default(),
+            &TransactionOptions::default(),
+        )
+    }
+
+    pub fn begin_opt(
+        &self,
+        owner: impl Into<String>,
+        write_opts: &WriteOptions,
+        txn_opts: &TransactionOptions,
+    ) -> TrackedTxn<'_, 'db> {
+        let id = TxnId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let owner = owner.into();
+        let txn = Arc::new(Mutex::new(Some(
+            self.db.transaction_opt(write_opts, txn_opts),
+        )));
+        let info = TxnInfo {
+            id,
+            owner: owner.clone(),
+            started: Instant::now(),
+            keys: BTreeSet::new(),
+        };
+        self.active.lock().unwrap().insert(
+            id,
+            Entry {
+                info,
+                txn: txn.clone(),
+            },
+        );
+        TrackedTxn {
+            registry: self,
+            id,
+            owner,
+            txn,
+        }
+    }
+
+    /// Open transactions, oldest first.
+    pub fn active(&self) -> Vec<TxnInfo> {
+        let mut active: Vec<_> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect();
+        active.sort_by_key(|info| info.id);
+        active
+    }
+
+    /// Rolls back and drops every transaction open for at least `max_age`, returns them.
+    pub fn reap(&self, max_age: Duration) -> Vec<TxnInfo> {
+        // Rollbacks happen with the registry released, so other owners can begin, record keys
+        // and finish meanwhile.
+        let stale: Vec<_> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.info.age() >= max_age)
+            .map(|entry| (entry.info.id, entry.txn.clone()))
+            .collect();
+
+        let mut reaped = Vec::new();
+        for (id, slot) in stale {
+            // Busy in an operation, left for the next call.
+            let Ok(mut slot) = slot.try_lock() else {
+                continue;
+            };
+            // Committed or rolled back in the meantime.
+            let Some(txn) = slot.take() else {
+                continue;
+            };
+            if let Err(err) = txn.rollback() {
+                tracing::warn!("failed to roll back transaction {id}: {err}");
+            }
+            drop(txn);
+            if let Some(entry) = self.active.lock().unwrap().remove(&id) {
+                tracing::warn!("reaped {}", entry.info);
+                reaped.push(entry.info);
+            }
+        }
+        reaped.sort_by_key(|info| info.id);
+        reaped
+    }
+
+    /// Calls [`reap`](Self::reap) every `interval` until `stop` receives a message or is
+    /// disconnected, returns the number of transactions reaped. Meant to run on its own
+    /// thread, in a `std::thread::scope` next to the transactions it watches.
+    pub fn reap_every(
+        &self,
+        max_age: Duration,
+        interval: Duration,
+        stop: &mpsc::Receiver<()>,
+    ) -> usize {
+        let mut reaped = 0;
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+            reaped += self.reap(max_age).len();
+        }
+        reaped
+    }
+
+    fn record_key(&self, id: TxnId, cf: &str, key: &[u8]) {
+        if let Some(entry) = self.active.lock().unwrap().get_mut(&id) {
+            entry.info.keys.insert((cf.to_string(), key.to_vec()));
+        }
+    }
+}
+
+/// Transaction registered in a [`TxnRegistry`], deregistered when dropped.
+///
+/// Column families are named rather than passed as handles, so the registry can report them.
+pub struct TrackedTxn<'r, 'db> {
+    registry: &'r TxnRegistry<'db>,
+    id: TxnId,
+    owner: String,
+    txn: Slot<'db>,
+}
+
+impl<'db> TrackedTxn<'_, 'db> {
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    pub fn get_cf(&self, cf: impl AsRef<str>, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let cf = self.cf(cf.as_ref())?;
+        self.with_txn(|txn| txn.get_cf(&cf, key))
+    }
+
+    pub fn get_for_update_cf(
+        &self,
+        cf: impl AsRef<str>,
+        key: impl AsRef<[u8]>,
+        exclusive: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let handle = self.cf(cf.as_ref())?;
+        let value = self.with_txn(|txn| txn.get_for_update_cf(&handle, &key, exclusive))?;
+        self.registry.record_key(self.id, cf.as_ref(), key.as_ref());
+        Ok(value)
+    }
+
+    pub fn put_cf(
+        &self,
+        cf: impl AsRef<str>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        let handle = self.cf(cf.as_ref())?;
+        self.with_txn(|txn| txn.put_cf(&handle, &key, value))?;
+        self.registry.record_key(self.id, cf.as_ref(), key.as_ref());
+        Ok(())
+    }
+
+    pub fn delete_cf(&self, cf: impl AsRef<str>, key: impl AsRef<[u8]>) -> Result<()> {
+        let handle = self.cf(cf.as_ref())?;
+        self.with_txn(|txn| txn.delete_cf(&handle, &key))?;
+        self.registry.record_key(self.id, cf.as_ref(), key.as_ref());
+        Ok(())
+    }
+
+    pub fn commit(self) -> Result<()> {
+        let txn = self.take()?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn rollback(self) -> Result<()> {
+        let txn = self.take()?;
+        txn.rollback()?;
+        Ok(())
+    }
+
+    fn take(&self) -> Result<Transaction<'db, TransactionDB>> {
+        self.txn
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| self.reaped().into())
+    }
+
+    fn with_txn<T>(
+        &self,
+        f: impl FnOnce(&Transaction<'db, TransactionDB>) -> Result<T, rocksdb::Error>,
+    ) -> Result<T> {
+        let txn = self.txn.lock().unwrap();
+        let txn = txn.as_ref().ok_or_else(|| self.reaped())?;
+        Ok(f(txn)?)
+    }
+
+    fn cf(&self, name: &str) -> Result<Arc<BoundColumnFamily<'db>>> {
+        self.registry
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("unknown column family {name}"))
+    }
+
+    fn reaped(&self) -> Reaped {
+        Reaped {
+            id: self.id,
+            owner: self.owner.clone(),
+        }
+    }
+}
+
+impl Drop for TrackedTxn<'_, '_> {
+    fn drop(&mut self) {
+        self.registry.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rocksdb::ErrorKind;
+
+    use super::*;
+    use crate::{model::no_wait_txn_opts, test_utils::temp_transaction_db, DBColumnFamilies};
+
+    fn key(cf: &DBColumnFamilies, key: &[u8]) -> (String, Vec<u8>) {
+        (cf.to_string(), key.to_vec())
+    }
+
+    #[test]
+    fn tracks_owner_and_locked_keys() {
+        let (_dir, db) = temp_transaction_db();
+        let registry = TxnRegistry::new(&db);
+
+        let txn = registry.begin("checkout");
+        txn.put_cf(DBColumnFamilies::User, b"user1", b"1").unwrap();
+        txn.get_for_update_cf(DBColumnFamilies::User, b"user2", true)
+            .unwrap();
+        txn.get_cf(DBColumnFamilies::User, b"user3").unwrap();
+        assert!(txn.put_cf("missing", b"user1", b"1").is_err());
+
+        let active = registry.active();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, txn.id());
+        assert_eq!(active[0].owner, "checkout");
+        assert_eq!(
+            active[0].keys,
+            BTreeSet::from([
+                key(&DBColumnFamilies::User, b"user1"),
+                key(&DBColumnFamilies::User, b"user2"),
+            ])
+        );
+
+        txn.commit().unwrap();
+        assert!(registry.active().is_empty());
+
+        drop(registry.begin("dropped"));
+        assert!(registry.active().is_empty());
+    }
+
+    #[test]
+    fn reaping_releases_locks_of_stuck_transaction() {
+        let (_dir, db) = temp_transaction_db();
+        let registry = TxnRegistry::new(&db);
+        let no_wait = no_wait_txn_opts();
+
+        let stuck = registry.begin("stuck");
+        stuck
+            .put_cf(DBColumnFamilies::User, b"user1", b"stuck")
+            .unwrap();
+
+        let other = registry.begin_opt("other", &WriteOptions::default(), &no_wait);
+        assert!(other
+            .put_cf(DBColumnFamilies::User, b"user1", b"other")
+            .is_err());
+
+        assert!(registry.reap(Duration::from_secs(60)).is_empty());
+        let reaped = registry.reap(Duration::ZERO);
+        // `other` is old enough as well.
+        assert_eq!(reaped.len(), 2);
+        assert_eq!(reaped[0].owner, "stuck");
+        assert!(registry.active().is_empty());
+
+        let err = stuck.put_cf(DBColumnFamilies::User, b"user2", b"stuck");
+        assert_eq!(
+            err.unwrap_err().downcast::<Reaped>().unwrap().owner,
+            "stuck"
+        );
+        assert!(stuck.commit().is_err());
+
+        let next = registry.begin_opt("next", &WriteOptions::default(), &no_wait);
+        next.put_cf(DBColumnFamilies::User, b"user1", b"next")
+            .unwrap();
+        next.commit().unwrap();
+        assert_eq!(
+            db.get_cf(&DBColumnFamilies::User.cf_db(&db), b"user1")
+                .unwrap(),
+            Some(b"next".to_vec())
+        );
+    }
+
+    #[test]
+    fn background_reaper_unblocks_waiting_writer() {
+        let (_dir, db) = temp_transaction_db();
+        let registry = TxnRegistry::new(&db);
+
+        let stuck = registry.begin("stuck");
+        stuck
+            .put_cf(DBColumnFamilies::User, b"user1", b"stuck")
+            .unwrap();
+
+        let reaped = thread::scope(|s| {
+            let registry = &registry;
+            let (stop, stopped) = mpsc::channel();
+            let reaper = s.spawn(move || {
+                registry.reap_every(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                    &stopped,
+                )
+            });
+
+            // Not tracked, the reaper would roll it back as well once it is old enough.
+            // Waits up to the default lock timeout of one second.
+            let writer = db.transaction();
+            let started = Instant::now();
+            writer
+                .put_cf(&DBColumnFamilies::User.cf_db(&db), b"user1", b"writer")
+                .unwrap();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            writer.commit().unwrap();
+            stop.send(()).unwrap();
+            reaper.join().unwrap()
+        });
+
+        assert!(reaped >= 1);
+        assert!(stuck.commit().unwrap_err().is::<Reaped>());
+        assert_eq!(
+            db.get_cf(&DBColumnFamilies::User.cf_db(&db), b"user1")
+                .unwrap(),
+            Some(b"writer".to_vec())
+        );
+    }
+
+    #[test]
+    fn expired_transaction_loses_its_locks() {
+        let (_dir, db) = temp_transaction_db();
+        let cf = DBColumnFamilies::User.cf_db(&db);
+
+        let txn1 = db.transaction_opt(
+            &WriteOptions::default(),
+            &expiring_txn_opts(Duration::from_millis(50)),
+        );
+        txn1.put_cf(&cf, b"user1", b"txn1").unwrap();
+
+        // Waits for the expiration instead of the lock timeout.
+        let txn2 = db.transaction();
+        txn2.put_cf(&cf, b"user1", b"txn2").unwrap();
+        txn2.commit().unwrap();
+
+        assert_eq!(txn1.commit().unwrap_err().kind(), ErrorKind::Expired);
+        assert_eq!(db.get_cf(&cf, b"user1").unwrap(), Some(b"txn2".to_vec()));
+    }
+}